@@ -0,0 +1,305 @@
+// Copyright 2022-2023 Andrew D. Straw.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT
+// or http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Parsing of Annex B byte streams, such as `.h264` files
+//!
+//! NAL units are delimited by 3 byte (`00 00 01`) or 4 byte (`00 00 00 01`)
+//! start codes. [AnnexBSliceReader] splits a stream held in memory and
+//! [AnnexBReader] reads one incrementally from a [std::io::Read]
+//! implementation. Both iterate over the parsed [NalUnit]s and also give access
+//! to the encapsulated byte sequence payload (EBSP) and position of each NAL
+//! unit.
+
+use crate::nal_unit::NalUnit;
+use crate::Result;
+
+#[cfg(feature = "std")]
+use alloc::vec::Vec;
+
+/// Return the index of the first start code prefix `00 00 01` at or after
+/// `from`.
+fn find_start_code(data: &[u8], mut from: usize) -> Option<usize> {
+    while let Some(idx) = memchr::memchr(0x01, &data[from..]) {
+        let one_idx = from + idx;
+        if one_idx >= from + 2 && data[one_idx - 1] == 0x00 && data[one_idx - 2] == 0x00 {
+            return Some(one_idx - 2);
+        }
+        from = one_idx + 1;
+    }
+    None
+}
+
+/// The position of the start code at `start_code_idx`, including a preceding
+/// `zero_byte` if present.
+fn start_code_offset(data: &[u8], start_code_idx: usize) -> usize {
+    if start_code_idx > 0 && data[start_code_idx - 1] == 0x00 {
+        start_code_idx - 1
+    } else {
+        start_code_idx
+    }
+}
+
+/// The end of the NAL unit which starts at `start` and is followed by
+/// `trailing_zero_8bits` (and the `zero_byte` of a 4 byte start code) until
+/// `end`.
+fn strip_trailing_zeros(data: &[u8], start: usize, mut end: usize) -> usize {
+    while end > start && data[end - 1] == 0x00 {
+        end -= 1;
+    }
+    end
+}
+
+/// Splits an Annex B byte stream held in memory into NAL units.
+///
+/// Any bytes before the first start code are ignored.
+pub struct AnnexBSliceReader<'a> {
+    data: &'a [u8],
+    /// Position at which to search for the next start code.
+    pos: usize,
+}
+
+impl<'a> AnnexBSliceReader<'a> {
+    /// Create a reader at the start of `data`.
+    pub fn new(data: &'a [u8]) -> Self {
+        Self { data, pos: 0 }
+    }
+
+    /// Return the next NAL unit, starting with the NAL unit header, together
+    /// with the position of its start code in the stream.
+    ///
+    /// The returned bytes are encapsulated byte sequence payload (EBSP) and
+    /// can be parsed with [NalUnit::from_nal_unit].
+    pub fn next_ebsp(&mut self) -> Option<(usize, &'a [u8])> {
+        let data = self.data;
+        let start_code_idx = find_start_code(data, self.pos)?;
+        let start = start_code_idx + 3;
+        let next = find_start_code(data, start).unwrap_or(data.len());
+        let end = strip_trailing_zeros(data, start, next);
+        self.pos = next;
+        Some((start_code_offset(data, start_code_idx), &data[start..end]))
+    }
+}
+
+impl Iterator for AnnexBSliceReader<'_> {
+    type Item = Result<NalUnit>;
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_ebsp()
+            .map(|(_offset, ebsp)| NalUnit::from_nal_unit(ebsp))
+    }
+}
+
+/// The number of bytes requested from the underlying reader at once.
+#[cfg(feature = "std")]
+const READ_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Reads NAL units incrementally from an Annex B byte stream.
+///
+/// Only the NAL unit being parsed is held in memory, together with up to one
+/// read chunk of following data. Any bytes before the first start code are
+/// ignored.
+#[cfg(feature = "std")]
+pub struct AnnexBReader<R> {
+    rdr: R,
+    buf: Vec<u8>,
+    /// Position in the stream of `buf[0]`.
+    buf_offset: u64,
+    /// Position in `buf` at which to search for the next start code.
+    pos: usize,
+    eof: bool,
+}
+
+#[cfg(feature = "std")]
+impl<R: std::io::Read> AnnexBReader<R> {
+    /// Create a reader which reads from the current position of `rdr`.
+    pub fn new(rdr: R) -> Self {
+        Self {
+            rdr,
+            buf: Vec::new(),
+            buf_offset: 0,
+            pos: 0,
+            eof: false,
+        }
+    }
+
+    /// Return the underlying reader.
+    pub fn into_inner(self) -> R {
+        self.rdr
+    }
+
+    /// Append up to [READ_CHUNK_SIZE] bytes to `buf`, setting `eof` if there
+    /// are no more.
+    fn fill_buf(&mut self) -> Result<()> {
+        let len = self.buf.len();
+        self.buf.resize(len + READ_CHUNK_SIZE, 0);
+        let n_read = loop {
+            match self.rdr.read(&mut self.buf[len..]) {
+                Ok(n_read) => break n_read,
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+                Err(e) => {
+                    self.buf.truncate(len);
+                    return Err(e.into());
+                }
+            }
+        };
+        self.buf.truncate(len + n_read);
+        self.eof = n_read == 0;
+        Ok(())
+    }
+
+    /// Discard the first `n` bytes of `buf`.
+    fn consume(&mut self, n: usize) {
+        self.buf.drain(..n);
+        self.buf_offset += n as u64;
+        self.pos -= n;
+    }
+
+    /// Return the next NAL unit, starting with the NAL unit header, together
+    /// with the position of its start code in the stream.
+    ///
+    /// The returned bytes are encapsulated byte sequence payload (EBSP) and
+    /// can be parsed with [NalUnit::from_nal_unit].
+    pub fn next_ebsp(&mut self) -> Result<Option<(u64, &[u8])>> {
+        // Keep a possible zero_byte before the next start code.
+        let keep_from = self.pos.saturating_sub(1);
+        self.consume(keep_from);
+
+        let start_code_idx = loop {
+            if let Some(idx) = find_start_code(&self.buf, self.pos) {
+                break idx;
+            }
+            if self.eof {
+                return Ok(None);
+            }
+            // Keep the last bytes, which may be the start of a start code.
+            let keep_from = self.buf.len().saturating_sub(3);
+            self.pos = keep_from;
+            self.consume(keep_from.saturating_sub(1));
+            self.fill_buf()?;
+        };
+
+        let start = start_code_idx + 3;
+        let mut search_from = start;
+        let next = loop {
+            if let Some(idx) = find_start_code(&self.buf, search_from) {
+                break idx;
+            }
+            if self.eof {
+                break self.buf.len();
+            }
+            search_from = self.buf.len().saturating_sub(2).max(start);
+            self.fill_buf()?;
+        };
+
+        let end = strip_trailing_zeros(&self.buf, start, next);
+        self.pos = next;
+        let offset = self.buf_offset + start_code_offset(&self.buf, start_code_idx) as u64;
+        Ok(Some((offset, &self.buf[start..end])))
+    }
+
+    /// Read and parse the next NAL unit.
+    pub fn read_nal_unit(&mut self) -> Result<Option<NalUnit>> {
+        match self.next_ebsp()? {
+            Some((_offset, ebsp)) => Ok(Some(NalUnit::from_nal_unit(ebsp)?)),
+            None => Ok(None),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl<R: std::io::Read> Iterator for AnnexBReader<R> {
+    type Item = Result<NalUnit>;
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_nal_unit().transpose()
+    }
+}
+
+#[test]
+fn test_find_start_code() {
+    assert_eq!(find_start_code(&[0, 0, 1], 0), Some(0));
+    assert_eq!(find_start_code(&[0, 0, 0, 1], 0), Some(1));
+    assert_eq!(find_start_code(&[1, 0, 1, 0, 0, 1], 0), Some(3));
+    assert_eq!(find_start_code(&[0, 0, 1], 1), None);
+    assert_eq!(find_start_code(&[0, 0, 2, 0, 1], 0), None);
+}
+
+#[test]
+fn test_annex_b_reader() {
+    use crate::nal_unit::{NalRefIdc, NalUnitType};
+
+    // leading zero byte, 4 byte start code, 3 byte start code with trailing zeros,
+    // SPS ending with emulation prevention byte
+    let stream: &[u8] = &[
+        0x00, 0x00, 0x00, 0x00, 0x01, 0x67, 0x42, 0x00, 0x00, 0x03, 0x00, 0x00, 0x01, 0x68, 0xce,
+        0x38, 0x80, 0x00, 0x00, 0x00, 0x00, 0x01, 0x06, 0x06, 0x01, 0xc4, 0x80,
+    ];
+    let expected = [
+        (
+            1,
+            NalRefIdc::Three,
+            NalUnitType::SequenceParameterSet,
+            &[0x42, 0x00, 0x00][..],
+        ),
+        (
+            10,
+            NalRefIdc::Three,
+            NalUnitType::PictureParameterSet,
+            &[0xce, 0x38, 0x80],
+        ),
+        (
+            18,
+            NalRefIdc::Zero,
+            NalUnitType::SupplementalEnhancementInformation,
+            &[0x06, 0x01, 0xc4, 0x80],
+        ),
+    ];
+
+    let mut rdr = AnnexBSliceReader::new(stream);
+    let mut offsets = Vec::new();
+    while let Some((offset, _ebsp)) = rdr.next_ebsp() {
+        offsets.push(offset);
+    }
+    assert_eq!(offsets, [1, 10, 18]);
+
+    let parsed: Vec<NalUnit> = AnnexBSliceReader::new(stream)
+        .collect::<Result<_>>()
+        .unwrap();
+    assert_eq!(parsed.len(), expected.len());
+    for (nal_unit, (_, ref_idc, unit_type, rbsp)) in parsed.iter().zip(expected.iter()) {
+        assert_eq!(nal_unit.ref_idc(), *ref_idc);
+        assert_eq!(nal_unit.unit_type(), *unit_type);
+        assert_eq!(nal_unit.rbsp_data().data, *rbsp);
+    }
+
+    // Read a few bytes at a time to cross every chunk boundary.
+    struct Trickle<'a>(&'a [u8], usize);
+    impl std::io::Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let n = self.1.min(self.0.len()).min(buf.len());
+            buf[..n].copy_from_slice(&self.0[..n]);
+            self.0 = &self.0[n..];
+            Ok(n)
+        }
+    }
+    for chunk_size in 1..stream.len() {
+        let mut rdr = AnnexBReader::new(Trickle(stream, chunk_size));
+        for (offset, ref_idc, unit_type, rbsp) in expected.iter() {
+            let (actual_offset, ebsp) = rdr.next_ebsp().unwrap().unwrap();
+            assert_eq!(actual_offset, *offset);
+            let nal_unit = NalUnit::from_nal_unit(ebsp).unwrap();
+            assert_eq!(nal_unit.ref_idc(), *ref_idc);
+            assert_eq!(nal_unit.unit_type(), *unit_type);
+            assert_eq!(nal_unit.rbsp_data().data, *rbsp);
+        }
+        assert!(rdr.next_ebsp().unwrap().is_none());
+    }
+
+    assert!(AnnexBSliceReader::new(&[0x12, 0x00, 0x00]).next().is_none());
+    assert!(AnnexBSliceReader::new(&[0x00, 0x00, 0x01, 0x80, 0x01])
+        .next()
+        .unwrap()
+        .is_err());
+}
@@ -0,0 +1,623 @@
+// Copyright 2022-2023 Andrew D. Straw.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT
+// or http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Writing and reading of bit-level syntax elements.
+//!
+//! This includes Variable Length Coding (VLC) using Exponential Golomb codes.
+//! [RbspWriter] can be used to build custom SEI payloads and NAL units with
+//! the same primitives used by this crate for parameter sets and slice headers.
+//! [BitReader] reads them back.
+
+#[cfg(feature = "alloc")]
+use alloc::vec::Vec;
+
+#[cfg(feature = "backtrace")]
+use std::backtrace::Backtrace;
+
+use crate::sink::ByteSink;
+#[cfg(feature = "alloc")]
+use crate::RbspData;
+use crate::{Error, Result};
+
+/// Writes bits, most significant bit first, into a [ByteSink].
+///
+/// Bits are accumulated in a machine word and passed to the sink four bytes at
+/// a time. Call [Self::finish] to write the remaining bits.
+pub(crate) struct BitWriter<S> {
+    out: S,
+    /// Pending bits, in the `n_pending` least significant bits.
+    pending: u64,
+    /// Always less than 32 between calls.
+    n_pending: u32,
+    /// The number of bits passed to `out`.
+    n_flushed: usize,
+}
+
+impl<S: ByteSink> BitWriter<S> {
+    pub(crate) fn new(out: S) -> Self {
+        Self {
+            out,
+            pending: 0,
+            n_pending: 0,
+            n_flushed: 0,
+        }
+    }
+
+    /// Write the `n_bits` least significant bits of `value`.
+    ///
+    /// `n_bits` must be at most 32.
+    #[inline]
+    pub(crate) fn write_bits(&mut self, n_bits: u32, value: u32) {
+        debug_assert!(n_bits <= 32);
+        if n_bits == 0 {
+            return;
+        }
+        let mask = u64::MAX >> (64 - n_bits);
+        self.pending = (self.pending << n_bits) | (u64::from(value) & mask);
+        self.n_pending += n_bits;
+        if self.n_pending >= 32 {
+            self.n_pending -= 32;
+            let word = (self.pending >> self.n_pending) as u32;
+            self.out.put(&word.to_be_bytes());
+            self.n_flushed += 32;
+        }
+    }
+
+    /// Write a single bit.
+    #[inline]
+    pub(crate) fn write_bit(&mut self, bit: bool) {
+        self.write_bits(1, bit.into())
+    }
+
+    /// Write the `n_bits` least significant bits of `value`, where `n_bits`
+    /// may be up to 64.
+    #[inline]
+    fn write_bits_u64(&mut self, n_bits: u32, value: u64) {
+        if n_bits > 32 {
+            self.write_bits(n_bits - 32, (value >> 32) as u32);
+            self.write_bits(32, value as u32);
+        } else {
+            self.write_bits(n_bits, value as u32);
+        }
+    }
+
+    /// Write `code_num` as Exponential-Golomb code. `code_num` must be less
+    /// than `u64::MAX`.
+    #[inline]
+    fn write_exp_golomb(&mut self, code_num: u64) {
+        let value = code_num + 1;
+        let n_bits = 64 - value.leading_zeros();
+        self.write_bits(n_bits - 1, 0);
+        self.write_bits_u64(n_bits, value);
+    }
+
+    /// Write an unsigned integer Exp-Golomb-coded syntax element, `ue(v)`.
+    ///
+    /// See https://en.wikipedia.org/wiki/Exponential-Golomb_coding
+    #[inline]
+    pub(crate) fn write_ue(&mut self, value: u32) {
+        self.write_exp_golomb(value.into())
+    }
+
+    /// Write a signed integer Exp-Golomb-coded syntax element, `se(v)`.
+    #[inline]
+    pub(crate) fn write_se(&mut self, value: i32) {
+        let value = i64::from(value);
+        let code_num = if value > 0 { 2 * value - 1 } else { -2 * value };
+        self.write_exp_golomb(code_num as u64)
+    }
+
+    /// Write a truncated Exp-Golomb-coded syntax element, `te(v)`, with the
+    /// given maximum value.
+    #[inline]
+    #[cfg_attr(not(feature = "alloc"), allow(dead_code))]
+    pub(crate) fn write_te(&mut self, max_value: u32, value: u32) {
+        debug_assert!(value <= max_value);
+        if max_value > 1 {
+            self.write_ue(value)
+        } else {
+            self.write_bit(value == 0)
+        }
+    }
+
+    /// The number of bits written.
+    #[cfg_attr(not(feature = "alloc"), allow(dead_code))]
+    pub(crate) fn len(&self) -> usize {
+        self.n_flushed + self.n_pending as usize
+    }
+
+    /// Whether the number of bits written is a multiple of 8.
+    pub(crate) fn is_byte_aligned(&self) -> bool {
+        self.n_pending % 8 == 0
+    }
+
+    /// Write zero bits until byte aligned.
+    pub(crate) fn align_with_zeros(&mut self) {
+        self.write_bits((8 - self.n_pending % 8) % 8, 0)
+    }
+
+    /// Write `rbsp_trailing_bits()`: a one bit followed by zero bits until
+    /// byte aligned.
+    pub(crate) fn write_trailing_bits(&mut self) {
+        self.write_bit(true);
+        self.align_with_zeros();
+    }
+
+    /// Write any pending bits, padded with zero bits to a whole byte, and
+    /// return the sink.
+    pub(crate) fn finish(mut self) -> S {
+        self.align_with_zeros();
+        let n_bytes = (self.n_pending / 8) as usize;
+        let bytes = (self.pending << ((64 - self.n_pending) % 64)).to_be_bytes();
+        if n_bytes > 0 {
+            self.out.put(&bytes[..n_bytes]);
+        }
+        self.out
+    }
+}
+
+/// Writes syntax elements into raw byte sequence payload ([RbspData]).
+///
+/// Bits are written most significant bit first. The result is typically
+/// wrapped in a [crate::nal_unit::NalUnit], which applies emulation
+/// prevention when converted to a NAL unit.
+#[cfg(feature = "alloc")]
+pub struct RbspWriter {
+    inner: BitWriter<Vec<u8>>,
+}
+
+#[cfg(feature = "alloc")]
+impl Default for RbspWriter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(feature = "alloc")]
+impl RbspWriter {
+    /// Create a writer with no bits written.
+    pub fn new() -> Self {
+        Self {
+            inner: BitWriter::new(Vec::new()),
+        }
+    }
+
+    /// Write a fixed-width syntax element, `u(n)`, from the `n_bits` least
+    /// significant bits of `value`.
+    ///
+    /// Panics if `n_bits` is greater than 32.
+    pub fn write_bits(&mut self, n_bits: u32, value: u32) {
+        assert!(n_bits <= 32, "at most 32 bits can be written at once");
+        self.inner.write_bits(n_bits, value)
+    }
+
+    /// Write a single bit flag, `u(1)`.
+    pub fn write_bit(&mut self, bit: bool) {
+        self.inner.write_bit(bit)
+    }
+
+    /// Write an unsigned integer Exp-Golomb-coded syntax element, `ue(v)`.
+    pub fn write_ue(&mut self, value: u32) {
+        self.inner.write_ue(value)
+    }
+
+    /// Write a signed integer Exp-Golomb-coded syntax element, `se(v)`.
+    pub fn write_se(&mut self, value: i32) {
+        self.inner.write_se(value)
+    }
+
+    /// Write a truncated Exp-Golomb-coded syntax element, `te(v)`, whose range
+    /// is 0 to `max_value` inclusive.
+    ///
+    /// Panics if `value` is greater than `max_value`.
+    pub fn write_te(&mut self, max_value: u32, value: u32) {
+        assert!(value <= max_value, "value exceeds te(v) range");
+        self.inner.write_te(max_value, value)
+    }
+
+    /// The number of bits written so far.
+    pub fn len(&self) -> usize {
+        self.inner.len()
+    }
+
+    /// Whether no bits have been written.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Whether the number of bits written is a multiple of 8.
+    pub fn is_byte_aligned(&self) -> bool {
+        self.inner.is_byte_aligned()
+    }
+
+    /// Write zero bits until byte aligned, as for `alignment_zero_bit`.
+    pub fn align_with_zeros(&mut self) {
+        self.inner.align_with_zeros()
+    }
+
+    /// Write `rbsp_trailing_bits()`: `rbsp_stop_one_bit` followed by
+    /// `rbsp_alignment_zero_bit` until byte aligned.
+    pub fn write_trailing_bits(&mut self) {
+        self.inner.write_trailing_bits()
+    }
+
+    /// Finish writing and return the payload.
+    ///
+    /// If the bits written are not byte aligned, the last byte is padded with
+    /// zero bits. Call [Self::write_trailing_bits] first for a complete RBSP.
+    pub fn finish(self) -> RbspData {
+        RbspData::new(self.inner.finish())
+    }
+}
+
+/// Reads syntax elements from raw byte sequence payload (RBSP).
+///
+/// Bits are read most significant bit first. Use
+/// [crate::nal_unit::ebsp_to_rbsp] to remove emulation prevention bytes from
+/// a NAL unit first. Reading past the end of the data results in
+/// [Error::InvalidBitstream].
+pub struct BitReader<'a> {
+    data: &'a [u8],
+    /// The number of bits read.
+    pos: usize,
+}
+
+impl<'a> BitReader<'a> {
+    /// Create a reader at the start of `data`.
+    pub fn new(data: &'a [u8]) -> Self {
+        Self { data, pos: 0 }
+    }
+
+    /// Read a fixed-width syntax element, `u(n)`.
+    ///
+    /// Panics if `n_bits` is greater than 32.
+    pub fn read_bits(&mut self, n_bits: u32) -> Result<u32> {
+        assert!(n_bits <= 32, "at most 32 bits can be read at once");
+        if n_bits as usize > self.bits_remaining() {
+            return Err(invalid_bitstream("unexpected end of data"));
+        }
+        let mut value = 0u64;
+        let mut remaining = n_bits;
+        while remaining > 0 {
+            let byte = self.data[self.pos / 8];
+            let available = 8 - (self.pos % 8) as u32;
+            let n = available.min(remaining);
+            let bits = (u32::from(byte) >> (available - n)) & ((1 << n) - 1);
+            value = (value << n) | u64::from(bits);
+            remaining -= n;
+            self.pos += n as usize;
+        }
+        Ok(value as u32)
+    }
+
+    /// Read a single bit flag, `u(1)`.
+    pub fn read_bit(&mut self) -> Result<bool> {
+        Ok(self.read_bits(1)? == 1)
+    }
+
+    /// Read an Exponential-Golomb code of up to 65 bits.
+    fn read_exp_golomb(&mut self) -> Result<u64> {
+        let mut leading_zeros = 0;
+        while !self.read_bit()? {
+            leading_zeros += 1;
+            if leading_zeros > 32 {
+                return Err(invalid_bitstream("Exp-Golomb code too long"));
+            }
+        }
+        let suffix = if leading_zeros == 32 {
+            u64::from(self.read_bits(32)?)
+        } else {
+            u64::from(self.read_bits(leading_zeros)?)
+        };
+        Ok((1u64 << leading_zeros) - 1 + suffix)
+    }
+
+    /// Read an unsigned integer Exp-Golomb-coded syntax element, `ue(v)`.
+    pub fn read_ue(&mut self) -> Result<u32> {
+        self.read_exp_golomb()?
+            .try_into()
+            .map_err(|_| invalid_bitstream("ue(v) value out of range"))
+    }
+
+    /// Read a signed integer Exp-Golomb-coded syntax element, `se(v)`.
+    pub fn read_se(&mut self) -> Result<i32> {
+        let code_num = self.read_exp_golomb()? as i64;
+        let value = if code_num % 2 == 1 {
+            (code_num + 1) / 2
+        } else {
+            -(code_num / 2)
+        };
+        value
+            .try_into()
+            .map_err(|_| invalid_bitstream("se(v) value out of range"))
+    }
+
+    /// Read a truncated Exp-Golomb-coded syntax element, `te(v)`, whose range
+    /// is 0 to `max_value` inclusive.
+    pub fn read_te(&mut self, max_value: u32) -> Result<u32> {
+        let value = if max_value > 1 {
+            self.read_ue()?
+        } else {
+            (!self.read_bit()?).into()
+        };
+        if value > max_value {
+            return Err(invalid_bitstream("te(v) value out of range"));
+        }
+        Ok(value)
+    }
+
+    /// Read `n_bytes` whole bytes, such as PCM samples.
+    ///
+    /// Panics if the reader is not byte aligned.
+    pub fn read_bytes(&mut self, n_bytes: usize) -> Result<&'a [u8]> {
+        assert!(self.is_byte_aligned(), "bytes read while not byte aligned");
+        let start = self.pos / 8;
+        let bytes = self
+            .data
+            .get(start..start + n_bytes)
+            .ok_or_else(|| invalid_bitstream("unexpected end of data"))?;
+        self.pos += n_bytes * 8;
+        Ok(bytes)
+    }
+
+    /// The number of bits read so far.
+    pub fn position(&self) -> usize {
+        self.pos
+    }
+
+    /// The number of bits not yet read.
+    pub fn bits_remaining(&self) -> usize {
+        self.data.len() * 8 - self.pos
+    }
+
+    /// Whether the number of bits read is a multiple of 8.
+    pub fn is_byte_aligned(&self) -> bool {
+        self.pos % 8 == 0
+    }
+
+    /// Whether there is more data before `rbsp_trailing_bits()`, as
+    /// `more_rbsp_data()` in the specification.
+    pub fn more_rbsp_data(&self) -> bool {
+        // The last bit equal to one is rbsp_stop_one_bit.
+        match self.data.iter().rposition(|b| *b != 0) {
+            Some(idx) => {
+                let stop_bit_pos = idx * 8 + 7 - self.data[idx].trailing_zeros() as usize;
+                self.pos < stop_bit_pos
+            }
+            None => false,
+        }
+    }
+
+    /// Read `rbsp_trailing_bits()`: `rbsp_stop_one_bit` followed by
+    /// `rbsp_alignment_zero_bit` until byte aligned.
+    pub fn read_trailing_bits(&mut self) -> Result<()> {
+        if !self.read_bit()? {
+            return Err(invalid_bitstream("expected rbsp_stop_one_bit"));
+        }
+        self.skip_alignment_zero_bits()
+    }
+
+    /// Read zero bits until byte aligned, as for `alignment_zero_bit`.
+    pub fn skip_alignment_zero_bits(&mut self) -> Result<()> {
+        let n_bits = (8 - (self.pos % 8) as u32) % 8;
+        if self.read_bits(n_bits)? != 0 {
+            return Err(invalid_bitstream("expected alignment zero bits"));
+        }
+        Ok(())
+    }
+}
+
+pub(crate) fn invalid_bitstream(msg: &'static str) -> Error {
+    Error::InvalidBitstream {
+        msg,
+        #[cfg(feature = "backtrace")]
+        backtrace: Backtrace::capture(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bits(f: impl FnOnce(&mut BitWriter<Vec<u8>>)) -> String {
+        let mut wtr = BitWriter::new(Vec::new());
+        f(&mut wtr);
+        let n_bits = wtr.len();
+        let bytes = wtr.finish();
+        let all: String = bytes.iter().map(|b| format!("{b:08b}")).collect();
+        all[..n_bits].to_string()
+    }
+
+    #[test]
+    fn test_exp_golomb() {
+        // tests from https://en.wikipedia.org/wiki/Exponential-Golomb_coding
+        let ue = |x| bits(|w| w.write_ue(x));
+        assert_eq!(ue(0), "1");
+        assert_eq!(ue(1), "010");
+        assert_eq!(ue(2), "011");
+        assert_eq!(ue(3), "00100");
+        assert_eq!(ue(4), "00101");
+        assert_eq!(ue(5), "00110");
+        assert_eq!(ue(6), "00111");
+        assert_eq!(ue(7), "0001000");
+        assert_eq!(ue(8), "0001001");
+        assert_eq!(
+            ue(u32::MAX),
+            format!("{}1{}", "0".repeat(32), "0".repeat(32))
+        );
+
+        let se = |x| bits(|w| w.write_se(x));
+        assert_eq!(se(0), "1");
+        assert_eq!(se(1), "010");
+        assert_eq!(se(-1), "011");
+        assert_eq!(se(2), "00100");
+        assert_eq!(se(-2), "00101");
+        assert_eq!(se(3), "00110");
+        assert_eq!(se(-3), "00111");
+        assert_eq!(se(4), "0001000");
+        assert_eq!(se(-4), "0001001");
+        assert_eq!(
+            se(i32::MIN),
+            format!("{}1{}1", "0".repeat(32), "0".repeat(31))
+        );
+    }
+
+    #[test]
+    fn test_te() {
+        assert_eq!(bits(|w| w.write_te(1, 0)), "1");
+        assert_eq!(bits(|w| w.write_te(1, 1)), "0");
+        assert_eq!(bits(|w| w.write_te(2, 1)), "010");
+    }
+
+    #[test]
+    fn test_write_bits() {
+        let mut wtr = BitWriter::new(Vec::new());
+        wtr.write_bits(3, 0b101);
+        wtr.write_bits(2, 0);
+        wtr.write_bits(3, 0xFF);
+        assert!(wtr.is_byte_aligned());
+        for i in 0..10 {
+            wtr.write_bits(32, 0x01020304 * i);
+            wtr.write_bit(i % 2 == 0);
+        }
+        wtr.write_trailing_bits();
+        assert_eq!(wtr.len(), 8 + 10 * 33 + 6);
+
+        let mut expected = String::from("10100111");
+        for i in 0..10u32 {
+            expected.push_str(&format!("{:032b}", 0x01020304 * i));
+            expected.push(if i % 2 == 0 { '1' } else { '0' });
+        }
+        expected.push_str("100000");
+        let actual: String = wtr.finish().iter().map(|b| format!("{b:08b}")).collect();
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn test_rbsp_writer() {
+        use crate::nal_unit::{NalRefIdc, NalUnit, NalUnitType};
+
+        // A recovery point SEI message built by hand matches the one from
+        // this crate.
+        let mut payload = RbspWriter::new();
+        payload.write_ue(0); // recovery_frame_cnt
+        payload.write_bit(true); // exact_match_flag
+        payload.write_bit(false); // broken_link_flag
+        payload.write_bits(2, 0); // changing_slice_group_idc
+        assert!(!payload.is_byte_aligned());
+        payload.write_trailing_bits();
+        assert_eq!(payload.len(), 8);
+        let payload = payload.finish().data;
+
+        let mut sei = RbspWriter::new();
+        sei.write_bits(8, 6); // payloadType
+        sei.write_bits(8, payload.len() as u32); // payloadSize
+        for byte in payload {
+            sei.write_bits(8, byte.into());
+        }
+        sei.write_trailing_bits();
+        let sei = sei.finish();
+
+        let expected = crate::sei::SupplementalEnhancementInformation::RecoveryPoint(
+            crate::sei::RecoveryPoint::new(0),
+        )
+        .to_rbsp();
+        assert_eq!(sei.data, expected.data);
+
+        let nal = NalUnit::new(
+            NalRefIdc::Zero,
+            NalUnitType::SupplementalEnhancementInformation,
+            sei,
+        );
+        assert_eq!(nal.to_annex_b_data(), [0, 0, 0, 1, 6, 6, 1, 0xc4, 0x80]);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_rbsp_writer_te_range() {
+        RbspWriter::new().write_te(1, 2);
+    }
+
+    #[test]
+    fn test_bit_reader() {
+        let mut wtr = RbspWriter::new();
+        wtr.write_bits(3, 0b101);
+        wtr.write_ue(0);
+        wtr.write_ue(u32::MAX);
+        wtr.write_se(i32::MIN);
+        wtr.write_se(i32::MAX);
+        wtr.write_te(1, 1);
+        wtr.write_te(7, 5);
+        wtr.write_bits(32, 0xdeadbeef);
+        wtr.write_trailing_bits();
+        let rbsp = wtr.finish();
+
+        let mut rdr = BitReader::new(&rbsp.data);
+        assert_eq!(rdr.read_bits(3).unwrap(), 0b101);
+        assert_eq!(rdr.read_ue().unwrap(), 0);
+        assert_eq!(rdr.read_ue().unwrap(), u32::MAX);
+        assert_eq!(rdr.read_se().unwrap(), i32::MIN);
+        assert_eq!(rdr.read_se().unwrap(), i32::MAX);
+        assert_eq!(rdr.read_te(1).unwrap(), 1);
+        assert_eq!(rdr.read_te(7).unwrap(), 5);
+        assert!(rdr.more_rbsp_data());
+        assert_eq!(rdr.read_bits(32).unwrap(), 0xdeadbeef);
+        assert!(!rdr.more_rbsp_data());
+        rdr.read_trailing_bits().unwrap();
+        assert_eq!(rdr.bits_remaining(), 0);
+        assert!(rdr.read_bytes(1).is_err());
+        assert!(matches!(
+            rdr.read_bit(),
+            Err(Error::InvalidBitstream { .. })
+        ));
+
+        // 33 leading zero bits
+        let mut rdr = BitReader::new(&[0, 0, 0, 0, 0, 0xff]);
+        assert!(rdr.read_ue().is_err());
+        // ue(v) code for 2^32
+        let mut wtr = RbspWriter::new();
+        wtr.write_se(i32::MIN);
+        let rbsp = wtr.finish();
+        assert!(BitReader::new(&rbsp.data).read_ue().is_err());
+    }
+
+    proptest::proptest! {
+        #[test]
+        fn prop_round_trip(
+            fields in proptest::collection::vec((0u8..4, proptest::num::u32::ANY), 0..64)
+        ) {
+            let mut wtr = RbspWriter::new();
+            for (kind, value) in fields.iter() {
+                match kind {
+                    0 => wtr.write_bits(value % 33, *value),
+                    1 => wtr.write_ue(*value),
+                    2 => wtr.write_se(*value as i32),
+                    _ => wtr.write_te(value % 4, value % 4),
+                }
+            }
+            wtr.write_trailing_bits();
+            let rbsp = wtr.finish();
+
+            let mut rdr = BitReader::new(&rbsp.data);
+            for (kind, value) in fields.iter() {
+                match kind {
+                    0 => {
+                        let n_bits = value % 33;
+                        let expected = if n_bits == 32 { *value } else { value & ((1 << n_bits) - 1) };
+                        proptest::prop_assert_eq!(rdr.read_bits(n_bits).unwrap(), expected);
+                    }
+                    1 => proptest::prop_assert_eq!(rdr.read_ue().unwrap(), *value),
+                    2 => proptest::prop_assert_eq!(rdr.read_se().unwrap(), *value as i32),
+                    _ => proptest::prop_assert_eq!(rdr.read_te(value % 4).unwrap(), value % 4),
+                }
+            }
+            proptest::prop_assert!(!rdr.more_rbsp_data());
+            rdr.read_trailing_bits().unwrap();
+            proptest::prop_assert_eq!(rdr.bits_remaining(), 0);
+        }
+    }
+}
@@ -0,0 +1,596 @@
+// Copyright 2022-2023 Andrew D. Straw.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT
+// or http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Decoding of I_PCM encoded pictures, such as those written by this crate
+
+use alloc::collections::BTreeMap;
+use alloc::{vec, vec::Vec};
+
+#[cfg(feature = "backtrace")]
+use std::backtrace::Backtrace;
+
+use crate::annex_b::AnnexBSliceReader;
+use crate::bitstream::{invalid_bitstream, BitReader};
+use crate::nal_unit::{NalRefIdc, NalUnit, NalUnitType};
+use crate::ycbcr_image::{OwnedDataPlane, OwnedPlanes, OwnedYCbCrImage};
+use crate::{BitDepth, Error, Result};
+
+/// Values of `profile_idc` whose SPS contains `chroma_format_idc` and bit
+/// depths.
+pub(crate) const HIGH_PROFILE_IDCS: [u8; 13] =
+    [100, 110, 122, 244, 44, 83, 86, 118, 128, 138, 139, 134, 135];
+
+/// The maximum frame size in macroblocks at any level.
+const MAX_FRAME_SIZE_MBS: u32 = 139_264;
+
+fn unsupported(msg: &'static str) -> Error {
+    Error::UnsupportedBitstream {
+        msg,
+        #[cfg(feature = "backtrace")]
+        backtrace: Backtrace::capture(),
+    }
+}
+
+/// The sequence parameter set fields needed for decoding.
+struct SeqParams {
+    /// Either 0 (monochrome) or 1 (4:2:0)
+    chroma_format_idc: u32,
+    bit_depth: BitDepth,
+    log2_max_frame_num: u32,
+    pic_order_cnt_type: u32,
+    log2_max_pic_order_cnt_lsb: u32,
+    delta_pic_order_always_zero_flag: bool,
+    width_mbs: u32,
+    height_mbs: u32,
+    /// The cropped width in luma samples.
+    width: u32,
+    /// The cropped height in luma samples.
+    height: u32,
+}
+
+/// The properties of a sequence parameter set which determine the layout of
+/// decoded pictures.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct PictureFormat {
+    pub(crate) chroma_format_idc: u32,
+    pub(crate) bit_depth: BitDepth,
+    pub(crate) width: u32,
+    pub(crate) height: u32,
+}
+
+impl PictureFormat {
+    /// Parse the picture format from the RBSP of a sequence parameter set.
+    pub(crate) fn from_sps(rbsp: &[u8]) -> Result<Self> {
+        let (_sps_id, sps) = SeqParams::parse(rbsp)?;
+        Ok(Self {
+            chroma_format_idc: sps.chroma_format_idc,
+            bit_depth: sps.bit_depth,
+            width: sps.width,
+            height: sps.height,
+        })
+    }
+}
+
+impl SeqParams {
+    /// Parse `seq_parameter_set_data()`, returning `seq_parameter_set_id`.
+    fn parse(rbsp: &[u8]) -> Result<(u32, Self)> {
+        let mut r = BitReader::new(rbsp);
+        let profile_idc = r.read_bits(8)? as u8;
+        // constraint_set flags, reserved_zero_2bits and level_idc
+        r.read_bits(16)?;
+        let sps_id = r.read_ue()?;
+
+        let (chroma_format_idc, bit_depth) = if HIGH_PROFILE_IDCS.contains(&profile_idc) {
+            let chroma_format_idc = r.read_ue()?;
+            if chroma_format_idc > 1 {
+                return Err(unsupported(
+                    "chroma formats other than monochrome and 4:2:0",
+                ));
+            }
+            let bit_depth_luma_minus8 = r.read_ue()?;
+            let bit_depth_chroma_minus8 = r.read_ue()?;
+            if chroma_format_idc == 1 && bit_depth_luma_minus8 != bit_depth_chroma_minus8 {
+                return Err(unsupported("different luma and chroma bit depths"));
+            }
+            let bit_depth = match bit_depth_luma_minus8 {
+                0 => BitDepth::Depth8,
+                4 => BitDepth::Depth12,
+                _ => return Err(unsupported("bit depths other than 8 and 12")),
+            };
+            // qpprime_y_zero_transform_bypass_flag
+            r.read_bit()?;
+            if r.read_bit()? {
+                return Err(unsupported("scaling matrices"));
+            }
+            (chroma_format_idc, bit_depth)
+        } else {
+            (1, BitDepth::Depth8)
+        };
+
+        let log2_max_frame_num = r.read_ue()?.saturating_add(4);
+        if log2_max_frame_num > 16 {
+            return Err(invalid_bitstream("log2_max_frame_num_minus4 out of range"));
+        }
+
+        let pic_order_cnt_type = r.read_ue()?;
+        let mut log2_max_pic_order_cnt_lsb = 0;
+        let mut delta_pic_order_always_zero_flag = false;
+        match pic_order_cnt_type {
+            0 => {
+                log2_max_pic_order_cnt_lsb = r.read_ue()?.saturating_add(4);
+                if log2_max_pic_order_cnt_lsb > 16 {
+                    return Err(invalid_bitstream(
+                        "log2_max_pic_order_cnt_lsb_minus4 out of range",
+                    ));
+                }
+            }
+            1 => {
+                delta_pic_order_always_zero_flag = r.read_bit()?;
+                // offset_for_non_ref_pic
+                r.read_se()?;
+                // offset_for_top_to_bottom_field
+                r.read_se()?;
+                let num_ref_frames_in_pic_order_cnt_cycle = r.read_ue()?;
+                if num_ref_frames_in_pic_order_cnt_cycle > 255 {
+                    return Err(invalid_bitstream(
+                        "num_ref_frames_in_pic_order_cnt_cycle out of range",
+                    ));
+                }
+                for _ in 0..num_ref_frames_in_pic_order_cnt_cycle {
+                    // offset_for_ref_frame
+                    r.read_se()?;
+                }
+            }
+            2 => {}
+            _ => return Err(invalid_bitstream("pic_order_cnt_type out of range")),
+        }
+
+        // max_num_ref_frames
+        r.read_ue()?;
+        // gaps_in_frame_num_value_allowed_flag
+        r.read_bit()?;
+
+        let width_mbs = r.read_ue()?.saturating_add(1);
+        let height_mbs = r.read_ue()?.saturating_add(1);
+        if u64::from(width_mbs) * u64::from(height_mbs) > u64::from(MAX_FRAME_SIZE_MBS) {
+            return Err(unsupported("picture size exceeds level limits"));
+        }
+
+        if !r.read_bit()? {
+            return Err(unsupported("interlaced pictures"));
+        }
+        // direct_8x8_inference_flag
+        r.read_bit()?;
+
+        let mut width = width_mbs * 16;
+        let mut height = height_mbs * 16;
+        if r.read_bit()? {
+            // frame_crop_left_offset, frame_crop_right_offset,
+            // frame_crop_top_offset, frame_crop_bottom_offset
+            let mut offsets = [0; 4];
+            for offset in offsets.iter_mut() {
+                *offset = r.read_ue()?;
+            }
+            let [left, right, top, bottom] = offsets;
+            if left != 0 || top != 0 {
+                return Err(unsupported("frame cropping at the left or top"));
+            }
+            // CropUnitX and CropUnitY
+            let crop_unit = if chroma_format_idc == 0 { 1 } else { 2 };
+            let crop_width = right.saturating_mul(crop_unit);
+            let crop_height = bottom.saturating_mul(crop_unit);
+            if crop_width >= width || crop_height >= height {
+                return Err(invalid_bitstream("frame cropping exceeds picture size"));
+            }
+            width -= crop_width;
+            height -= crop_height;
+        }
+        // The VUI parameters which may follow are not needed.
+
+        Ok((
+            sps_id,
+            Self {
+                chroma_format_idc,
+                bit_depth,
+                log2_max_frame_num,
+                pic_order_cnt_type,
+                log2_max_pic_order_cnt_lsb,
+                delta_pic_order_always_zero_flag,
+                width_mbs,
+                height_mbs,
+                width,
+                height,
+            },
+        ))
+    }
+}
+
+/// The picture parameter set fields needed for decoding.
+struct PicParams {
+    seq_parameter_set_id: u32,
+    bottom_field_pic_order_in_frame_present_flag: bool,
+    deblocking_filter_control_present_flag: bool,
+}
+
+impl PicParams {
+    /// Parse `pic_parameter_set_rbsp()`, returning `pic_parameter_set_id`.
+    fn parse(rbsp: &[u8]) -> Result<(u32, Self)> {
+        let mut r = BitReader::new(rbsp);
+        let pps_id = r.read_ue()?;
+        let seq_parameter_set_id = r.read_ue()?;
+        if r.read_bit()? {
+            return Err(unsupported("CABAC entropy coding"));
+        }
+        let bottom_field_pic_order_in_frame_present_flag = r.read_bit()?;
+        if r.read_ue()? != 0 {
+            return Err(unsupported("slice groups"));
+        }
+        // num_ref_idx_l0_default_active_minus1
+        r.read_ue()?;
+        // num_ref_idx_l1_default_active_minus1
+        r.read_ue()?;
+        // weighted_pred_flag and weighted_bipred_idc
+        r.read_bits(3)?;
+        // pic_init_qp_minus26, pic_init_qs_minus26, chroma_qp_index_offset
+        r.read_se()?;
+        r.read_se()?;
+        r.read_se()?;
+        let deblocking_filter_control_present_flag = r.read_bit()?;
+        // constrained_intra_pred_flag
+        r.read_bit()?;
+        if r.read_bit()? {
+            return Err(unsupported("redundant pictures"));
+        }
+        // Any following fields concern transforms, which I_PCM macroblocks
+        // do not use.
+
+        Ok((
+            pps_id,
+            Self {
+                seq_parameter_set_id,
+                bottom_field_pic_order_in_frame_present_flag,
+                deblocking_filter_control_present_flag,
+            },
+        ))
+    }
+}
+
+/// Read `dec_ref_pic_marking()`.
+fn skip_dec_ref_pic_marking(r: &mut BitReader, is_idr: bool) -> Result<()> {
+    if is_idr {
+        // no_output_of_prior_pics_flag and long_term_reference_flag
+        r.read_bits(2)?;
+    } else if r.read_bit()? {
+        // adaptive_ref_pic_marking_mode_flag is set
+        loop {
+            let memory_management_control_operation = r.read_ue()?;
+            match memory_management_control_operation {
+                0 => break,
+                1 | 2 | 4 | 6 => {
+                    r.read_ue()?;
+                }
+                3 => {
+                    r.read_ue()?;
+                    r.read_ue()?;
+                }
+                5 => {}
+                _ => {
+                    return Err(invalid_bitstream(
+                        "memory_management_control_operation out of range",
+                    ))
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// A decoded plane, with the row layout used by [crate::YCbCrImage].
+struct PlaneBuffer {
+    data: Vec<u8>,
+    stride: usize,
+    /// The number of bytes in one row of a macroblock.
+    mb_row_bytes: usize,
+    /// The number of rows in a macroblock.
+    mb_rows: usize,
+}
+
+impl PlaneBuffer {
+    fn new(width_mbs: u32, height_mbs: u32, mb_row_bytes: usize, mb_rows: usize) -> Self {
+        let stride = width_mbs as usize * mb_row_bytes;
+        Self {
+            data: vec![0u8; stride * height_mbs as usize * mb_rows],
+            stride,
+            mb_row_bytes,
+            mb_rows,
+        }
+    }
+
+    /// Copy the PCM samples of one macroblock from `r`.
+    #[inline]
+    fn read_macroblock(&mut self, mb_row: usize, mb_col: usize, r: &mut BitReader) -> Result<()> {
+        let samples = r.read_bytes(self.mb_row_bytes * self.mb_rows)?;
+        let mut start = mb_row * self.mb_rows * self.stride + mb_col * self.mb_row_bytes;
+        for src_row in samples.chunks_exact(self.mb_row_bytes) {
+            self.data[start..start + self.mb_row_bytes].copy_from_slice(src_row);
+            start += self.stride;
+        }
+        Ok(())
+    }
+
+    fn into_owned(self, bit_depth: BitDepth) -> OwnedDataPlane {
+        OwnedDataPlane {
+            data: self.data,
+            stride: self.stride,
+            bit_depth,
+        }
+    }
+}
+
+/// A picture whose macroblocks are being decoded.
+struct Picture {
+    luma: PlaneBuffer,
+    chroma: Option<(PlaneBuffer, PlaneBuffer)>,
+    bit_depth: BitDepth,
+    width: u32,
+    height: u32,
+    width_mbs: u32,
+    /// The address of the next macroblock to decode.
+    next_mb: u32,
+    num_mbs: u32,
+}
+
+impl Picture {
+    fn new(sps: &SeqParams) -> Self {
+        let sample_bytes = |n_samples: usize| match sps.bit_depth {
+            BitDepth::Depth8 => n_samples,
+            BitDepth::Depth12 => n_samples * 3 / 2,
+        };
+        let luma = PlaneBuffer::new(sps.width_mbs, sps.height_mbs, sample_bytes(16), 16);
+        let chroma = if sps.chroma_format_idc == 0 {
+            None
+        } else {
+            let new_chroma = || PlaneBuffer::new(sps.width_mbs, sps.height_mbs, sample_bytes(8), 8);
+            Some((new_chroma(), new_chroma()))
+        };
+        Self {
+            luma,
+            chroma,
+            bit_depth: sps.bit_depth,
+            width: sps.width,
+            height: sps.height,
+            width_mbs: sps.width_mbs,
+            next_mb: 0,
+            num_mbs: sps.width_mbs * sps.height_mbs,
+        }
+    }
+
+    /// Read `slice_data()` starting at `first_mb_in_slice`.
+    fn read_slice_data(&mut self, first_mb_in_slice: u32, r: &mut BitReader) -> Result<()> {
+        if first_mb_in_slice != self.next_mb {
+            return Err(invalid_bitstream("slice does not continue picture"));
+        }
+        loop {
+            if self.next_mb >= self.num_mbs {
+                return Err(invalid_bitstream("too many macroblocks in picture"));
+            }
+            if r.read_ue()? != 25 {
+                return Err(unsupported("macroblock types other than I_PCM"));
+            }
+            r.skip_alignment_zero_bits()?;
+
+            let mb_row = (self.next_mb / self.width_mbs) as usize;
+            let mb_col = (self.next_mb % self.width_mbs) as usize;
+            self.luma.read_macroblock(mb_row, mb_col, r)?;
+            if let Some((cb, cr)) = &mut self.chroma {
+                cb.read_macroblock(mb_row, mb_col, r)?;
+                cr.read_macroblock(mb_row, mb_col, r)?;
+            }
+            self.next_mb += 1;
+
+            if !r.more_rbsp_data() {
+                break;
+            }
+        }
+        r.read_trailing_bits()
+    }
+
+    fn is_complete(&self) -> bool {
+        self.next_mb == self.num_mbs
+    }
+
+    fn into_image(self) -> OwnedYCbCrImage {
+        let bit_depth = self.bit_depth;
+        let y = self.luma.into_owned(bit_depth);
+        let planes = match self.chroma {
+            None => OwnedPlanes::Mono(y),
+            Some((cb, cr)) => {
+                OwnedPlanes::YCbCr((y, cb.into_owned(bit_depth), cr.into_owned(bit_depth)))
+            }
+        };
+        OwnedYCbCrImage {
+            planes,
+            width: self.width,
+            height: self.height,
+        }
+    }
+}
+
+/// Decodes H.264 streams consisting of I_PCM macroblocks, such as those
+/// encoded by [crate::LessEncoder].
+///
+/// As the samples are stored uncompressed, decoding copies them into the
+/// planes of an [OwnedYCbCrImage]. The planes have the layout of the images
+/// accepted by [crate::LessEncoder], with the padding to whole macroblocks
+/// included, and the image width and height are those after frame cropping.
+/// Only progressive pictures in monochrome or 4:2:0 with a bit depth of 8 or 12,
+/// CAVLC entropy coding and I slices of only I_PCM macroblocks are supported.
+/// Other streams result in [Error::UnsupportedBitstream].
+#[derive(Default)]
+pub struct LessDecoder {
+    sps: BTreeMap<u32, SeqParams>,
+    pps: BTreeMap<u32, PicParams>,
+    picture: Option<Picture>,
+    /// The `idr_pic_id` of the last IDR slice decoded.
+    last_idr_pic_id: Option<u32>,
+    pictures_discarded: u64,
+}
+
+impl LessDecoder {
+    /// Create a decoder which expects parameter sets before the first slice.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Decode all pictures in an Annex B byte stream, such as a `.h264` file.
+    pub fn decode_annex_b(&mut self, data: &[u8]) -> Result<Vec<OwnedYCbCrImage>> {
+        let mut pictures = Vec::new();
+        for nal_unit in AnnexBSliceReader::new(data) {
+            if let Some(picture) = self.decode_nal_unit(&nal_unit?)? {
+                pictures.push(picture);
+            }
+        }
+        Ok(pictures)
+    }
+
+    /// The number of incomplete pictures discarded because the next picture
+    /// started before their last slice.
+    pub fn pictures_discarded(&self) -> u64 {
+        self.pictures_discarded
+    }
+
+    /// The `idr_pic_id` of the last IDR slice decoded, if any.
+    #[cfg(feature = "std")]
+    pub(crate) fn last_idr_pic_id(&self) -> Option<u32> {
+        self.last_idr_pic_id
+    }
+
+    /// Decode the NAL units of one access unit in Annex B format, returning
+    /// the picture it contains, if any.
+    ///
+    /// Any incomplete picture from previously decoded NAL units is discarded
+    /// first.
+    #[cfg(feature = "std")]
+    pub(crate) fn decode_access_unit(&mut self, data: &[u8]) -> Result<Option<OwnedYCbCrImage>> {
+        self.picture = None;
+        let mut decoded = None;
+        for nal_unit in AnnexBSliceReader::new(data) {
+            if let Some(picture) = self.decode_nal_unit(&nal_unit?)? {
+                decoded = Some(picture);
+            }
+        }
+        Ok(decoded)
+    }
+
+    /// Decode a NAL unit, returning the picture it completes, if any.
+    ///
+    /// Parameter sets are stored for use by later slices. NAL units which do
+    /// not affect the decoded samples, such as SEI messages, are ignored. When
+    /// a picture starts before the previous one is complete, the incomplete
+    /// picture is discarded and counted in [Self::pictures_discarded], and
+    /// decoding continues with the new picture.
+    pub fn decode_nal_unit(&mut self, nal_unit: &NalUnit) -> Result<Option<OwnedYCbCrImage>> {
+        let rbsp = &nal_unit.rbsp_data().data;
+        match nal_unit.unit_type() {
+            NalUnitType::SequenceParameterSet => {
+                let (sps_id, sps) = SeqParams::parse(rbsp)?;
+                self.sps.insert(sps_id, sps);
+                Ok(None)
+            }
+            NalUnitType::PictureParameterSet => {
+                let (pps_id, pps) = PicParams::parse(rbsp)?;
+                self.pps.insert(pps_id, pps);
+                Ok(None)
+            }
+            NalUnitType::CodedSliceOfAnIDRPicture => self.decode_slice(nal_unit, true),
+            NalUnitType::CodedSliceOfANonIDRPicture => self.decode_slice(nal_unit, false),
+            NalUnitType::CodedSliceDataPartitionA
+            | NalUnitType::CodedSliceDataPartitionB
+            | NalUnitType::CodedSliceDataPartitionC => Err(unsupported("data partitioning")),
+            _ => Ok(None),
+        }
+    }
+
+    fn decode_slice(
+        &mut self,
+        nal_unit: &NalUnit,
+        is_idr: bool,
+    ) -> Result<Option<OwnedYCbCrImage>> {
+        let mut r = BitReader::new(&nal_unit.rbsp_data().data);
+
+        // slice_header()
+        let first_mb_in_slice = r.read_ue()?;
+        let slice_type = r.read_ue()?;
+        if slice_type % 5 != 2 {
+            return Err(unsupported("slice types other than I"));
+        }
+        let pps_id = r.read_ue()?;
+        let pps = self
+            .pps
+            .get(&pps_id)
+            .ok_or_else(|| invalid_bitstream("slice refers to missing PPS"))?;
+        let sps = self
+            .sps
+            .get(&pps.seq_parameter_set_id)
+            .ok_or_else(|| invalid_bitstream("PPS refers to missing SPS"))?;
+
+        // frame_num
+        r.read_bits(sps.log2_max_frame_num)?;
+        if is_idr {
+            self.last_idr_pic_id = Some(r.read_ue()?);
+        }
+        match sps.pic_order_cnt_type {
+            0 => {
+                // pic_order_cnt_lsb
+                r.read_bits(sps.log2_max_pic_order_cnt_lsb)?;
+                if pps.bottom_field_pic_order_in_frame_present_flag {
+                    // delta_pic_order_cnt_bottom
+                    r.read_se()?;
+                }
+            }
+            1 if !sps.delta_pic_order_always_zero_flag => {
+                // delta_pic_order_cnt[0]
+                r.read_se()?;
+                if pps.bottom_field_pic_order_in_frame_present_flag {
+                    // delta_pic_order_cnt[1]
+                    r.read_se()?;
+                }
+            }
+            _ => {}
+        }
+        // I slices have no reference picture list modification or weighted
+        // prediction.
+        if nal_unit.ref_idc() != NalRefIdc::Zero {
+            skip_dec_ref_pic_marking(&mut r, is_idr)?;
+        }
+        // slice_qp_delta
+        r.read_se()?;
+        if pps.deblocking_filter_control_present_flag {
+            let disable_deblocking_filter_idc = r.read_ue()?;
+            if disable_deblocking_filter_idc != 1 {
+                // slice_alpha_c0_offset_div2 and slice_beta_offset_div2
+                r.read_se()?;
+                r.read_se()?;
+            }
+        }
+
+        if first_mb_in_slice == 0 && self.picture.take().is_some() {
+            self.pictures_discarded += 1;
+        }
+        let picture = self.picture.get_or_insert_with(|| Picture::new(sps));
+        if let Err(e) = picture.read_slice_data(first_mb_in_slice, &mut r) {
+            self.picture = None;
+            return Err(e);
+        }
+
+        if picture.is_complete() {
+            Ok(self.picture.take().map(Picture::into_image))
+        } else {
+            Ok(None)
+        }
+    }
+}
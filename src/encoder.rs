@@ -10,8 +10,98 @@
 #[cfg(feature = "backtrace")]
 use std::backtrace::Backtrace;
 
+use core::num::NonZeroU32;
+
+#[cfg(feature = "rayon")]
+use rayon::prelude::*;
+
 use super::nal_unit::*;
+use super::sei::RecoveryPoint;
+#[cfg(feature = "alloc")]
+use super::sei::SupplementalEnhancementInformation;
 use super::*;
+#[cfg(feature = "alloc")]
+use crate::sink::{BufferedSink, NalSink};
+use crate::sink::{EbspWriter, NalBuffer, SliceCursor};
+
+/// Options controlling the group of pictures (GOP) structure.
+///
+/// Every frame is encoded as an intra (I) frame. This determines which of
+/// these are instantaneous decoding refresh (IDR) pictures. IDR pictures are
+/// the sync samples (random access points) of the stream. The others are
+/// encoded as non-IDR I frames.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GopConfig {
+    /// The number of frames from one IDR picture to the next.
+    ///
+    /// The default of 1 encodes every frame as an IDR picture. If `None`,
+    /// only the first frame and frames requested with
+    /// [LessEncoder::force_keyframe] are IDR pictures.
+    pub idr_interval: Option<NonZeroU32>,
+    /// Precede every non-IDR frame with a recovery point SEI message.
+    ///
+    /// This signals decoders that decoding may start at this frame.
+    pub recovery_point_sei: bool,
+}
+
+impl Default for GopConfig {
+    fn default() -> Self {
+        Self {
+            idr_interval: NonZeroU32::new(1),
+            recovery_point_sei: false,
+        }
+    }
+}
+
+impl GopConfig {
+    fn all_idr(&self) -> bool {
+        self.idr_interval == NonZeroU32::new(1)
+    }
+}
+
+/// Options controlling how a [LessEncoder] encodes a stream.
+///
+/// The default values reproduce the output of [LessEncoder::new].
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct EncoderConfig {
+    /// Signal to decoders that frames may be output immediately.
+    ///
+    /// If true, the sequence parameter set includes the VUI
+    /// `bitstream_restriction` fields with `max_num_reorder_frames = 0` and
+    /// `max_dec_frame_buffering = 0`. Without these, decoders such as ffmpeg
+    /// buffer several frames before output, adding latency to live display.
+    pub low_latency: bool,
+    /// The picture order count type signalled in the sequence parameter set.
+    ///
+    /// Combining [PicOrderCntType::Two] with `low_latency` further allows
+    /// decoders to infer that output order equals decoding order.
+    pub pic_order_cnt_type: PicOrderCntType,
+    /// The group of pictures (GOP) structure.
+    pub gop: GopConfig,
+    /// How each picture is divided into slices.
+    pub slice_mode: SliceMode,
+}
+
+/// How a picture is divided into slices.
+///
+/// Each slice is encoded in its own NAL unit. Using multiple slices limits the
+/// size of each NAL unit, which is useful for packetization and error
+/// resilience.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum SliceMode {
+    /// Encode each picture as a single slice.
+    #[default]
+    Single,
+    /// Each slice contains at most this many rows of macroblocks.
+    MacroblockRows(NonZeroU32),
+    /// Each slice NAL unit is at most this many bytes.
+    ///
+    /// The size includes the NAL unit header byte but not any start code or
+    /// length prefix. To guarantee this limit, the worst case overhead of
+    /// emulation prevention is assumed. [LessEncoder::new_with_config] returns
+    /// an error if a single macroblock cannot fit.
+    MaxBytes(usize),
+}
 
 /// Convert input images [YCbCrImage] into H.264 NAL units [NalUnit].
 ///
@@ -23,8 +113,23 @@ pub struct LessEncoder {
     height: u32,
     mbs_width: usize,
     mbs_height: usize,
+    bit_depth: BitDepth,
+    /// The maximum number of macroblocks in each slice.
+    mbs_per_slice: usize,
     sps: Sps,
     pps: Pps,
+    gop: GopConfig,
+    /// The `idr_pic_id` of the next IDR picture.
+    next_idr_pic_id: u32,
+    /// The number of frames encoded since the last IDR picture, if any.
+    ///
+    /// This does not wrap, so that long IDR intervals are kept.
+    frames_since_idr: Option<u64>,
+    /// If true, the next frame will be an IDR picture.
+    force_keyframe: bool,
+    /// Buffers for bands of macroblocks, reused across frames.
+    #[cfg(feature = "rayon")]
+    band_buffers: Vec<Vec<u8>>,
 }
 
 impl LessEncoder {
@@ -32,7 +137,112 @@ impl LessEncoder {
     ///
     /// The sequence parameter set and picture parameter set are inferred from
     /// the input [YCbCrImage].
+    #[cfg(feature = "alloc")]
     pub fn new(y4m_frame: &YCbCrImage) -> Result<(InitialNalUnits, Self)> {
+        Self::new_with_config(y4m_frame, EncoderConfig::default())
+    }
+
+    /// Initialize an encoder with options and encode first frame.
+    ///
+    /// The sequence parameter set and picture parameter set are inferred from
+    /// the input [YCbCrImage] and `config`.
+    #[cfg(feature = "alloc")]
+    pub fn new_with_config(
+        y4m_frame: &YCbCrImage,
+        config: EncoderConfig,
+    ) -> Result<(InitialNalUnits, Self)> {
+        let mut self_ = Self::configure(y4m_frame, config)?;
+        let (sps_nal_unit, pps_nal_unit) = self_.parameter_set_nal_units();
+        let frame_nal_unit = self_.encode(y4m_frame)?;
+        let nal_units = InitialNalUnits {
+            sps: sps_nal_unit,
+            pps: pps_nal_unit,
+            frame: frame_nal_unit,
+        };
+        Ok((nal_units, self_))
+    }
+
+    /// Initialize an encoder with options and stream the sequence parameter
+    /// set, picture parameter set and first frame to `sink`.
+    ///
+    /// Like [Self::encode_to_sink], this does not hold the encoded frame in
+    /// memory.
+    #[cfg(feature = "alloc")]
+    pub fn new_to_sink<S: NalSink + ?Sized>(
+        y4m_frame: &YCbCrImage,
+        config: EncoderConfig,
+        sink: &mut S,
+    ) -> Result<Self> {
+        let mut self_ = Self::configure(y4m_frame, config)?;
+        let (sps_nal_unit, pps_nal_unit) = self_.parameter_set_nal_units();
+        sink.write_nal_bytes(&sps_nal_unit.to_annex_b_data())?;
+        sink.write_nal_bytes(&pps_nal_unit.to_annex_b_data())?;
+        self_.encode_to_sink(y4m_frame, sink)?;
+        Ok(self_)
+    }
+
+    /// Initialize an encoder with options and write the sequence parameter
+    /// set, picture parameter set and first frame into `dest`.
+    ///
+    /// This does not allocate. `dest` must be at least
+    /// [Self::MAX_PARAMETER_SETS_SIZE] plus [Self::max_encoded_size] bytes
+    /// long, otherwise [Error::BufferTooSmall] is returned.
+    ///
+    /// Returns the number of bytes written and the encoder.
+    pub fn new_into_slice(
+        y4m_frame: &YCbCrImage,
+        config: EncoderConfig,
+        framing: NalFraming,
+        dest: &mut [u8],
+    ) -> Result<(usize, Self)> {
+        let mut self_ = Self::configure(y4m_frame, config)?;
+        let needed = Self::MAX_PARAMETER_SETS_SIZE + self_.max_encoded_size();
+        if dest.len() < needed {
+            return Err(Error::BufferTooSmall {
+                needed,
+                #[cfg(feature = "backtrace")]
+                backtrace: Backtrace::capture(),
+            });
+        }
+        let mut cursor = SliceCursor::new(dest);
+        for (unit_type, rbsp) in [
+            (NalUnitType::SequenceParameterSet, self_.sps.to_rbsp_array()),
+            (NalUnitType::PictureParameterSet, self_.pps.to_rbsp_array()),
+        ] {
+            let start = begin_nal(
+                framing,
+                nal_header_byte(&NalRefIdc::Three, &unit_type),
+                &mut cursor,
+            );
+            EbspWriter::new(&mut cursor).put(rbsp.as_bytes());
+            end_nal(framing, start, &mut cursor);
+        }
+        let param_size = cursor.position();
+        let (frame_size, _) =
+            self_.encode_into_slice(y4m_frame, framing, &mut dest[param_size..])?;
+        Ok((param_size + frame_size, self_))
+    }
+
+    /// Create an encoder which continues an existing stream with the same
+    /// parameter sets, without encoding a frame.
+    ///
+    /// The last IDR picture of the stream had `last_idr_pic_id` and was
+    /// followed by `frames_since_idr - 1` other frames.
+    #[cfg(feature = "std")]
+    pub(crate) fn new_continuing(
+        y4m_frame: &YCbCrImage,
+        config: EncoderConfig,
+        last_idr_pic_id: u32,
+        frames_since_idr: u64,
+    ) -> Result<Self> {
+        let mut self_ = Self::configure(y4m_frame, config)?;
+        self_.next_idr_pic_id = (last_idr_pic_id + 1) % (MAX_IDR_PIC_ID + 1);
+        self_.frames_since_idr = Some(frames_since_idr);
+        Ok(self_)
+    }
+
+    /// Create the encoder without encoding a frame.
+    fn configure(y4m_frame: &YCbCrImage, config: EncoderConfig) -> Result<Self> {
         let width = y4m_frame.width;
         let height = y4m_frame.height;
 
@@ -112,26 +322,27 @@ impl LessEncoder {
         };
 
         // SPS
-        let sps = Sps::new(
+        let mut vui = Vui::new(true);
+        let mut sps = Sps::new(
             profile_idc,
             pic_width_in_mbs_minus1,
             pic_height_in_map_units_minus1,
             frame_cropping,
-            Some(Vui::new(true)),
-        );
-        let sps_nal_unit = NalUnit::new(
-            NalRefIdc::Three,
-            NalUnitType::SequenceParameterSet,
-            sps.to_rbsp(),
+            None,
         );
+        sps.pic_order_cnt_type = config.pic_order_cnt_type;
+        if !config.gop.all_idr() {
+            // Non-IDR pictures are reference pictures.
+            sps.max_num_ref_frames = 1;
+        }
+        if config.low_latency {
+            vui.bitstream_restriction =
+                Some(BitstreamRestriction::low_latency(sps.max_num_ref_frames));
+        }
+        sps.vui = Some(vui);
 
         // PPS
         let pps = Pps::new(0);
-        let pps_nal_unit = NalUnit::new(
-            NalRefIdc::Three,
-            NalUnitType::PictureParameterSet,
-            pps.to_rbsp(),
-        );
 
         let mbs_width = (pic_width_in_mbs_minus1 + 1).try_into().unwrap();
         let mbs_height = (pic_height_in_map_units_minus1 + 1).try_into().unwrap();
@@ -141,60 +352,445 @@ impl LessEncoder {
             height,
             mbs_width,
             mbs_height,
+            bit_depth,
+            mbs_per_slice: mbs_width * mbs_height,
             sps,
             pps,
+            gop: config.gop,
+            next_idr_pic_id: 0,
+            frames_since_idr: None,
+            force_keyframe: false,
+            #[cfg(feature = "rayon")]
+            band_buffers: Vec::new(),
         };
 
-        let frame_nal_unit = self_.encode(y4m_frame)?;
-        let nal_units = InitialNalUnits {
-            sps: sps_nal_unit,
-            pps: pps_nal_unit,
-            frame: frame_nal_unit,
-        };
-        Ok((nal_units, self_))
+        self_.mbs_per_slice = self_.calc_mbs_per_slice(&config.slice_mode)?;
+
+        Ok(self_)
+    }
+
+    /// The sequence parameter set and picture parameter set NAL units.
+    #[cfg(feature = "alloc")]
+    pub(crate) fn parameter_set_nal_units(&self) -> (NalUnit, NalUnit) {
+        let sps_nal_unit = NalUnit::new(
+            NalRefIdc::Three,
+            NalUnitType::SequenceParameterSet,
+            self.sps.to_rbsp(),
+        );
+        let pps_nal_unit = NalUnit::new(
+            NalRefIdc::Three,
+            NalUnitType::PictureParameterSet,
+            self.pps.to_rbsp(),
+        );
+        (sps_nal_unit, pps_nal_unit)
+    }
+
+    /// An upper bound on the size of the parameter sets written by
+    /// [Self::new_into_slice], including framing.
+    pub const MAX_PARAMETER_SETS_SIZE: usize = 2 * max_nal_size(MAX_HEADER_SIZE);
+
+    /// An upper bound on the size of an encoded frame, including framing.
+    ///
+    /// This is a `const fn` so that buffers can be sized at compile time. The
+    /// frame is `width` by `height` pixels, monochrome or 4:2:0 YCbCr, and
+    /// divided into `num_slices` slices. With [SliceMode::Single], this is 1.
+    /// With [SliceMode::MacroblockRows], it is the number of macroblock rows
+    /// (`height` divided by 16, rounded up) divided by the rows per slice,
+    /// rounded up. A `num_slices` of 0 is treated as 1, as every frame has at
+    /// least one slice. [Self::max_encoded_size] gives the bound for the
+    /// configuration of an encoder.
+    pub const fn max_frame_size(
+        width: u32,
+        height: u32,
+        bit_depth: BitDepth,
+        monochrome: bool,
+        num_slices: usize,
+    ) -> usize {
+        let num_slices = if num_slices == 0 { 1 } else { num_slices };
+        let num_macroblocks = (width.div_ceil(16) * height.div_ceil(16)) as usize;
+        let mb_size = I_PCM_HEADER_SIZE + pcm_macroblock_size(bit_depth, monochrome);
+        // Each slice has a header and a stop bit. As `max_nal_size` is linear,
+        // the slices can be summed before applying it.
+        let rbsp_size = num_slices * (MAX_HEADER_SIZE + 1) + num_macroblocks * mb_size;
+        MAX_RECOVERY_POINT_NAL_SIZE + max_nal_size(rbsp_size) + (num_slices - 1) * NAL_OVERHEAD
+    }
+
+    /// An upper bound on the size of a frame encoded by this encoder,
+    /// including framing.
+    ///
+    /// Buffers of this size can be passed to [Self::encode_into_slice].
+    pub fn max_encoded_size(&self) -> usize {
+        let num_macroblocks = self.mbs_width * self.mbs_height;
+        Self::max_frame_size(
+            self.width,
+            self.height,
+            self.bit_depth,
+            self.sps.profile_idc.is_monochrome(),
+            num_macroblocks.div_ceil(self.mbs_per_slice),
+        )
+    }
+
+    /// Encode the next frame as an IDR picture.
+    ///
+    /// This has no effect if the next frame would be an IDR picture anyway.
+    pub fn force_keyframe(&mut self) {
+        self.force_keyframe = true;
+    }
+
+    /// Encode a frame, converting an input image [YCbCrImage] into
+    /// [EncodedFrame].
+    #[cfg(feature = "alloc")]
+    pub fn encode(&mut self, y4m_frame: &YCbCrImage) -> Result<EncodedFrame> {
+        y4m_frame.check_sizes()?;
+
+        debug_assert_eq!(self.width, y4m_frame.width);
+        debug_assert_eq!(self.height, y4m_frame.height);
+
+        let slice_header = self.next_slice_header();
+        Ok(self.encode_picture(&slice_header, y4m_frame))
+    }
+
+    /// Encode a frame, appending its NAL units with the given framing to
+    /// `dest`.
+    ///
+    /// The result is identical to calling [Self::encode] and writing each NAL
+    /// unit, but the macroblocks are copied directly into `dest` with emulation
+    /// prevention applied on the way. Reusing `dest` across frames avoids
+    /// allocation once it has grown to the size of a frame.
+    ///
+    /// Returns whether the frame is an IDR picture.
+    #[cfg(feature = "alloc")]
+    pub fn encode_into(
+        &mut self,
+        y4m_frame: &YCbCrImage,
+        framing: NalFraming,
+        dest: &mut Vec<u8>,
+    ) -> Result<bool> {
+        y4m_frame.check_sizes()?;
+
+        debug_assert_eq!(self.width, y4m_frame.width);
+        debug_assert_eq!(self.height, y4m_frame.height);
+
+        let slice_header = self.next_slice_header();
+        Ok(self.encode_picture_into(&slice_header, y4m_frame, framing, dest))
+    }
+
+    /// Encode a frame, writing its NAL units with the given framing into
+    /// `dest`.
+    ///
+    /// This is the same as [Self::encode_into] but does not allocate (except
+    /// for buffers reused across frames with the `rayon` feature). `dest`
+    /// must be at least [Self::max_encoded_size] bytes long, otherwise
+    /// [Error::BufferTooSmall] is returned.
+    ///
+    /// Returns the number of bytes written and whether the frame is an IDR
+    /// picture.
+    pub fn encode_into_slice(
+        &mut self,
+        y4m_frame: &YCbCrImage,
+        framing: NalFraming,
+        dest: &mut [u8],
+    ) -> Result<(usize, bool)> {
+        y4m_frame.check_sizes()?;
+
+        debug_assert_eq!(self.width, y4m_frame.width);
+        debug_assert_eq!(self.height, y4m_frame.height);
+
+        // Checking the worst case up front guarantees that writing cannot
+        // overflow `dest`.
+        let needed = self.max_encoded_size();
+        if dest.len() < needed {
+            return Err(Error::BufferTooSmall {
+                needed,
+                #[cfg(feature = "backtrace")]
+                backtrace: Backtrace::capture(),
+            });
+        }
+
+        let slice_header = self.next_slice_header();
+        let mut cursor = SliceCursor::new(dest);
+        let is_idr = self.encode_picture_into(&slice_header, y4m_frame, framing, &mut cursor);
+        Ok((cursor.position(), is_idr))
     }
 
-    /// Encode a frame, converting an input image [YCbCrImage] into [NalUnit].
-    pub fn encode(&mut self, y4m_frame: &YCbCrImage) -> Result<NalUnit> {
+    /// Append the NAL units of a picture with the given header to `dest`.
+    ///
+    /// Returns whether the picture is an IDR picture.
+    fn encode_picture_into<S: NalBuffer>(
+        &mut self,
+        slice_header: &SliceHeader,
+        y4m_frame: &YCbCrImage,
+        framing: NalFraming,
+        dest: &mut S,
+    ) -> bool {
+        let is_idr = slice_header.idr_pic_id.is_some();
+        if !is_idr && self.gop.recovery_point_sei {
+            let nal_byte = nal_header_byte(
+                &NalRefIdc::Zero,
+                &NalUnitType::SupplementalEnhancementInformation,
+            );
+            let start = begin_nal(framing, nal_byte, dest);
+            RecoveryPoint::new(0).put_rbsp(&mut EbspWriter::new(dest));
+            end_nal(framing, start, dest);
+        }
+
+        #[cfg(feature = "rayon")]
+        let mut band_buffers = core::mem::take(&mut self.band_buffers);
+        let num_macroblocks = self.mbs_height * self.mbs_width;
+        for first_mb in (0..num_macroblocks).step_by(self.mbs_per_slice) {
+            let num_mbs = self.mbs_per_slice.min(num_macroblocks - first_mb);
+            self.encode_slice_into(
+                slice_header,
+                first_mb,
+                num_mbs,
+                y4m_frame,
+                framing,
+                dest,
+                #[cfg(feature = "rayon")]
+                &mut band_buffers,
+            );
+        }
+        #[cfg(feature = "rayon")]
+        {
+            self.band_buffers = band_buffers;
+        }
+
+        is_idr
+    }
+
+    /// Encode a frame, streaming its NAL units in Annex B format to `sink`.
+    ///
+    /// The result is identical to calling [Self::encode] and writing each NAL
+    /// unit with [NalUnit::to_annex_b_data]. Instead of the whole encoded
+    /// frame, at most about one row of macroblocks is held in memory. Each
+    /// macroblock is copied into this buffer with emulation prevention
+    /// applied on the way. Slices are encoded one after another, also with the
+    /// `rayon` feature.
+    ///
+    /// Returns whether the frame is an IDR picture.
+    #[cfg(feature = "alloc")]
+    pub fn encode_to_sink<S: NalSink + ?Sized>(
+        &mut self,
+        y4m_frame: &YCbCrImage,
+        sink: &mut S,
+    ) -> Result<bool> {
         y4m_frame.check_sizes()?;
 
         debug_assert_eq!(self.width, y4m_frame.width);
         debug_assert_eq!(self.height, y4m_frame.height);
 
-        let mut slice_data = SliceHeader::new().to_rbsp(&self.sps, &self.pps);
+        let slice_header = self.next_slice_header();
+        let is_idr = slice_header.idr_pic_id.is_some();
+        let mb_size =
+            MacroblockType::I_PCM.as_encoded_macroblock_header().len() + self.macroblock_size();
+        let mut row_buf = BufferedSink::new(sink, self.mbs_width * mb_size);
+
+        if !is_idr && self.gop.recovery_point_sei {
+            let sei = SupplementalEnhancementInformation::RecoveryPoint(RecoveryPoint::new(0));
+            NalUnit::new(
+                NalRefIdc::Zero,
+                NalUnitType::SupplementalEnhancementInformation,
+                sei.to_rbsp(),
+            )
+            .put_annex_b(&mut row_buf);
+        }
 
         let luma_only = self.sps.profile_idc.is_monochrome();
+        let num_macroblocks = self.mbs_height * self.mbs_width;
+        for first_mb in (0..num_macroblocks).step_by(self.mbs_per_slice) {
+            let num_mbs = self.mbs_per_slice.min(num_macroblocks - first_mb);
+            let mut slice_header = slice_header.clone();
+            slice_header.first_mb_in_slice = first_mb.try_into().unwrap();
+
+            row_buf.put(&[0x00, 0x00, 0x00, 0x01]);
+            row_buf.put(&[nal_header_byte(
+                &NalRefIdc::One,
+                &slice_header.nal_unit_type(),
+            )]);
+            let mut ebsp = EbspWriter::new(&mut row_buf);
+            ebsp.put(&slice_header.to_rbsp(&self.sps, &self.pps).data);
+            for mb_addr in first_mb..(first_mb + num_mbs) {
+                // The type of the first macroblock is in the slice header.
+                macroblock(
+                    mb_addr / self.mbs_width,
+                    mb_addr % self.mbs_width,
+                    mb_addr == first_mb,
+                    &mut ebsp,
+                    y4m_frame,
+                    luma_only,
+                );
+            }
+            ebsp.put(&[0x80]); // slice stop bit
+        }
+
+        row_buf.finish()?;
+        Ok(is_idr)
+    }
+
+    /// Encode several frames, converting input images [YCbCrImage] into
+    /// [EncodedFrame]s.
+    ///
+    /// The result is identical to calling [Self::encode] for each frame in
+    /// order. With the `rayon` feature, the frames are encoded in parallel.
+    ///
+    /// All frames are checked before any is encoded, so upon error, the
+    /// encoder state is unchanged.
+    #[cfg(feature = "alloc")]
+    pub fn encode_batch(&mut self, y4m_frames: &[YCbCrImage]) -> Result<Vec<EncodedFrame>> {
+        for y4m_frame in y4m_frames.iter() {
+            y4m_frame.check_sizes()?;
+
+            debug_assert_eq!(self.width, y4m_frame.width);
+            debug_assert_eq!(self.height, y4m_frame.height);
+        }
+
+        // Header state depends on previous frames and thus is determined
+        // sequentially.
+        let slice_headers: Vec<SliceHeader> = y4m_frames
+            .iter()
+            .map(|_| self.next_slice_header())
+            .collect();
+
+        #[cfg(feature = "rayon")]
+        let encoded = slice_headers
+            .par_iter()
+            .zip(y4m_frames.par_iter())
+            .map(|(slice_header, y4m_frame)| self.encode_picture(slice_header, y4m_frame))
+            .collect();
+        #[cfg(not(feature = "rayon"))]
+        let encoded = slice_headers
+            .iter()
+            .zip(y4m_frames.iter())
+            .map(|(slice_header, y4m_frame)| self.encode_picture(slice_header, y4m_frame))
+            .collect();
+        Ok(encoded)
+    }
 
+    /// Encode a picture with the given header.
+    ///
+    /// This does not depend on or modify the per-stream state.
+    #[cfg(feature = "alloc")]
+    fn encode_picture(&self, slice_header: &SliceHeader, y4m_frame: &YCbCrImage) -> EncodedFrame {
+        let is_idr = slice_header.idr_pic_id.is_some();
         let num_macroblocks = self.mbs_height * self.mbs_width;
+        let mut nal_units = Vec::with_capacity(1 + num_macroblocks.div_ceil(self.mbs_per_slice));
+        if !is_idr && self.gop.recovery_point_sei {
+            let sei = SupplementalEnhancementInformation::RecoveryPoint(RecoveryPoint::new(0));
+            nal_units.push(NalUnit::new(
+                NalRefIdc::Zero,
+                NalUnitType::SupplementalEnhancementInformation,
+                sei.to_rbsp(),
+            ));
+        }
+
+        let slice_nal_unit = |first_mb| {
+            let num_mbs = self.mbs_per_slice.min(num_macroblocks - first_mb);
+            let slice_data = self.encode_slice(slice_header, first_mb, num_mbs, y4m_frame);
+            NalUnit::new(NalRefIdc::One, slice_header.nal_unit_type(), slice_data)
+        };
+
+        // Slices are independent, so they can be encoded in parallel.
+        #[cfg(feature = "rayon")]
+        nal_units.par_extend(
+            (0..num_macroblocks)
+                .into_par_iter()
+                .step_by(self.mbs_per_slice)
+                .map(slice_nal_unit),
+        );
+        #[cfg(not(feature = "rayon"))]
+        nal_units.extend(
+            (0..num_macroblocks)
+                .step_by(self.mbs_per_slice)
+                .map(slice_nal_unit),
+        );
 
-        // reserve space for frame without requiring reallocation
-        let row_sz = match y4m_frame.luma_bit_depth() {
-            BitDepth::Depth8 => 16,
-            BitDepth::Depth12 => 24,
+        EncodedFrame { nal_units, is_idr }
+    }
+
+    /// Calculate the maximum number of macroblocks per slice.
+    fn calc_mbs_per_slice(&self, slice_mode: &SliceMode) -> Result<usize> {
+        let num_macroblocks = self.mbs_width * self.mbs_height;
+        let mbs_per_slice = match slice_mode {
+            SliceMode::Single => num_macroblocks,
+            SliceMode::MacroblockRows(rows) => {
+                let rows: usize = rows.get().try_into().unwrap();
+                rows.saturating_mul(self.mbs_width)
+            }
+            SliceMode::MaxBytes(max_bytes) => {
+                // The largest possible slice header, including the first
+                // macroblock type.
+                let mut worst_header = SliceHeader::new();
+                worst_header.first_mb_in_slice = (num_macroblocks - 1).try_into().unwrap();
+                worst_header.idr_pic_id = Some(MAX_IDR_PIC_ID);
+                let header_size = worst_header
+                    .to_rbsp_array(&self.sps, &self.pps)
+                    .as_bytes()
+                    .len();
+
+                // Emulation prevention adds at most one byte per two RBSP
+                // bytes. One byte is for the NAL unit header.
+                let max_rbsp_size = max_bytes.saturating_sub(1) * 2 / 3;
+
+                // With n macroblocks, the RBSP size is
+                // `header_size + n * (mb_header + mb_size) - mb_header + 1`.
+                let mb_header_size = MacroblockType::I_PCM.as_encoded_macroblock_header().len();
+                let n = (max_rbsp_size + mb_header_size).saturating_sub(header_size + 1)
+                    / (mb_header_size + self.macroblock_size());
+                if n == 0 {
+                    return Err(Error::InvalidConfiguration {
+                        msg: "maximum slice size too small for a single macroblock",
+                        #[cfg(feature = "backtrace")]
+                        backtrace: Backtrace::capture(),
+                    });
+                }
+                n
+            }
         };
+        Ok(mbs_per_slice.min(num_macroblocks))
+    }
+
+    /// The size of the data in a macroblock, excluding its header.
+    fn macroblock_size(&self) -> usize {
+        pcm_macroblock_size(self.bit_depth, self.sps.profile_idc.is_monochrome())
+    }
+
+    /// Encode `num_mbs` macroblocks starting at `first_mb` into one slice.
+    #[cfg(feature = "alloc")]
+    fn encode_slice(
+        &self,
+        picture_header: &SliceHeader,
+        first_mb: usize,
+        num_mbs: usize,
+        y4m_frame: &YCbCrImage,
+    ) -> RbspData {
+        let mut slice_header = picture_header.clone();
+        slice_header.first_mb_in_slice = first_mb.try_into().unwrap();
+        let mut slice_data = slice_header.to_rbsp(&self.sps, &self.pps);
+
+        let luma_only = self.sps.profile_idc.is_monochrome();
+
+        // reserve space for slice without requiring reallocation
         let orig_len = slice_data.data.len();
 
         // space for macroblock data
-        let mut reserve_size = if luma_only {
-            // luma only in output
-            num_macroblocks * row_sz * 16
-        } else {
-            // 4:2:0
-            num_macroblocks * row_sz * 16 * 3 / 2
-        };
+        let mut reserve_size = num_mbs * self.macroblock_size();
 
         // space for header and final slice stop bit
         reserve_size +=
-            (num_macroblocks - 1) * MacroblockType::I_PCM.as_encoded_macroblock_header().len() + 1;
+            (num_mbs - 1) * MacroblockType::I_PCM.as_encoded_macroblock_header().len() + 1;
 
         slice_data.data.reserve(reserve_size);
 
-        for mbs_row in 0..self.mbs_height {
-            for mbs_col in 0..self.mbs_width {
-                // todo: look at mb_skip_flag and mb_skip_run for luma only images.
-                macroblock(mbs_row, mbs_col, &mut slice_data, y4m_frame, luma_only);
-            }
-        }
+        // The type of the first macroblock is in the slice header.
+        macroblock(
+            first_mb / self.mbs_width,
+            first_mb % self.mbs_width,
+            true,
+            &mut slice_data.data,
+            y4m_frame,
+            luma_only,
+        );
+        self.macroblocks_with_header(first_mb + 1, num_mbs - 1, &mut slice_data.data, y4m_frame);
 
         slice_data.data.push(0x80); // slice stop bit
 
@@ -203,10 +799,237 @@ impl LessEncoder {
 
         debug_assert_eq!(should_have_reserved, reserve_size);
 
-        Ok(NalUnit::new(
-            NalRefIdc::One,
-            NalUnitType::CodedSliceOfAnIDRPicture,
-            slice_data,
-        ))
+        slice_data
+    }
+
+    /// Append `num_mbs` macroblocks starting at `first_mb` as one slice NAL
+    /// unit with the given framing to `dest`.
+    #[allow(clippy::too_many_arguments)]
+    fn encode_slice_into<S: NalBuffer>(
+        &self,
+        picture_header: &SliceHeader,
+        first_mb: usize,
+        num_mbs: usize,
+        y4m_frame: &YCbCrImage,
+        framing: NalFraming,
+        dest: &mut S,
+        #[cfg(feature = "rayon")] band_buffers: &mut Vec<Vec<u8>>,
+    ) {
+        let mut slice_header = picture_header.clone();
+        slice_header.first_mb_in_slice = first_mb.try_into().unwrap();
+        let header_data = slice_header.to_rbsp_array(&self.sps, &self.pps);
+        let header_data = header_data.as_bytes();
+
+        let luma_only = self.sps.profile_idc.is_monochrome();
+
+        // Reserve space for the framing, the NAL unit header and the worst
+        // case of emulation prevention.
+        let rbsp_size = header_data.len()
+            + num_mbs * self.macroblock_size()
+            + (num_mbs - 1) * I_PCM_HEADER_SIZE
+            + 1;
+        dest.reserve(max_nal_size(rbsp_size));
+
+        let nal_byte = nal_header_byte(&NalRefIdc::One, &slice_header.nal_unit_type());
+        let start = begin_nal(framing, nal_byte, dest);
+        let mut ebsp = EbspWriter::new(dest);
+        ebsp.put(header_data);
+
+        // The type of the first macroblock is in the slice header.
+        macroblock(
+            first_mb / self.mbs_width,
+            first_mb % self.mbs_width,
+            true,
+            &mut ebsp,
+            y4m_frame,
+            luma_only,
+        );
+        #[cfg(feature = "rayon")]
+        self.escaped_macroblocks_with_header(
+            first_mb + 1,
+            num_mbs - 1,
+            &mut ebsp,
+            y4m_frame,
+            band_buffers,
+        );
+        #[cfg(not(feature = "rayon"))]
+        self.macroblocks_with_header(first_mb + 1, num_mbs - 1, &mut ebsp, y4m_frame);
+
+        ebsp.put(&[0x80]); // slice stop bit
+        end_nal(framing, start, dest);
+    }
+
+    /// Append `num_mbs` macroblocks starting at `first_mb` to `dest`, each
+    /// with a macroblock header.
+    #[cfg(not(feature = "rayon"))]
+    fn macroblocks_with_header<S: ByteSink>(
+        &self,
+        first_mb: usize,
+        num_mbs: usize,
+        dest: &mut S,
+        y4m_frame: &YCbCrImage,
+    ) {
+        let luma_only = self.sps.profile_idc.is_monochrome();
+        for mb_addr in first_mb..(first_mb + num_mbs) {
+            // todo: look at mb_skip_flag and mb_skip_run for luma only images.
+            macroblock(
+                mb_addr / self.mbs_width,
+                mb_addr % self.mbs_width,
+                false,
+                dest,
+                y4m_frame,
+                luma_only,
+            );
+        }
+    }
+
+    /// Append `num_mbs` macroblocks starting at `first_mb` to `dest`, each
+    /// with a macroblock header.
+    ///
+    /// Every macroblock has the same size, so bands of macroblocks are written
+    /// in parallel directly into their final location.
+    #[cfg(feature = "rayon")]
+    fn macroblocks_with_header(
+        &self,
+        first_mb: usize,
+        num_mbs: usize,
+        dest: &mut Vec<u8>,
+        y4m_frame: &YCbCrImage,
+    ) {
+        let luma_only = self.sps.profile_idc.is_monochrome();
+        let mb_size =
+            MacroblockType::I_PCM.as_encoded_macroblock_header().len() + self.macroblock_size();
+        let start = dest.len();
+        dest.resize(start + num_mbs * mb_size, 0);
+        dest[start..]
+            .par_chunks_mut(self.mbs_width * mb_size)
+            .enumerate()
+            .for_each(|(band_idx, band)| {
+                let band_first_mb = first_mb + band_idx * self.mbs_width;
+                let band_num_mbs = band.len() / mb_size;
+                let mut cursor = crate::sink::SliceCursor::new(band);
+                for mb_addr in band_first_mb..(band_first_mb + band_num_mbs) {
+                    macroblock(
+                        mb_addr / self.mbs_width,
+                        mb_addr % self.mbs_width,
+                        false,
+                        &mut cursor,
+                        y4m_frame,
+                        luma_only,
+                    );
+                }
+                debug_assert_eq!(cursor.position(), band_num_mbs * mb_size);
+            });
+    }
+
+    /// Append `num_mbs` macroblocks starting at `first_mb` to `dest`, each
+    /// with a macroblock header.
+    ///
+    /// Emulation prevention is applied to bands of macroblocks in parallel,
+    /// each in one of `band_buffers`. This is possible because each band
+    /// starts with a macroblock header, which never needs protecting.
+    #[cfg(feature = "rayon")]
+    fn escaped_macroblocks_with_header<S: ByteSink>(
+        &self,
+        first_mb: usize,
+        num_mbs: usize,
+        dest: &mut EbspWriter<S>,
+        y4m_frame: &YCbCrImage,
+        band_buffers: &mut Vec<Vec<u8>>,
+    ) {
+        let luma_only = self.sps.profile_idc.is_monochrome();
+        let num_bands = num_mbs.div_ceil(self.mbs_width);
+        if band_buffers.len() < num_bands {
+            band_buffers.resize_with(num_bands, Vec::new);
+        }
+        band_buffers[..num_bands]
+            .par_iter_mut()
+            .enumerate()
+            .for_each(|(band_idx, band)| {
+                let band_first_mb = first_mb + band_idx * self.mbs_width;
+                let band_num_mbs = self.mbs_width.min(first_mb + num_mbs - band_first_mb);
+                band.clear();
+                let mut ebsp = EbspWriter::new(band);
+                for mb_addr in band_first_mb..(band_first_mb + band_num_mbs) {
+                    macroblock(
+                        mb_addr / self.mbs_width,
+                        mb_addr % self.mbs_width,
+                        false,
+                        &mut ebsp,
+                        y4m_frame,
+                        luma_only,
+                    );
+                }
+            });
+        for band in band_buffers[..num_bands].iter() {
+            dest.put_escaped(band);
+        }
+    }
+
+    /// Advance the per-stream state and return the slice header for the next
+    /// picture.
+    fn next_slice_header(&mut self) -> SliceHeader {
+        let is_idr = match (self.frames_since_idr, self.gop.idr_interval) {
+            (None, _) => true,
+            _ if self.force_keyframe => true,
+            (Some(n), Some(interval)) => n >= u64::from(interval.get()),
+            (Some(_), None) => false,
+        };
+        self.force_keyframe = false;
+
+        let mut hdr = SliceHeader::new();
+        if is_idr {
+            // An IDR picture resets `frame_num` and the picture order count.
+            // Consecutive IDR pictures must differ in `idr_pic_id`.
+            hdr.idr_pic_id = Some(self.next_idr_pic_id);
+            self.next_idr_pic_id = (self.next_idr_pic_id + 1) % (MAX_IDR_PIC_ID + 1);
+            self.frames_since_idr = Some(0);
+        } else {
+            // Every picture is a reference picture, so `frame_num` increments
+            // with each. The picture order count increments by 2 per frame.
+            // Both wrap, as `MaxFrameNum` and `MaxPicOrderCntLsb` divide
+            // 1 << 16.
+            let n = (self.frames_since_idr.unwrap() % (1 << 16)) as u32;
+            hdr.idr_pic_id = None;
+            hdr.frame_num = n;
+            hdr.pic_order_cnt = 2 * n;
+        }
+        self.frames_since_idr = self.frames_since_idr.map(|n| n + 1);
+        hdr
+    }
+}
+
+/// The largest allowed value of `idr_pic_id`.
+const MAX_IDR_PIC_ID: u32 = 65535;
+
+/// The size of the encoded I_PCM macroblock type.
+const I_PCM_HEADER_SIZE: usize = MacroblockType::I_PCM.as_encoded_macroblock_header().len();
+
+/// The size of the start code or length prefix and NAL unit header byte.
+const NAL_OVERHEAD: usize = 5;
+
+/// An upper bound on the size of a NAL unit with the given RBSP size,
+/// including framing.
+const fn max_nal_size(rbsp_size: usize) -> usize {
+    // Emulation prevention adds at most one byte per two RBSP bytes.
+    NAL_OVERHEAD + rbsp_size + rbsp_size / 2
+}
+
+/// An upper bound on the size of the recovery point SEI NAL unit, including
+/// framing.
+const MAX_RECOVERY_POINT_NAL_SIZE: usize = max_nal_size(16);
+
+/// The size of the data in an I_PCM macroblock, excluding its header.
+const fn pcm_macroblock_size(bit_depth: BitDepth, monochrome: bool) -> usize {
+    let row_sz = match bit_depth {
+        BitDepth::Depth8 => 16,
+        BitDepth::Depth12 => 24,
+    };
+    if monochrome {
+        // luma only in output
+        row_sz * 16
+    } else {
+        // 4:2:0
+        row_sz * 16 * 3 / 2
     }
 }
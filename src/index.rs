@@ -0,0 +1,648 @@
+// Copyright 2022-2023 Andrew D. Straw.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT
+// or http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Random access to the frames of Annex B byte streams, such as `.h264` files
+//!
+//! A [FrameIndex] holds the position of each access unit (frame) in a stream.
+//! It can be built by scanning the stream once, saved to and loaded from a
+//! sidecar file, or written while recording with
+//! [crate::H264Writer::set_index_sidecar]. [IndexedReader] uses it to decode
+//! any frame with a single seek.
+//!
+//! Sidecar files are written in one of two formats selected by [IndexFormat].
+//! The binary format starts with the 8 bytes `LAVCIDX1`, followed by a record
+//! for each frame, in order, with the following little-endian fields:
+//!
+//! | bytes   | field                                              |
+//! |---------|----------------------------------------------------|
+//! | 0..8    | `offset` (u64)                                     |
+//! | 8..12   | `size` (u32)                                       |
+//! | 12..16  | flags (u32): bit 0 `is_sync`, bit 1 has timestamp  |
+//! | 16..24  | `timestamp` (u64), zero if absent                  |
+//! | 24..28  | number of NAL units `n` (u32)                      |
+//! | 28..    | `nal_sizes` (`n` times u32)                        |
+//!
+//! The CSV format has the header line
+//! `frame_number,offset,size,is_sync,timestamp,nal_sizes` followed by a line
+//! for each frame. `is_sync` is `0` or `1`, `timestamp` is empty if absent and
+//! `nal_sizes` are separated by spaces.
+
+use std::{
+    fs::File,
+    io::{Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    annex_b::{AnnexBReader, AnnexBSliceReader},
+    nal_unit::{NalUnit, NalUnitType},
+    sei::SupplementalEnhancementInformation,
+    ycbcr_image::OwnedYCbCrImage,
+    Error, LessDecoder, Result,
+};
+
+const MAGIC: [u8; 8] = *b"LAVCIDX1";
+/// The size of a binary record without the NAL unit sizes.
+const RECORD_HEADER_SIZE: usize = 28;
+const FLAG_SYNC: u32 = 0x01;
+const FLAG_TIMESTAMP: u32 = 0x02;
+const CSV_HEADER: &str = "frame_number,offset,size,is_sync,timestamp,nal_sizes\n";
+
+/// The format of a frame index sidecar file.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum IndexFormat {
+    /// Compact binary records.
+    #[default]
+    Binary,
+    /// Comma separated values with a header line.
+    Csv,
+}
+
+/// The position and properties of one access unit (frame) in a stream.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FrameIndexEntry {
+    /// The position of the first byte of the access unit in the stream,
+    /// including any parameter sets and SEI messages preceding the slices.
+    pub offset: u64,
+    /// The number of bytes in the access unit.
+    pub size: u32,
+    /// Whether the frame is a sync sample (an IDR picture).
+    pub is_sync: bool,
+    /// The time stamp of the frame.
+    ///
+    /// When the index is built from a stream, this is the precision time stamp
+    /// (MISB Standard 0604) in the SEI messages of the access unit, in
+    /// microseconds since the UNIX epoch. When written by
+    /// [crate::H264Writer], this is the value given to
+    /// [crate::H264Writer::write_with_timestamp].
+    pub timestamp: Option<u64>,
+    /// The number of bytes of each NAL unit of the access unit, including its
+    /// start code. These add up to `size`.
+    pub nal_sizes: Vec<u32>,
+}
+
+impl FrameIndexEntry {
+    fn put_record(&self, dest: &mut Vec<u8>) {
+        let mut flags = 0;
+        if self.is_sync {
+            flags |= FLAG_SYNC;
+        }
+        if self.timestamp.is_some() {
+            flags |= FLAG_TIMESTAMP;
+        }
+        dest.extend_from_slice(&self.offset.to_le_bytes());
+        dest.extend_from_slice(&self.size.to_le_bytes());
+        dest.extend_from_slice(&flags.to_le_bytes());
+        dest.extend_from_slice(&self.timestamp.unwrap_or(0).to_le_bytes());
+        dest.extend_from_slice(&(self.nal_sizes.len() as u32).to_le_bytes());
+        for nal_size in self.nal_sizes.iter() {
+            dest.extend_from_slice(&nal_size.to_le_bytes());
+        }
+    }
+
+    /// Parse the record at the start of `data`, returning it and its length,
+    /// or `None` if `data` holds only part of a record.
+    fn from_record(data: &[u8]) -> Option<(Self, usize)> {
+        let u32_at = |i: usize| u32::from_le_bytes(data[i..i + 4].try_into().unwrap());
+        let u64_at = |i: usize| u64::from_le_bytes(data[i..i + 8].try_into().unwrap());
+        if data.len() < RECORD_HEADER_SIZE {
+            return None;
+        }
+        let n_nal_units = u32_at(24) as usize;
+        let len = RECORD_HEADER_SIZE + 4 * n_nal_units;
+        if data.len() < len {
+            return None;
+        }
+        let flags = u32_at(12);
+        let entry = Self {
+            offset: u64_at(0),
+            size: u32_at(8),
+            is_sync: flags & FLAG_SYNC != 0,
+            timestamp: (flags & FLAG_TIMESTAMP != 0).then(|| u64_at(16)),
+            nal_sizes: (0..n_nal_units)
+                .map(|i| u32_at(RECORD_HEADER_SIZE + 4 * i))
+                .collect(),
+        };
+        Some((entry, len))
+    }
+
+    fn put_csv_line(&self, frame_number: u64, dest: &mut Vec<u8>) {
+        use std::fmt::Write;
+        let mut line = format!(
+            "{frame_number},{},{},{},",
+            self.offset,
+            self.size,
+            u8::from(self.is_sync)
+        );
+        if let Some(timestamp) = self.timestamp {
+            write!(line, "{timestamp}").unwrap();
+        }
+        line.push(',');
+        for (i, nal_size) in self.nal_sizes.iter().enumerate() {
+            if i > 0 {
+                line.push(' ');
+            }
+            write!(line, "{nal_size}").unwrap();
+        }
+        line.push('\n');
+        dest.extend_from_slice(line.as_bytes());
+    }
+
+    /// Parse a CSV line without the line terminator, returning the frame
+    /// number and entry.
+    fn from_csv_line(line: &str) -> Result<(u64, Self)> {
+        fn field<T: std::str::FromStr>(value: Option<&str>) -> Result<T> {
+            value
+                .and_then(|value| value.parse().ok())
+                .ok_or_else(|| invalid_index("malformed CSV line"))
+        }
+        let mut fields = line.split(',');
+        let frame_number = field(fields.next())?;
+        let offset = field(fields.next())?;
+        let size = field(fields.next())?;
+        let is_sync = match fields.next() {
+            Some("0") => false,
+            Some("1") => true,
+            _ => return Err(invalid_index("malformed CSV line")),
+        };
+        let timestamp = match fields.next() {
+            Some("") => None,
+            value => Some(field(value)?),
+        };
+        let nal_sizes = fields
+            .next()
+            .ok_or_else(|| invalid_index("malformed CSV line"))?
+            .split(' ')
+            .filter(|value| !value.is_empty())
+            .map(|value| field(Some(value)))
+            .collect::<Result<_>>()?;
+        if fields.next().is_some() {
+            return Err(invalid_index("malformed CSV line"));
+        }
+        let entry = Self {
+            offset,
+            size,
+            is_sync,
+            timestamp,
+            nal_sizes,
+        };
+        Ok((frame_number, entry))
+    }
+}
+
+fn invalid_index(msg: &'static str) -> Error {
+    Error::InvalidIndex {
+        msg,
+        #[cfg(feature = "backtrace")]
+        backtrace: std::backtrace::Backtrace::capture(),
+    }
+}
+
+/// The positions of the access units (frames) in an Annex B byte stream.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FrameIndex {
+    entries: Vec<FrameIndexEntry>,
+}
+
+impl FrameIndex {
+    /// Build the index by reading a stream from its current position to the
+    /// end.
+    ///
+    /// Offsets are positions in `rdr`, as used by [std::io::Seek]. A new
+    /// access unit starts with an access unit delimiter, parameter set or SEI
+    /// message following a slice, or with a slice whose `first_mb_in_slice`
+    /// is zero.
+    pub fn build<R: Read + Seek>(mut rdr: R) -> Result<Self> {
+        let start = rdr.stream_position()?;
+        let mut builder = IndexBuilder::default();
+        let mut nal_rdr = AnnexBReader::new(rdr);
+        while let Some((offset, ebsp)) = nal_rdr.next_ebsp()? {
+            builder.push(start + offset, ebsp)?;
+        }
+        let end = nal_rdr.into_inner().stream_position()?;
+        builder.finish(end)
+    }
+
+    /// Build the index of a stream held in memory.
+    ///
+    /// Offsets are positions in `data`. See [Self::build].
+    pub fn from_annex_b(data: &[u8]) -> Result<Self> {
+        let mut builder = IndexBuilder::default();
+        let mut nal_rdr = AnnexBSliceReader::new(data);
+        while let Some((offset, ebsp)) = nal_rdr.next_ebsp() {
+            builder.push(offset as u64, ebsp)?;
+        }
+        builder.finish(data.len() as u64)
+    }
+
+    /// Load an index from a sidecar file in either [IndexFormat].
+    ///
+    /// A partial record or line at the end, as left by an interrupted
+    /// recording, is ignored.
+    pub fn read_from<R: Read>(mut rdr: R) -> Result<Self> {
+        let mut data = Vec::new();
+        rdr.read_to_end(&mut data)?;
+        let mut entries = Vec::new();
+        if let Some(mut records) = data.strip_prefix(&MAGIC[..]) {
+            while let Some((entry, len)) = FrameIndexEntry::from_record(records) {
+                entries.push(entry);
+                records = &records[len..];
+            }
+        } else if let Some(lines) = data.strip_prefix(CSV_HEADER.as_bytes()) {
+            let lines =
+                std::str::from_utf8(lines).map_err(|_| invalid_index("CSV is not UTF-8"))?;
+            // The text after the last line terminator is incomplete.
+            let mut lines: Vec<&str> = lines.split('\n').collect();
+            lines.pop();
+            for line in lines {
+                let (frame_number, entry) =
+                    FrameIndexEntry::from_csv_line(line.trim_end_matches('\r'))?;
+                if frame_number != entries.len() as u64 {
+                    return Err(invalid_index("CSV frame numbers not consecutive"));
+                }
+                entries.push(entry);
+            }
+        } else {
+            return Err(invalid_index("unknown sidecar file format"));
+        }
+        Ok(Self { entries })
+    }
+
+    /// Save the index as a sidecar file in the given format.
+    pub fn write_to<W: Write>(&self, wtr: W, format: IndexFormat) -> Result<()> {
+        let mut wtr = FrameIndexWriter::new(wtr, format)?;
+        for entry in self.entries.iter() {
+            wtr.write_entry(entry)?;
+        }
+        Ok(())
+    }
+
+    /// The path of the sidecar file for the stream at `path`, which has
+    /// `.idx` appended, e.g. `movie.h264.idx` for `movie.h264`.
+    pub fn sidecar_path<P: AsRef<Path>>(path: P) -> PathBuf {
+        let mut sidecar = path.as_ref().as_os_str().to_owned();
+        sidecar.push(".idx");
+        sidecar.into()
+    }
+
+    /// The entries of all frames, in stream order.
+    pub fn entries(&self) -> &[FrameIndexEntry] {
+        &self.entries
+    }
+
+    /// The entry of frame `frame_number`, if it exists.
+    pub fn get(&self, frame_number: usize) -> Option<&FrameIndexEntry> {
+        self.entries.get(frame_number)
+    }
+
+    /// The number of frames.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Whether there are no frames.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+/// Groups NAL units into access units while building a [FrameIndex].
+#[derive(Default)]
+struct IndexBuilder {
+    entries: Vec<FrameIndexEntry>,
+    /// The access unit being built and whether it has a slice yet.
+    current: Option<(FrameIndexEntry, bool)>,
+    /// The offset of the last NAL unit.
+    nal_offset: u64,
+}
+
+impl IndexBuilder {
+    fn push(&mut self, offset: u64, ebsp: &[u8]) -> Result<()> {
+        let Some(&header) = ebsp.first() else {
+            // An empty NAL unit is counted as part of the previous one.
+            return Ok(());
+        };
+        self.finish_nal_unit(offset);
+        self.nal_offset = offset;
+        let unit_type = NalUnitType::from_nal_unit_type(header & 0x1f);
+        let has_slice = self
+            .current
+            .as_ref()
+            .is_some_and(|(_, has_slice)| *has_slice);
+        let starts_access_unit = match unit_type {
+            NalUnitType::SupplementalEnhancementInformation
+            | NalUnitType::SequenceParameterSet
+            | NalUnitType::PictureParameterSet
+            | NalUnitType::AccessUnitDelimiter
+            | NalUnitType::PrefixNalUnit
+            | NalUnitType::SubsetSequenceParameterSet => has_slice,
+            NalUnitType::Other(other) if (16..=18).contains(&other.value()) => has_slice,
+            // first_mb_in_slice is zero if the first bit of the slice header
+            // is set. The first byte cannot be an emulation prevention byte.
+            unit_type if unit_type.is_slice() => {
+                has_slice && ebsp.get(1).is_some_and(|b| b & 0x80 != 0)
+            }
+            _ => false,
+        };
+        if starts_access_unit || self.current.is_none() {
+            self.finish_access_unit(offset)?;
+            self.current = Some((
+                FrameIndexEntry {
+                    offset,
+                    size: 0,
+                    is_sync: false,
+                    timestamp: None,
+                    nal_sizes: Vec::new(),
+                },
+                false,
+            ));
+        }
+        let (entry, has_slice) = self.current.as_mut().unwrap();
+        match unit_type {
+            NalUnitType::CodedSliceOfAnIDRPicture => {
+                entry.is_sync = true;
+                *has_slice = true;
+            }
+            NalUnitType::SupplementalEnhancementInformation if entry.timestamp.is_none() => {
+                let nal_unit = NalUnit::from_nal_unit(ebsp)?;
+                let messages =
+                    SupplementalEnhancementInformation::from_rbsp(&nal_unit.rbsp_data().data)?;
+                entry.timestamp = messages.iter().find_map(|message| match message {
+                    SupplementalEnhancementInformation::UserDataUnregistered(udu) => {
+                        udu.precision_time_stamp()
+                    }
+                    _ => None,
+                });
+            }
+            unit_type if unit_type.is_slice() => *has_slice = true,
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Record the size of the last NAL unit, which ends at `end`.
+    fn finish_nal_unit(&mut self, end: u64) {
+        if let Some((entry, _)) = self.current.as_mut() {
+            // The access unit size is checked to fit when it is finished.
+            entry.nal_sizes.push((end - self.nal_offset) as u32);
+        }
+    }
+
+    /// Complete the current access unit, if any, which ends at `end`.
+    fn finish_access_unit(&mut self, end: u64) -> Result<()> {
+        if let Some((mut entry, has_slice)) = self.current.take() {
+            if has_slice {
+                entry.size = (end - entry.offset).try_into().map_err(|_| {
+                    crate::bitstream::invalid_bitstream("access unit larger than 4 GiB")
+                })?;
+                self.entries.push(entry);
+            }
+        }
+        Ok(())
+    }
+
+    fn finish(mut self, end: u64) -> Result<FrameIndex> {
+        self.finish_nal_unit(end);
+        self.finish_access_unit(end)?;
+        Ok(FrameIndex {
+            entries: self.entries,
+        })
+    }
+}
+
+/// Writes [FrameIndexEntry]s to a sidecar file as they become known.
+///
+/// Each entry is written with a single call to [Write::write_all], so with an
+/// unbuffered writer the sidecar file is complete up to the last frame
+/// written.
+pub struct FrameIndexWriter<W> {
+    wtr: W,
+    format: IndexFormat,
+    frame_count: u64,
+    /// Buffer for the entry being written, reused across entries.
+    buf: Vec<u8>,
+}
+
+impl<W: Write> FrameIndexWriter<W> {
+    /// Create a writer, writing the file signature or header line
+    /// immediately.
+    pub fn new(mut wtr: W, format: IndexFormat) -> Result<Self> {
+        match format {
+            IndexFormat::Binary => wtr.write_all(&MAGIC)?,
+            IndexFormat::Csv => wtr.write_all(CSV_HEADER.as_bytes())?,
+        }
+        Ok(Self {
+            wtr,
+            format,
+            frame_count: 0,
+            buf: Vec::new(),
+        })
+    }
+
+    /// Append the entry of the next frame.
+    pub fn write_entry(&mut self, entry: &FrameIndexEntry) -> Result<()> {
+        self.buf.clear();
+        match self.format {
+            IndexFormat::Binary => entry.put_record(&mut self.buf),
+            IndexFormat::Csv => entry.put_csv_line(self.frame_count, &mut self.buf),
+        }
+        self.wtr.write_all(&self.buf)?;
+        self.frame_count += 1;
+        Ok(())
+    }
+
+    /// Retrieve the underlying [std::io::Write] implementation.
+    pub fn into_inner(self) -> W {
+        self.wtr
+    }
+}
+
+/// Decodes arbitrary frames of an Annex B byte stream using a [FrameIndex].
+///
+/// Each frame is read with one seek, so its cost does not depend on its
+/// position in the stream. Streams consisting of I_PCM macroblocks, such as
+/// those written by [crate::H264Writer], are decoded by [LessDecoder], so every
+/// frame can be decoded independently of the others.
+///
+/// To read from a memory map, wrap it in a [std::io::Cursor], e.g.
+/// `IndexedReader::new(Cursor::new(mmap), index)` with a `memmap2::Mmap`.
+pub struct IndexedReader<R> {
+    rdr: R,
+    index: FrameIndex,
+    decoder: LessDecoder,
+    /// Whether the parameter sets of the first access unit have been decoded.
+    have_parameter_sets: bool,
+    /// Buffer for the access unit being decoded, reused across frames.
+    buf: Vec<u8>,
+}
+
+impl IndexedReader<File> {
+    /// Open the stream at `path`, loading its index from the sidecar file at
+    /// [FrameIndex::sidecar_path] if it exists and building it otherwise.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let rdr = File::open(&path)?;
+        match File::open(FrameIndex::sidecar_path(&path)) {
+            Ok(sidecar) => Ok(Self::new(rdr, FrameIndex::read_from(sidecar)?)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Self::build(rdr),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+impl<R: Read + Seek> IndexedReader<R> {
+    /// Create a reader using an existing index of `rdr`.
+    pub fn new(rdr: R, index: FrameIndex) -> Self {
+        Self {
+            rdr,
+            index,
+            decoder: LessDecoder::new(),
+            have_parameter_sets: false,
+            buf: Vec::new(),
+        }
+    }
+
+    /// Create a reader, building the index by scanning `rdr` from the start.
+    pub fn build(mut rdr: R) -> Result<Self> {
+        rdr.seek(SeekFrom::Start(0))?;
+        let index = FrameIndex::build(&mut rdr)?;
+        Ok(Self::new(rdr, index))
+    }
+
+    /// The index of the stream.
+    pub fn index(&self) -> &FrameIndex {
+        &self.index
+    }
+
+    /// The number of frames in the stream.
+    pub fn len(&self) -> usize {
+        self.index.len()
+    }
+
+    /// Whether the stream has no frames.
+    pub fn is_empty(&self) -> bool {
+        self.index.is_empty()
+    }
+
+    /// Retrieve the underlying reader.
+    pub fn into_inner(self) -> R {
+        self.rdr
+    }
+
+    /// Read the access unit of frame `frame_number` into `buf`.
+    fn read_access_unit(&mut self, frame_number: usize) -> Result<bool> {
+        let Some(entry) = self.index.get(frame_number) else {
+            return Ok(false);
+        };
+        self.buf.resize(entry.size as usize, 0);
+        self.rdr.seek(SeekFrom::Start(entry.offset))?;
+        self.rdr.read_exact(&mut self.buf)?;
+        Ok(true)
+    }
+
+    /// Read the access unit of frame `frame_number` in Annex B format without
+    /// decoding it, returning `None` if there is no such frame.
+    pub fn access_unit(&mut self, frame_number: usize) -> Result<Option<&[u8]>> {
+        Ok(self
+            .read_access_unit(frame_number)?
+            .then_some(&self.buf[..]))
+    }
+
+    /// The decoder used by [Self::frame].
+    pub(crate) fn decoder(&self) -> &LessDecoder {
+        &self.decoder
+    }
+
+    /// Decode frame `frame_number`, returning `None` if there is no such
+    /// frame.
+    ///
+    /// The parameter sets are taken from the access unit of the first frame
+    /// unless the access unit of the frame has its own.
+    pub fn frame(&mut self, frame_number: usize) -> Result<Option<OwnedYCbCrImage>> {
+        if !self.have_parameter_sets && frame_number != 0 && self.read_access_unit(0)? {
+            let mut nal_rdr = AnnexBSliceReader::new(&self.buf);
+            while let Some((_offset, ebsp)) = nal_rdr.next_ebsp() {
+                let Some(&header) = ebsp.first() else {
+                    continue;
+                };
+                let unit_type = NalUnitType::from_nal_unit_type(header & 0x1f);
+                if matches!(
+                    unit_type,
+                    NalUnitType::SequenceParameterSet | NalUnitType::PictureParameterSet
+                ) {
+                    self.decoder
+                        .decode_nal_unit(&NalUnit::from_nal_unit(ebsp)?)?;
+                }
+            }
+        }
+        self.have_parameter_sets = true;
+        if !self.read_access_unit(frame_number)? {
+            return Ok(None);
+        }
+        match self.decoder.decode_access_unit(&self.buf)? {
+            Some(image) => Ok(Some(image)),
+            None => Err(crate::bitstream::invalid_bitstream(
+                "access unit does not contain a complete picture",
+            )),
+        }
+    }
+}
+
+#[test]
+fn test_frame_index_formats() {
+    let entries = [
+        FrameIndexEntry {
+            offset: 0x0102_0304_0506_0708,
+            size: 1234,
+            is_sync: true,
+            timestamp: Some(0),
+            nal_sizes: vec![1000, 234],
+        },
+        FrameIndexEntry {
+            offset: 10,
+            size: u32::MAX,
+            is_sync: false,
+            timestamp: None,
+            nal_sizes: vec![u32::MAX],
+        },
+    ];
+    let index = FrameIndex {
+        entries: entries.to_vec(),
+    };
+
+    let mut buf = Vec::new();
+    index.write_to(&mut buf, IndexFormat::Binary).unwrap();
+    assert_eq!(buf.len(), MAGIC.len() + 2 * RECORD_HEADER_SIZE + 3 * 4);
+    assert_eq!(FrameIndex::read_from(&buf[..]).unwrap(), index);
+    // A partial record is ignored.
+    assert_eq!(
+        FrameIndex::read_from(&buf[..buf.len() - 1])
+            .unwrap()
+            .entries(),
+        &entries[..1]
+    );
+    assert!(FrameIndex::read_from(&buf[1..]).is_err());
+
+    let mut buf = Vec::new();
+    index.write_to(&mut buf, IndexFormat::Csv).unwrap();
+    assert_eq!(
+        std::str::from_utf8(&buf).unwrap(),
+        "frame_number,offset,size,is_sync,timestamp,nal_sizes\n\
+         0,72623859790382856,1234,1,0,1000 234\n\
+         1,10,4294967295,0,,4294967295\n"
+    );
+    assert_eq!(FrameIndex::read_from(&buf[..]).unwrap(), index);
+    assert_eq!(
+        FrameIndex::read_from(&buf[..buf.len() - 1])
+            .unwrap()
+            .entries(),
+        &entries[..1]
+    );
+    let csv = "frame_number,offset,size,is_sync,timestamp,nal_sizes\n1,10,5,0,,5\n";
+    assert!(FrameIndex::read_from(csv.as_bytes()).is_err());
+    let csv = "frame_number,offset,size,is_sync,timestamp,nal_sizes\n0,10,5,2,,5\n";
+    assert!(FrameIndex::read_from(csv.as_bytes()).is_err());
+}
@@ -23,16 +23,20 @@
 #[cfg(not(feature = "std"))]
 extern crate core as std;
 
+#[cfg(feature = "alloc")]
 extern crate alloc;
+#[cfg(feature = "alloc")]
 use alloc::{vec, vec::Vec};
 
 #[cfg(feature = "backtrace")]
 use std::backtrace::Backtrace;
 
-use bitvec::prelude::{BitVec, Msb0};
+pub mod bitstream;
+use bitstream::BitWriter;
 
-mod golomb;
-use golomb::BitVecGolomb;
+mod sink;
+pub use sink::NalSink;
+use sink::{ByteArray, ByteSink};
 
 pub mod ycbcr_image;
 use ycbcr_image::*;
@@ -42,13 +46,56 @@ use nal_unit::*;
 
 pub mod sei;
 
+#[cfg(feature = "alloc")]
+pub mod annex_b;
+
+#[cfg(feature = "std")]
+pub mod index;
+
 #[cfg(feature = "std")]
 mod writer;
 #[cfg(feature = "std")]
-pub use writer::H264Writer;
+pub use writer::{
+    recover_h264_file, FrameInfo, H264Writer, RecoveryInfo, SyncWrite, WriteInterval,
+};
+
+#[cfg(feature = "std")]
+mod mp4_writer;
+#[cfg(feature = "std")]
+pub use mp4_writer::{Mp4Config, Mp4Writer};
+
+#[cfg(feature = "std")]
+mod mkv_writer;
+#[cfg(feature = "std")]
+pub use mkv_writer::{MkvConfig, MkvWriter};
+
+#[cfg(feature = "std")]
+mod multi_camera;
+#[cfg(feature = "std")]
+pub use multi_camera::{CameraStats, MultiCameraWriter};
+
+#[cfg(feature = "std")]
+mod pre_trigger;
+#[cfg(feature = "std")]
+pub use pre_trigger::{PreTriggerLimits, PreTriggerRecorder, PreTriggerStats};
+
+#[cfg(feature = "std")]
+mod rotating_writer;
+#[cfg(feature = "std")]
+pub use rotating_writer::{RotatingH264Writer, RotationPolicy, SegmentInfo};
+
+#[cfg(feature = "std")]
+mod threaded_writer;
+#[cfg(feature = "std")]
+pub use threaded_writer::{QueueFullPolicy, ThreadedH264Writer, ThreadedWriterStats};
 
 mod encoder;
-pub use encoder::LessEncoder;
+pub use encoder::{EncoderConfig, GopConfig, LessEncoder, SliceMode};
+
+#[cfg(feature = "alloc")]
+mod decoder;
+#[cfg(feature = "alloc")]
+pub use decoder::LessDecoder;
 
 // Error type ----------------------
 
@@ -72,6 +119,42 @@ pub enum Error {
         #[cfg(feature = "backtrace")]
         backtrace: Backtrace,
     },
+    InvalidConfiguration {
+        msg: &'static str,
+        #[cfg(feature = "backtrace")]
+        backtrace: Backtrace,
+    },
+    BufferTooSmall {
+        needed: usize,
+        #[cfg(feature = "backtrace")]
+        backtrace: Backtrace,
+    },
+    /// Writing to a [NalSink] failed.
+    SinkError {
+        msg: &'static str,
+        #[cfg(feature = "backtrace")]
+        backtrace: Backtrace,
+    },
+    /// Encoded data does not conform to the H.264 syntax.
+    InvalidBitstream {
+        msg: &'static str,
+        #[cfg(feature = "backtrace")]
+        backtrace: Backtrace,
+    },
+    /// Encoded data uses H.264 features which cannot be decoded by
+    /// [LessDecoder].
+    UnsupportedBitstream {
+        msg: &'static str,
+        #[cfg(feature = "backtrace")]
+        backtrace: Backtrace,
+    },
+    /// A frame index sidecar file is malformed.
+    #[cfg(feature = "std")]
+    InvalidIndex {
+        msg: &'static str,
+        #[cfg(feature = "backtrace")]
+        backtrace: Backtrace,
+    },
     #[cfg(feature = "std")]
     IoError {
         source: std::io::Error,
@@ -81,6 +164,20 @@ pub enum Error {
 }
 type Result<T> = std::result::Result<T, Error>;
 
+impl Error {
+    /// Create an [Error::SinkError] with the given message.
+    ///
+    /// This is for [NalSink] implementations outside this crate, which cannot
+    /// otherwise fill in the backtrace of the `backtrace` feature.
+    pub fn sink_error(msg: &'static str) -> Self {
+        Error::SinkError {
+            msg,
+            #[cfg(feature = "backtrace")]
+            backtrace: Backtrace::capture(),
+        }
+    }
+}
+
 #[cfg(feature = "std")]
 impl From<std::io::Error> for Error {
     fn from(source: std::io::Error) -> Self {
@@ -134,6 +231,49 @@ impl std::fmt::Display for Error {
             } => {
                 write!(f, "internal error: inconsistent state")
             }
+            Error::InvalidConfiguration {
+                msg,
+                #[cfg(feature = "backtrace")]
+                    backtrace: _,
+            } => {
+                write!(f, "invalid configuration: {msg}")
+            }
+            Error::BufferTooSmall {
+                needed,
+                #[cfg(feature = "backtrace")]
+                    backtrace: _,
+            } => {
+                write!(f, "buffer too small: {needed} bytes needed")
+            }
+            Error::SinkError {
+                msg,
+                #[cfg(feature = "backtrace")]
+                    backtrace: _,
+            } => {
+                write!(f, "sink error: {msg}")
+            }
+            Error::InvalidBitstream {
+                msg,
+                #[cfg(feature = "backtrace")]
+                    backtrace: _,
+            } => {
+                write!(f, "invalid bitstream: {msg}")
+            }
+            Error::UnsupportedBitstream {
+                msg,
+                #[cfg(feature = "backtrace")]
+                    backtrace: _,
+            } => {
+                write!(f, "unsupported bitstream: {msg}")
+            }
+            #[cfg(feature = "std")]
+            Error::InvalidIndex {
+                msg,
+                #[cfg(feature = "backtrace")]
+                    backtrace: _,
+            } => {
+                write!(f, "invalid frame index: {msg}")
+            }
             #[cfg(feature = "std")]
             Error::IoError {
                 source,
@@ -182,6 +322,27 @@ struct TimingInfo {
     fixed_frame_rate_flag: bool,
 }
 
+#[derive(Debug, PartialEq, Eq)]
+struct BitstreamRestriction {
+    /// The maximum number of frames that precede any frame in decoding order
+    /// and follow it in output order.
+    max_num_reorder_frames: u32,
+
+    /// The required size of the decoded picture buffer, in frames.
+    max_dec_frame_buffering: u32,
+}
+
+impl BitstreamRestriction {
+    /// Restrictions allowing a decoder to output each frame immediately.
+    fn low_latency(max_num_ref_frames: u32) -> Self {
+        Self {
+            max_num_reorder_frames: 0,
+            // must be at least max_num_ref_frames
+            max_dec_frame_buffering: max_num_ref_frames,
+        }
+    }
+}
+
 #[derive(Debug, PartialEq, Eq)]
 struct Vui {
     /// Whether intensity range in encoded signal uses full luma/chroma range.
@@ -190,6 +351,7 @@ struct Vui {
     full_range: bool,
     video_format: VideoFormat,
     timing_info: Option<TimingInfo>,
+    bitstream_restriction: Option<BitstreamRestriction>,
 }
 
 impl Vui {
@@ -198,21 +360,22 @@ impl Vui {
             full_range,
             video_format: VideoFormat::Unspecified,
             timing_info: None,
+            bitstream_restriction: None,
         }
     }
 
-    fn append_to_rbsp(&self, bv: &mut BitVec<u8, Msb0>) {
+    fn append_to_rbsp<S: ByteSink>(&self, bv: &mut BitWriter<S>) {
         // vui_parameters( )
         // Annex E
 
         // aspect_ratio_info_present_flag 0
-        bv.push(false);
+        bv.write_bit(false);
 
         // overscan_info_present_flag 0
-        bv.push(false);
+        bv.write_bit(false);
 
         // video_signal_type_present_flag 1
-        bv.push(true);
+        bv.write_bit(true);
 
         // video_format
         let video_format_arr = match &self.video_format {
@@ -224,35 +387,60 @@ impl Vui {
             VideoFormat::Unspecified => [true, false, true],
             VideoFormat::Reserved => [true, true, true],
         };
-        bv.extend(video_format_arr);
+        for bit in video_format_arr {
+            bv.write_bit(bit);
+        }
 
         // video_full_range_flag
-        bv.push(self.full_range);
+        bv.write_bit(self.full_range);
 
         // colour_description_present_flag 0
-        bv.push(false);
+        bv.write_bit(false);
 
         // chroma_loc_info_present_flag 0
-        bv.push(false);
+        bv.write_bit(false);
 
         // timing_info_present_flag
         if let Some(_timing_info) = &self.timing_info {
             todo!();
         } else {
-            bv.push(false);
+            bv.write_bit(false);
         }
 
         // nal_hrd_parameters_present_flag 0
-        bv.push(false);
+        bv.write_bit(false);
 
         // vcl_hrd_parameters_present_flag 0
-        bv.push(false);
+        bv.write_bit(false);
 
         // pic_struct_present_flag 0
-        bv.push(false);
+        bv.write_bit(false);
+
+        if let Some(restriction) = &self.bitstream_restriction {
+            // bitstream_restriction_flag 1
+            bv.write_bit(true);
+
+            // motion_vectors_over_pic_boundaries_flag 1
+            bv.write_bit(true);
+
+            // max_bytes_per_pic_denom 0 (no limit)
+            bv.write_ue(0);
+
+            // max_bits_per_mb_denom 0 (no limit)
+            bv.write_ue(0);
 
-        // bitstream_restriction_flag 0
-        bv.push(false);
+            // log2_max_mv_length_horizontal 15 (the inferred default)
+            bv.write_ue(15);
+
+            // log2_max_mv_length_vertical 15 (the inferred default)
+            bv.write_ue(15);
+
+            bv.write_ue(restriction.max_num_reorder_frames);
+            bv.write_ue(restriction.max_dec_frame_buffering);
+        } else {
+            // bitstream_restriction_flag 0
+            bv.write_bit(false);
+        }
     }
 }
 
@@ -275,6 +463,30 @@ impl BitDepth {
     }
 }
 
+/// The method used to signal picture order count (POC) in the bitstream.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub enum PicOrderCntType {
+    /// `pic_order_cnt_type = 0`: POC is sent explicitly in each slice header.
+    #[default]
+    Zero,
+    /// `pic_order_cnt_type = 1`: POC is derived from `frame_num` and an
+    /// expected increment per frame signalled in the SPS.
+    One,
+    /// `pic_order_cnt_type = 2`: POC is derived from `frame_num`, so output
+    /// order equals decoding order.
+    Two,
+}
+
+impl PicOrderCntType {
+    fn value(&self) -> u32 {
+        match self {
+            Self::Zero => 0,
+            Self::One => 1,
+            Self::Two => 2,
+        }
+    }
+}
+
 #[derive(Debug, PartialEq, Eq)]
 enum ProfileIdc {
     Bare(u8),
@@ -303,15 +515,15 @@ impl ProfileIdc {
             Self::Extra((_, ChromaFormatIdc::Monochrome(_))) => true,
         }
     }
-    fn append_to_rbsp(&self, bv: &mut BitVec<u8, Msb0>) {
+    fn append_to_rbsp<S: ByteSink>(&self, bv: &mut BitWriter<S>) {
         match self {
             Self::Bare(_) => {}
             Self::Extra((_, chroma_format_idc)) => {
                 let chroma_format_idc_value = chroma_format_idc.value();
-                bv.extend_exp_golomb(chroma_format_idc_value);
+                bv.write_ue(chroma_format_idc_value);
                 if chroma_format_idc_value == 3 {
                     // separate_colour_plane_flag 0
-                    bv.push(false);
+                    bv.write_bit(false);
                 }
                 let bit_depth = match chroma_format_idc {
                     ChromaFormatIdc::Monochrome(bit_depth)
@@ -320,13 +532,13 @@ impl ProfileIdc {
 
                 let bit_depth_luma_minus8 = bit_depth.num_bits() - 8;
                 let bit_depth_chroma_minus8 = bit_depth.num_bits() - 8;
-                bv.extend_exp_golomb(bit_depth_luma_minus8.into());
-                bv.extend_exp_golomb(bit_depth_chroma_minus8.into());
+                bv.write_ue(bit_depth_luma_minus8.into());
+                bv.write_ue(bit_depth_chroma_minus8.into());
 
                 // qpprime_y_zero_transform_bypass_flag 0
-                bv.push(false);
+                bv.write_bit(false);
                 // seq_scaling_matrix_present_flag 0
-                bv.push(false);
+                bv.write_bit(false);
             }
         }
     }
@@ -364,8 +576,9 @@ struct Sps {
     pic_height_in_map_units_minus1: u32,
     frame_cropping: Option<[u32; 4]>,
     log2_max_frame_num_minus4: u32,
-    pic_order_cnt_type: u32,
+    pic_order_cnt_type: PicOrderCntType,
     log2_max_pic_order_cnt_lsb_minus4: u32,
+    max_num_ref_frames: u32,
     vui: Option<Vui>,
     // Future: expand with ability to set more parameters.
 }
@@ -384,22 +597,26 @@ impl Sps {
             pic_height_in_map_units_minus1,
             frame_cropping,
             log2_max_frame_num_minus4: 0,
-            pic_order_cnt_type: 0,
+            pic_order_cnt_type: PicOrderCntType::Zero,
             log2_max_pic_order_cnt_lsb_minus4: 0,
+            max_num_ref_frames: 0,
             vui,
         }
     }
 
-    #[allow(dead_code)]
     fn log2_max_frame_num(&self) -> u32 {
         self.log2_max_frame_num_minus4 + 4
     }
 
-    #[allow(dead_code)]
     fn log2_max_pic_order_cnt_lsb(&self) -> u32 {
         self.log2_max_pic_order_cnt_lsb_minus4 + 4
     }
+    #[cfg(feature = "alloc")]
     fn to_rbsp(&self) -> RbspData {
+        RbspData::new(self.to_rbsp_array().as_bytes().to_vec())
+    }
+
+    fn to_rbsp_array(&self) -> HeaderBytes {
         // Payload
         // profile_idc
         let profile_idc = self.profile_idc.profile_idc_byte();
@@ -416,67 +633,91 @@ impl Sps {
         // level_idc = 10
         let level_idc = 10;
 
-        let start = vec![profile_idc, reserved, level_idc];
-        let mut bv: BitVec<u8, Msb0> = BitVec::from_vec(start);
+        let mut bv = BitWriter::new(HeaderBytes::new());
+        for byte in [profile_idc, reserved, level_idc] {
+            bv.write_bits(8, byte.into());
+        }
 
         // seq_parameter_set_id = 0
-        bv.extend_exp_golomb(0);
+        bv.write_ue(0);
 
         // chroma_format_idc etc if in the correct `profile_idc`.
         self.profile_idc.append_to_rbsp(&mut bv);
 
-        bv.extend_exp_golomb(self.log2_max_frame_num_minus4);
+        bv.write_ue(self.log2_max_frame_num_minus4);
 
         // pic_order_cnt_type
-        bv.extend_exp_golomb(self.pic_order_cnt_type);
+        bv.write_ue(self.pic_order_cnt_type.value());
+
+        match self.pic_order_cnt_type {
+            PicOrderCntType::Zero => {
+                // log2_max_pic_order_cnt_lsb_minus4
+                bv.write_ue(self.log2_max_pic_order_cnt_lsb_minus4);
+            }
+            PicOrderCntType::One => {
+                // delta_pic_order_always_zero_flag = 1
+                bv.write_bit(true);
 
-        // log2_max_pic_order_cnt_lsb_minus4
-        bv.extend_exp_golomb(self.log2_max_pic_order_cnt_lsb_minus4);
+                // offset_for_non_ref_pic = 0
+                bv.write_se(0);
+
+                // offset_for_top_to_bottom_field = 0
+                bv.write_se(0);
+
+                // num_ref_frames_in_pic_order_cnt_cycle = 1
+                bv.write_ue(1);
+
+                // offset_for_ref_frame[0] = 2, i.e. each frame increments
+                // picture order count by 2 as in the other types.
+                bv.write_se(2);
+            }
+            PicOrderCntType::Two => {}
+        }
 
         // max_num_ref_frames
-        bv.extend_exp_golomb(0);
+        bv.write_ue(self.max_num_ref_frames);
 
         // gaps_in_frame_num_value_allowed_flag = 0
-        bv.push(false);
+        bv.write_bit(false);
 
         // pic_width_in_mbs_minus1
-        bv.extend_exp_golomb(self.pic_width_in_mbs_minus1);
+        bv.write_ue(self.pic_width_in_mbs_minus1);
 
         // pic_height_in_map_units_minus1
-        bv.extend_exp_golomb(self.pic_height_in_map_units_minus1);
+        bv.write_ue(self.pic_height_in_map_units_minus1);
 
         // frame_mbs_only_flag = 1
-        bv.push(true);
+        bv.write_bit(true);
 
         // direct_8x8_inference_flag = 0
-        bv.push(false);
+        bv.write_bit(false);
 
         if let Some(lrtb) = &self.frame_cropping {
             // frame_cropping_flag = 1
-            bv.push(true);
+            bv.write_bit(true);
             for frame_crop_offset in lrtb.iter() {
-                bv.extend_exp_golomb(*frame_crop_offset);
+                bv.write_ue(*frame_crop_offset);
             }
         } else {
             // frame_cropping_flag = 0
-            bv.push(false);
+            bv.write_bit(false);
         }
 
         match &self.vui {
             None => {
                 // vui_prameters_present_flag = 0
-                bv.push(false);
+                bv.write_bit(false);
             }
             Some(vui) => {
-                bv.push(true);
+                bv.write_bit(true);
                 vui.append_to_rbsp(&mut bv);
             }
         }
 
-        // rbsp_stop_one_bit = 1
-        bv.push(true);
+        // rbsp_trailing_bits( )
+        bv.write_trailing_bits();
 
-        RbspData::new(bv.into_vec())
+        bv.finish()
     }
 }
 
@@ -494,124 +735,172 @@ impl Pps {
         }
     }
 
+    #[cfg(feature = "alloc")]
     fn to_rbsp(&self) -> RbspData {
+        RbspData::new(self.to_rbsp_array().as_bytes().to_vec())
+    }
+
+    fn to_rbsp_array(&self) -> HeaderBytes {
         // Payload
 
-        let mut bv: BitVec<u8, Msb0> = BitVec::with_capacity(20 * 8); // 20 bytes should be enough
+        let mut bv = BitWriter::new(HeaderBytes::new());
 
-        bv.extend_exp_golomb(self.pic_parameter_set_id);
+        bv.write_ue(self.pic_parameter_set_id);
 
         // seq_parameter_set_id = 0
-        bv.extend_exp_golomb(0);
+        bv.write_ue(0);
 
         // entropy_coding_mode_flag = 0
-        bv.push(false);
+        bv.write_bit(false);
 
         // bottom_field_pic_order_in_frame_present_flag = 0
-        bv.push(false);
+        bv.write_bit(false);
 
         // num_slice_groups_minus1 = 0
-        bv.extend_exp_golomb(0);
+        bv.write_ue(0);
 
         // num_ref_idx_l0_default_active_minus1 = 0
-        bv.extend_exp_golomb(0);
+        bv.write_ue(0);
 
         // num_ref_idx_l1_default_active_minus1 = 0
-        bv.extend_exp_golomb(0);
+        bv.write_ue(0);
 
         // weighted_pred_flag = 0
-        bv.push(false);
+        bv.write_bit(false);
 
         // weighted_bipred_idc = 0
-        bv.push(false);
-        bv.push(false);
+        bv.write_bit(false);
+        bv.write_bit(false);
 
         // pic_init_qp_minus26 = 0
-        bv.extend_signed_exp_golomb(0);
+        bv.write_se(0);
 
         // pic_init_qs_minus26 = 0
-        bv.extend_signed_exp_golomb(0);
+        bv.write_se(0);
 
         // chroma_qp_index_offset
-        bv.extend_signed_exp_golomb(0);
+        bv.write_se(0);
 
         // deblocking_filter_control_present_flag = 0
-        bv.push(false);
+        bv.write_bit(false);
 
         // constrained_intra_pred_flag = 0
-        bv.push(false);
+        bv.write_bit(false);
 
         // redundant_pic_cnt_present_flag = 0
-        bv.push(false);
+        bv.write_bit(false);
 
         // rbsp_trailing_bits( )
-        bv.push(true);
+        bv.write_trailing_bits();
 
-        RbspData::new(bv.into_vec())
+        bv.finish()
     }
 }
 
-struct SliceHeader {}
+#[derive(Clone)]
+struct SliceHeader {
+    /// The address of the first macroblock in the slice.
+    first_mb_in_slice: u32,
+    /// Distinguishes consecutive IDR pictures. Range 0..=65535. `None` for
+    /// non-IDR pictures.
+    idr_pic_id: Option<u32>,
+    /// Used as `frame_num` modulo `MaxFrameNum`.
+    frame_num: u32,
+    /// Used as `pic_order_cnt_lsb` modulo `MaxPicOrderCntLsb` for
+    /// [PicOrderCntType::Zero].
+    pic_order_cnt: u32,
+}
 
 impl SliceHeader {
     fn new() -> Self {
-        Self {}
+        Self {
+            first_mb_in_slice: 0,
+            idr_pic_id: Some(0),
+            frame_num: 0,
+            pic_order_cnt: 0,
+        }
+    }
+
+    /// The type of NAL unit which contains this slice.
+    fn nal_unit_type(&self) -> NalUnitType {
+        if self.idr_pic_id.is_some() {
+            NalUnitType::CodedSliceOfAnIDRPicture
+        } else {
+            NalUnitType::CodedSliceOfANonIDRPicture
+        }
     }
 
+    #[cfg(feature = "alloc")]
     fn to_rbsp(&self, sps: &Sps, pps: &Pps) -> RbspData {
+        RbspData::new(self.to_rbsp_array(sps, pps).as_bytes().to_vec())
+    }
+
+    fn to_rbsp_array(&self, sps: &Sps, pps: &Pps) -> HeaderBytes {
         // We are `slice_layer_without_partitioning_rbsp` because we have
-        // nal_unit_type 5 (NalUnitType::CodedSliceOfAnIDRPicture). Also
-        // `IdrPicFlag` is 1 for the same reason.
+        // nal_unit_type 5 (NalUnitType::CodedSliceOfAnIDRPicture) or 1
+        // (NalUnitType::CodedSliceOfANonIDRPicture). `IdrPicFlag` is 1 only in
+        // the first case. In both cases, nal_ref_idc is not zero.
 
         // Payload
 
-        let mut bv: BitVec<u8, Msb0> = BitVec::with_capacity(20 * 8); // 20 bytes should be enough for slice header
+        let mut bv = BitWriter::new(HeaderBytes::new());
 
-        // first_mb_in_slice = 0
-        bv.extend_exp_golomb(0);
+        bv.write_ue(self.first_mb_in_slice);
 
         // slice_type = 7 (I)
-        bv.extend_exp_golomb(7);
+        bv.write_ue(7);
 
-        bv.extend_exp_golomb(pps.pic_parameter_set_id);
+        bv.write_ue(pps.pic_parameter_set_id);
 
         // colour_plane: None,
 
-        // frame_num = 0
+        // frame_num
         let n_bits = sps.log2_max_frame_num();
-        for _ in 0..n_bits {
-            bv.push(false);
-        }
+        bv.write_bits(n_bits, self.frame_num % (1 << n_bits));
 
-        // idr_pic_id = 0
-        bv.extend_exp_golomb(0);
+        if let Some(idr_pic_id) = self.idr_pic_id {
+            bv.write_ue(idr_pic_id);
+        }
 
-        if sps.pic_order_cnt_type == 0 {
-            // pic_order_cnt_lsb = 0
-            let n_bits = sps.log2_max_pic_order_cnt_lsb();
-            for _ in 0..n_bits {
-                bv.push(false);
+        match sps.pic_order_cnt_type {
+            PicOrderCntType::Zero => {
+                // pic_order_cnt_lsb
+                let n_bits = sps.log2_max_pic_order_cnt_lsb();
+                bv.write_bits(n_bits, self.pic_order_cnt % (1 << n_bits));
+            }
+            PicOrderCntType::One => {
+                // delta_pic_order_always_zero_flag is 1 in our SPS, so no
+                // delta_pic_order_cnt values are signalled. Picture order
+                // count is derived from frame_num.
+            }
+            PicOrderCntType::Two => {
+                // Nothing is signalled, picture order count is derived from
+                // frame_num.
             }
-        } else {
-            todo!();
         }
 
         // dec_ref_pic_marking
-        //   no_output_of_prior_pics_flag u(1)
-        bv.push(true);
+        if self.idr_pic_id.is_some() {
+            //   no_output_of_prior_pics_flag u(1)
+            bv.write_bit(true);
 
-        //   long_term_reference_flag u(1)
-        bv.push(false);
+            //   long_term_reference_flag u(1)
+            bv.write_bit(false);
+        } else {
+            //   adaptive_ref_pic_marking_mode_flag u(1), use sliding window
+            bv.write_bit(false);
+        }
 
         // slice_qp_delta = 0
-        bv.extend_signed_exp_golomb(0);
+        bv.write_se(0);
 
         // For the first macroblock, the macroblock type (mb_type) is read without
         // aligning to a byte boundary. This would explain why we must put this here
         // rather than in the first macroblock.
-        bv.extend_exp_golomb(MacroblockType::I_PCM.mb_type());
+        bv.write_ue(MacroblockType::I_PCM.mb_type());
 
-        RbspData::new(bv.into_vec())
+        // pcm_alignment_zero_bit
+        bv.finish()
     }
 }
 
@@ -642,21 +931,21 @@ impl MacroblockType {
 fn test_macroblock_header() {
     {
         let typ = &MacroblockType::I_PCM;
-        let mut bv: BitVec<u8, Msb0> = BitVec::new();
-        bv.extend_exp_golomb(typ.mb_type());
-        let macroblock_header_dynamic = bv.as_raw_slice();
-        dbg!(macroblock_header_dynamic);
+        let mut bv = BitWriter::new(Vec::new());
+        bv.write_ue(typ.mb_type());
+        let macroblock_header_dynamic = bv.finish();
+        dbg!(&macroblock_header_dynamic);
         let macroblock_header_static = typ.as_encoded_macroblock_header();
         assert_eq!(macroblock_header_static, macroblock_header_dynamic);
     }
 }
 
 #[inline]
-fn copy_to_macroblock_8bit(
+fn copy_to_macroblock_8bit<S: ByteSink>(
     mbs_row: usize,
     mbs_col: usize,
     src_plane: &DataPlane,
-    dest: &mut Vec<u8>,
+    dest: &mut S,
     dest_sz: usize,
 ) {
     // `dest_sz` will be 16 when copying luma block and 8 when copying 4:2:0
@@ -668,16 +957,16 @@ fn copy_to_macroblock_8bit(
         // buffer.
         let row_chunk = &src_data[src_row * src_stride..(src_row + 1) * src_stride];
         let chunk = &row_chunk[mbs_col * dest_sz..(mbs_col + 1) * dest_sz];
-        dest.extend(chunk);
+        dest.put(chunk);
     }
 }
 
 #[inline]
-fn copy_to_macroblock_12bit(
+fn copy_to_macroblock_12bit<S: ByteSink>(
     mbs_row: usize,
     mbs_col: usize,
     src_plane: &DataPlane,
-    dest: &mut Vec<u8>,
+    dest: &mut S,
     dest_sz: usize,
 ) {
     // `dest_sz` will be 24 when copying luma block and 12 when copying 4:2:0
@@ -690,31 +979,31 @@ fn copy_to_macroblock_12bit(
         // buffer.
         let row_chunk = &src_data[src_row * src_stride..(src_row + 1) * src_stride];
         let chunk = &row_chunk[mbs_col * dest_sz..(mbs_col + 1) * dest_sz];
-        dest.extend(chunk);
+        dest.put(chunk);
     }
 }
 
 #[inline]
-fn macroblock(
+fn macroblock<S: ByteSink>(
     mbs_row: usize,
     mbs_col: usize,
-    result: &mut RbspData,
+    first_in_slice: bool,
+    result: &mut S,
     y4m_frame: &YCbCrImage,
     luma_only: bool,
 ) {
-    if !(mbs_row == 0 && mbs_col == 0) {
-        result
-            .data
-            .extend(MacroblockType::I_PCM.as_encoded_macroblock_header());
+    // The first macroblock type of a slice is written in the slice header.
+    if !first_in_slice {
+        result.put(MacroblockType::I_PCM.as_encoded_macroblock_header());
     }
 
     match &y4m_frame.planes {
         Planes::Mono(y_plane) | Planes::YCbCr((y_plane, _, _)) => match y_plane.bit_depth {
             BitDepth::Depth8 => {
-                copy_to_macroblock_8bit(mbs_row, mbs_col, y_plane, &mut result.data, 16);
+                copy_to_macroblock_8bit(mbs_row, mbs_col, y_plane, result, 16);
             }
             BitDepth::Depth12 => {
-                copy_to_macroblock_12bit(mbs_row, mbs_col, y_plane, &mut result.data, 24);
+                copy_to_macroblock_12bit(mbs_row, mbs_col, y_plane, result, 24);
             }
         },
     }
@@ -727,34 +1016,42 @@ fn macroblock(
         Planes::Mono(y_plane) => {
             assert_eq!(y_plane.bit_depth, BitDepth::Depth8);
             // 2 macroblocks of chrominance at 8x8 each
-            result.data.extend(vec![128u8; 2 * 8 * 8]);
+            result.put(&[128u8; 2 * 8 * 8]);
         }
         Planes::YCbCr((_, u_plane, v_plane)) => {
             assert_eq!(u_plane.bit_depth, v_plane.bit_depth);
             match u_plane.bit_depth {
                 BitDepth::Depth8 => {
-                    copy_to_macroblock_8bit(mbs_row, mbs_col, u_plane, &mut result.data, 8);
-                    copy_to_macroblock_8bit(mbs_row, mbs_col, v_plane, &mut result.data, 8);
+                    copy_to_macroblock_8bit(mbs_row, mbs_col, u_plane, result, 8);
+                    copy_to_macroblock_8bit(mbs_row, mbs_col, v_plane, result, 8);
                 }
                 BitDepth::Depth12 => {
-                    copy_to_macroblock_12bit(mbs_row, mbs_col, u_plane, &mut result.data, 12);
-                    copy_to_macroblock_12bit(mbs_row, mbs_col, v_plane, &mut result.data, 12);
+                    copy_to_macroblock_12bit(mbs_row, mbs_col, u_plane, result, 12);
+                    copy_to_macroblock_12bit(mbs_row, mbs_col, v_plane, result, 12);
                 }
             }
         }
     }
 }
 
+/// The maximum size of an encoded parameter set or slice header.
+const MAX_HEADER_SIZE: usize = 64;
+
+/// An encoded parameter set or slice header.
+type HeaderBytes = ByteArray<MAX_HEADER_SIZE>;
+
 /// Raw byte sequence payload (RBSP) data.
 ///
 /// This is merely a newtype to indicate the type of data help within the
 /// `Vec<u8>`.
+#[cfg(feature = "alloc")]
 #[derive(Clone)]
 pub struct RbspData {
     /// Raw byte sequence payload (RBSP) data.
     pub data: Vec<u8>,
 }
 
+#[cfg(feature = "alloc")]
 impl RbspData {
     fn new(data: Vec<u8>) -> Self {
         Self { data }
@@ -922,11 +1219,1061 @@ mod tests {
         assert_eq!(dbg_hex(&encoded), dbg_hex(FIXED_HELLO_SLICE_HEADER));
     }
 
+    fn mono8_image(data: &[u8], width: u32, height: u32) -> YCbCrImage<'_> {
+        YCbCrImage {
+            planes: Planes::Mono(DataPlane {
+                data,
+                stride: width as usize,
+                bit_depth: BitDepth::Depth8,
+            }),
+            width,
+            height,
+        }
+    }
+
+    /// The data of `n` 48x40 mono8 frames with many zeros, to exercise
+    /// emulation prevention.
+    fn test_frames(n: u32) -> Vec<Vec<u8>> {
+        (0..n)
+            .map(|i| (0..48 * 48).map(|j| ((j * i) % 5) as u8).collect())
+            .collect()
+    }
+
+    /// The images of frames from [test_frames].
+    fn test_images(datas: &[Vec<u8>]) -> Vec<YCbCrImage<'_>> {
+        datas.iter().map(|data| mono8_image(data, 48, 40)).collect()
+    }
+
+    /// An encoder configuration with an IDR picture every `idr_interval`
+    /// frames, recovery point SEI messages and slices of `rows` macroblock
+    /// rows.
+    fn gop_config(idr_interval: u32, rows: u32) -> EncoderConfig {
+        EncoderConfig {
+            gop: GopConfig {
+                idr_interval: core::num::NonZeroU32::new(idr_interval),
+                recovery_point_sei: true,
+            },
+            slice_mode: SliceMode::MacroblockRows(core::num::NonZeroU32::new(rows).unwrap()),
+            ..Default::default()
+        }
+    }
+
+    /// Parse the SPS and PPS in `initial` into a new [Context].
+    fn parse_parameter_sets(initial: &InitialNalUnits) -> Context {
+        let encoded = initial.sps.to_annex_b_data();
+        let nal = RefNal::new(&encoded[4..], &[], true);
+        let sps = SeqParameterSet::from_bits(nal.rbsp_bits()).unwrap();
+        let mut ctx = Context::default();
+        ctx.put_seq_param_set(sps);
+        let encoded = initial.pps.to_annex_b_data();
+        let nal = RefNal::new(&encoded[4..], &[], true);
+        let pps = PicParameterSet::from_bits(&ctx, nal.rbsp_bits()).unwrap();
+        ctx.put_pic_param_set(pps);
+        ctx
+    }
+
+    fn parse_slice_header(
+        ctx: &Context,
+        nal_unit: &NalUnit,
+    ) -> h264_reader::nal::slice::SliceHeader {
+        let encoded = nal_unit.to_annex_b_data();
+        let nal = RefNal::new(&encoded[4..], &[], true);
+        h264_reader::nal::slice::SliceHeader::from_bits(
+            ctx,
+            &mut nal.rbsp_bits(),
+            nal.header().unwrap(),
+        )
+        .unwrap()
+        .0
+    }
+
+    #[test]
+    fn test_idr_pic_id_sequence() {
+        use h264_reader::nal::sps::PicOrderCntType as RdrPicOrderCntType;
+
+        let data = vec![0u8; 32 * 32];
+        let image = mono8_image(&data, 32, 32);
+        for pic_order_cnt_type in [
+            PicOrderCntType::Zero,
+            PicOrderCntType::One,
+            PicOrderCntType::Two,
+        ] {
+            let config = EncoderConfig {
+                pic_order_cnt_type,
+                ..Default::default()
+            };
+            let (initial, mut encoder) = LessEncoder::new_with_config(&image, config).unwrap();
+            let ctx = parse_parameter_sets(&initial);
+            let sps = ctx.sps().next().unwrap();
+            match (pic_order_cnt_type, &sps.pic_order_cnt) {
+                (PicOrderCntType::Zero, RdrPicOrderCntType::TypeZero { .. })
+                | (PicOrderCntType::Two, RdrPicOrderCntType::TypeTwo) => {}
+                (
+                    PicOrderCntType::One,
+                    RdrPicOrderCntType::TypeOne {
+                        delta_pic_order_always_zero_flag,
+                        offsets_for_ref_frame,
+                        ..
+                    },
+                ) => {
+                    assert!(delta_pic_order_always_zero_flag);
+                    assert_eq!(offsets_for_ref_frame, &[2]);
+                }
+                (expected, actual) => panic!("expected {expected:?}, got {actual:?}"),
+            }
+
+            let mut frames = vec![initial.frame];
+            for _ in 0..2 {
+                frames.push(encoder.encode(&image).unwrap());
+            }
+            for (i, frame) in frames.iter().enumerate() {
+                let slice_header = parse_slice_header(&ctx, &frame.nal_units[0]);
+                assert_eq!(slice_header.frame_num, 0);
+                let dbg = format!("{slice_header:?}");
+                assert!(dbg.contains(&format!("idr_pic_id: Some({i})")), "{dbg}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_long_idr_interval() {
+        // Longer than the wrapping of `frame_num` and the picture order count.
+        let interval = (1 << 16) + 3;
+        let data = vec![0u8; 16 * 16];
+        let image = mono8_image(&data, 16, 16);
+        let config = EncoderConfig {
+            gop: GopConfig {
+                idr_interval: core::num::NonZeroU32::new(interval),
+                recovery_point_sei: false,
+            },
+            ..Default::default()
+        };
+        let (initial, mut encoder) = LessEncoder::new_with_config(&image, config).unwrap();
+        assert!(initial.frame.is_idr);
+        let mut buf = Vec::new();
+        for i in 1..=interval {
+            buf.clear();
+            let is_idr = encoder
+                .encode_into(&image, NalFraming::AnnexB, &mut buf)
+                .unwrap();
+            assert_eq!(is_idr, i == interval, "frame {i}");
+        }
+    }
+
+    #[test]
+    fn test_gop() {
+        use h264_reader::nal::{sei::HeaderType, Nal, UnitType};
+
+        let data = vec![0u8; 32 * 32];
+        let image = mono8_image(&data, 32, 32);
+        let config = EncoderConfig {
+            gop: GopConfig {
+                idr_interval: core::num::NonZeroU32::new(3),
+                recovery_point_sei: true,
+            },
+            ..Default::default()
+        };
+        let (initial, mut encoder) = LessEncoder::new_with_config(&image, config).unwrap();
+        let ctx = parse_parameter_sets(&initial);
+        assert_eq!(ctx.sps().next().unwrap().max_num_ref_frames, 1);
+
+        let mut frames = vec![initial.frame];
+        for i in 1..8 {
+            if i == 5 {
+                encoder.force_keyframe();
+            }
+            frames.push(encoder.encode(&image).unwrap());
+        }
+
+        // (is_idr, frame_num, pic_order_cnt_lsb, idr_pic_id)
+        let expected = [
+            (true, 0, 0, 0),
+            (false, 1, 2, 0),
+            (false, 2, 4, 0),
+            (true, 0, 0, 1),
+            (false, 1, 2, 0),
+            (true, 0, 0, 2),
+            (false, 1, 2, 0),
+            (false, 2, 4, 0),
+        ];
+        for (frame, (is_idr, frame_num, poc, idr_pic_id)) in frames.iter().zip(expected) {
+            assert_eq!(frame.is_idr, is_idr);
+            let slice = frame.nal_units.last().unwrap();
+            if is_idr {
+                assert_eq!(frame.nal_units.len(), 1);
+            } else {
+                // recovery point SEI precedes the slice
+                assert_eq!(frame.nal_units.len(), 2);
+                let encoded = frame.nal_units[0].to_annex_b_data();
+                let nal = RefNal::new(&encoded[4..], &[], true);
+                assert_eq!(nal.header().unwrap().nal_unit_type(), UnitType::SEI);
+                let mut scratch = Vec::new();
+                let mut rdr = h264_reader::nal::sei::SeiReader::from_rbsp_bytes(
+                    nal.rbsp_bytes(),
+                    &mut scratch,
+                );
+                let msg = rdr.next().unwrap().unwrap();
+                assert_eq!(msg.payload_type, HeaderType::RecoveryPoint);
+                assert_eq!(msg.payload, &[0xC4]);
+            }
+            let slice_header = parse_slice_header(&ctx, slice);
+            assert_eq!(slice_header.frame_num, frame_num);
+            assert_eq!(
+                slice_header.pic_order_cnt_lsb,
+                Some(h264_reader::nal::slice::PicOrderCountLsb::Frame(poc))
+            );
+            let dbg = format!("{slice_header:?}");
+            if is_idr {
+                assert!(
+                    dbg.contains(&format!("idr_pic_id: Some({idr_pic_id})")),
+                    "{dbg}"
+                );
+            } else {
+                assert!(dbg.contains("idr_pic_id: None"), "{dbg}");
+            }
+        }
+    }
+
+    fn first_mb_in_slice(nal_unit: &NalUnit) -> u32 {
+        use h264_reader::rbsp::BitRead;
+        let encoded = nal_unit.to_annex_b_data();
+        let nal = RefNal::new(&encoded[4..], &[], true);
+        nal.rbsp_bits().read_ue("first_mb_in_slice").unwrap()
+    }
+
+    #[test]
+    fn test_slices() {
+        // 2 x 3 macroblocks
+        let data = vec![0u8; 32 * 48];
+        let image = mono8_image(&data, 32, 48);
+
+        let config = EncoderConfig {
+            slice_mode: SliceMode::MacroblockRows(core::num::NonZeroU32::new(2).unwrap()),
+            ..Default::default()
+        };
+        let (initial, mut encoder) = LessEncoder::new_with_config(&image, config).unwrap();
+        let ctx = parse_parameter_sets(&initial);
+        for frame in [initial.frame, encoder.encode(&image).unwrap()] {
+            assert_eq!(frame.nal_units.len(), 2);
+            let first_mbs: Vec<u32> = frame
+                .nal_units
+                .iter()
+                .map(|nal_unit| {
+                    // parse to check validity
+                    parse_slice_header(&ctx, nal_unit);
+                    first_mb_in_slice(nal_unit)
+                })
+                .collect();
+            assert_eq!(first_mbs, [0, 4]);
+        }
+
+        // All-zero data is the worst case for emulation prevention.
+        let max_bytes = 600;
+        let config = EncoderConfig {
+            slice_mode: SliceMode::MaxBytes(max_bytes),
+            ..Default::default()
+        };
+        let (initial, _encoder) = LessEncoder::new_with_config(&image, config).unwrap();
+        let ctx = parse_parameter_sets(&initial);
+        assert_eq!(initial.frame.nal_units.len(), 6);
+        for (i, nal_unit) in initial.frame.nal_units.iter().enumerate() {
+            assert!(nal_unit.to_nal_unit().len() <= max_bytes);
+            parse_slice_header(&ctx, nal_unit);
+            assert_eq!(first_mb_in_slice(nal_unit), i as u32);
+        }
+
+        let config = EncoderConfig {
+            slice_mode: SliceMode::MaxBytes(300),
+            ..Default::default()
+        };
+        assert!(matches!(
+            LessEncoder::new_with_config(&image, config),
+            Err(Error::InvalidConfiguration { .. })
+        ));
+    }
+
+    #[test]
+    fn test_encode_batch() {
+        let (width, height) = (48, 32);
+        let datas: Vec<Vec<u8>> = (0..7u8).map(|i| vec![i; 48 * 32]).collect();
+        let images: Vec<YCbCrImage> = datas
+            .iter()
+            .map(|data| mono8_image(data, width, height))
+            .collect();
+        let config = gop_config(3, 1);
+
+        let (_, mut sequential) = LessEncoder::new_with_config(&images[0], config.clone()).unwrap();
+        let (_, mut batch) = LessEncoder::new_with_config(&images[0], config).unwrap();
+        let mut expected = Vec::new();
+        for image in images.iter() {
+            expected.push(sequential.encode(image).unwrap());
+        }
+        sequential.force_keyframe();
+        expected.push(sequential.encode(&images[0]).unwrap());
+
+        let mut actual = batch.encode_batch(&images).unwrap();
+        batch.force_keyframe();
+        actual.extend(batch.encode_batch(&images[..1]).unwrap());
+
+        assert_eq!(actual.len(), expected.len());
+        for (actual, expected) in actual.iter().zip(expected.iter()) {
+            assert_eq!(actual.is_idr, expected.is_idr);
+            assert_eq!(actual.to_annex_b_data(), expected.to_annex_b_data());
+        }
+    }
+
+    #[test]
+    fn test_encode_into() {
+        use nal_unit::NalFraming;
+
+        let datas = test_frames(5);
+        let images = test_images(&datas);
+        let config = gop_config(3, 2);
+        let (_, mut expected_encoder) =
+            LessEncoder::new_with_config(&images[0], config.clone()).unwrap();
+        let (_, mut encoder) = LessEncoder::new_with_config(&images[0], config).unwrap();
+
+        let mut buf = Vec::new();
+        for image in images.iter() {
+            let expected = expected_encoder.encode(image).unwrap();
+
+            buf.clear();
+            let is_idr = encoder
+                .encode_into(image, NalFraming::AnnexB, &mut buf)
+                .unwrap();
+            assert_eq!(is_idr, expected.is_idr);
+            assert_eq!(buf, expected.to_annex_b_data());
+
+            // Each NAL unit is preceded by its size.
+            buf.clear();
+            encoder.force_keyframe();
+            expected_encoder.force_keyframe();
+            let expected = expected_encoder.encode(image).unwrap();
+            encoder
+                .encode_into(image, NalFraming::LengthPrefixed, &mut buf)
+                .unwrap();
+            let mut remaining = &buf[..];
+            for nal_unit in expected.nal_units.iter() {
+                let nal = nal_unit.to_annex_b_data();
+                let size = u32::from_be_bytes(remaining[..4].try_into().unwrap()) as usize;
+                assert_eq!(&remaining[4..4 + size], &nal[4..]);
+                remaining = &remaining[4 + size..];
+            }
+            assert!(remaining.is_empty());
+        }
+    }
+
+    #[test]
+    fn test_annex_b_parse() {
+        use annex_b::{AnnexBReader, AnnexBSliceReader};
+
+        let datas = test_frames(5);
+        let images = test_images(&datas);
+        let config = gop_config(3, 2);
+        let (initial, mut encoder) = LessEncoder::new_with_config(&images[0], config).unwrap();
+        let mut expected: Vec<NalUnit> = initial.into_iter().collect();
+        for image in images[1..].iter() {
+            expected.extend(encoder.encode(image).unwrap().nal_units);
+        }
+        let stream: Vec<u8> = expected.iter().flat_map(|n| n.to_annex_b_data()).collect();
+
+        let check = |parsed: Vec<NalUnit>| {
+            assert_eq!(parsed.len(), expected.len());
+            for (actual, expected) in parsed.iter().zip(expected.iter()) {
+                assert_eq!(actual.ref_idc(), expected.ref_idc());
+                assert_eq!(actual.unit_type(), expected.unit_type());
+                assert_eq!(actual.rbsp_data().data, expected.rbsp_data().data);
+            }
+        };
+        check(
+            AnnexBSliceReader::new(&stream)
+                .collect::<Result<_>>()
+                .unwrap(),
+        );
+        check(
+            AnnexBReader::new(std::io::Cursor::new(&stream))
+                .collect::<Result<_>>()
+                .unwrap(),
+        );
+    }
+
+    #[test]
+    fn test_decoder() {
+        // Planes include the padding to whole macroblocks so that decoding
+        // returns them unchanged.
+        fn plane(seed: u32, stride: usize, rows: usize, bit_depth: BitDepth) -> OwnedDataPlane {
+            let data = (0..stride * rows)
+                .map(|i| ((i as u32 * seed) % 7) as u8)
+                .collect();
+            OwnedDataPlane {
+                data,
+                stride,
+                bit_depth,
+            }
+        }
+
+        for (width, height) in [(40u32, 30u32), (39, 30), (64, 32)] {
+            let (mbs_width, mbs_height) =
+                (width.div_ceil(16) as usize, height.div_ceil(16) as usize);
+            for (bit_depth, bytes_per_16) in [(BitDepth::Depth8, 16), (BitDepth::Depth12, 24)] {
+                let y = plane(3, mbs_width * bytes_per_16, mbs_height * 16, bit_depth);
+                let cb = plane(5, mbs_width * bytes_per_16 / 2, mbs_height * 8, bit_depth);
+                let cr = plane(11, mbs_width * bytes_per_16 / 2, mbs_height * 8, bit_depth);
+                // Odd widths are only supported for mono8.
+                let mut all_planes = Vec::new();
+                if width % 2 == 0 || bit_depth == BitDepth::Depth8 {
+                    all_planes.push(OwnedPlanes::Mono(y.clone()));
+                }
+                if width % 2 == 0 {
+                    all_planes.push(OwnedPlanes::YCbCr((y, cb, cr)));
+                }
+                for planes in all_planes {
+                    let image = OwnedYCbCrImage {
+                        planes,
+                        width,
+                        height,
+                    };
+                    let config = EncoderConfig {
+                        gop: GopConfig {
+                            idr_interval: core::num::NonZeroU32::new(2),
+                            recovery_point_sei: true,
+                        },
+                        slice_mode: SliceMode::MaxBytes(1200),
+                        ..Default::default()
+                    };
+                    let (initial, mut encoder) =
+                        LessEncoder::new_with_config(&image.view(), config).unwrap();
+                    let mut stream = initial.sps.to_annex_b_data();
+                    stream.extend(initial.pps.to_annex_b_data());
+                    stream.extend(initial.frame.to_annex_b_data());
+                    for _ in 0..2 {
+                        stream.extend(encoder.encode(&image.view()).unwrap().to_annex_b_data());
+                    }
+
+                    let decoded = LessDecoder::new().decode_annex_b(&stream).unwrap();
+                    assert_eq!(decoded.len(), 3);
+                    for decoded_image in decoded {
+                        assert!(decoded_image == image);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_decoder_errors() {
+        use bitstream::RbspWriter;
+        use nal_unit::{NalRefIdc, NalUnitType};
+
+        let data = vec![0u8; 32 * 32];
+        let (initial, _encoder) = LessEncoder::new(&mono8_image(&data, 32, 32)).unwrap();
+        let mut decoder = LessDecoder::new();
+        for nal_unit in [&initial.sps, &initial.pps] {
+            assert!(decoder.decode_nal_unit(nal_unit).unwrap().is_none());
+        }
+        // The picture has 4 macroblocks, so one slice per macroblock.
+        let slices = LessEncoder::new_with_config(
+            &mono8_image(&data, 32, 32),
+            EncoderConfig {
+                slice_mode: SliceMode::MaxBytes(500),
+                ..Default::default()
+            },
+        )
+        .unwrap()
+        .0
+        .frame
+        .nal_units;
+        assert_eq!(slices.len(), 4);
+        assert!(decoder.decode_nal_unit(&slices[0]).unwrap().is_none());
+        assert!(matches!(
+            decoder.decode_nal_unit(&slices[2]),
+            Err(Error::InvalidBitstream { .. })
+        ));
+
+        // A picture whose last slices were lost is discarded when the next
+        // picture starts, and the next picture is decoded.
+        let mut stream = Vec::new();
+        for nal_unit in [&initial.sps, &initial.pps].into_iter().chain(&slices[..2]) {
+            stream.extend(nal_unit.to_annex_b_data());
+        }
+        for nal_unit in slices.iter() {
+            stream.extend(nal_unit.to_annex_b_data());
+        }
+        let mut decoder = LessDecoder::new();
+        let decoded = decoder.decode_annex_b(&stream).unwrap();
+        assert_eq!(decoded.len(), 1);
+        assert_eq!(decoder.pictures_discarded(), 1);
+
+        // PPS with entropy_coding_mode_flag = 1
+        let mut pps = RbspWriter::new();
+        pps.write_ue(0);
+        pps.write_ue(0);
+        pps.write_bit(true);
+        pps.write_trailing_bits();
+        let pps = NalUnit::new(
+            NalRefIdc::Three,
+            NalUnitType::PictureParameterSet,
+            pps.finish(),
+        );
+        assert!(matches!(
+            decoder.decode_nal_unit(&pps),
+            Err(Error::UnsupportedBitstream { .. })
+        ));
+    }
+
+    #[test]
+    fn test_frame_index() {
+        use index::{FrameIndex, FrameIndexEntry, IndexFormat, IndexedReader};
+        use sei::{SupplementalEnhancementInformation, UserDataUnregistered};
+
+        let datas = test_frames(5);
+        let images = test_images(&datas);
+        let config = gop_config(3, 2);
+
+        // Precede each frame but the last with a time stamp.
+        let timestamp_sei = |i: usize| {
+            let udu = UserDataUnregistered::new_precision_time_stamp(1_000_000 + i as u64);
+            NalUnit::new(
+                NalRefIdc::Zero,
+                NalUnitType::SupplementalEnhancementInformation,
+                SupplementalEnhancementInformation::UserDataUnregistered(udu).to_rbsp(),
+            )
+            .to_annex_b_data()
+        };
+        let (initial, mut encoder) =
+            LessEncoder::new_with_config(&images[0], config.clone()).unwrap();
+        let mut stream = vec![0xff];
+        let mut offsets = vec![stream.len() as u64];
+        stream.extend(initial.sps.to_annex_b_data());
+        stream.extend(initial.pps.to_annex_b_data());
+        stream.extend(timestamp_sei(0));
+        stream.extend(initial.frame.to_annex_b_data());
+        for (i, image) in images.iter().enumerate().skip(1) {
+            offsets.push(stream.len() as u64);
+            if i < 4 {
+                stream.extend(timestamp_sei(i));
+            }
+            stream.extend(encoder.encode(image).unwrap().to_annex_b_data());
+        }
+
+        let index = FrameIndex::from_annex_b(&stream).unwrap();
+        let mut cursor = std::io::Cursor::new(&stream);
+        cursor.set_position(1);
+        assert_eq!(FrameIndex::build(&mut cursor).unwrap(), index);
+        assert_eq!(index.len(), 5);
+        for (i, entry) in index.entries().iter().enumerate() {
+            assert_eq!(entry.offset, offsets[i]);
+            let end = offsets.get(i + 1).copied().unwrap_or(stream.len() as u64);
+            assert_eq!(entry.offset + u64::from(entry.size), end);
+            assert_eq!(entry.is_sync, i % 3 == 0);
+            assert_eq!(entry.timestamp, (i < 4).then_some(1_000_000 + i as u64));
+        }
+
+        let mut rdr = IndexedReader::new(std::io::Cursor::new(&stream), index.clone());
+        for i in [3, 1, 4, 0, 2] {
+            let decoded = rdr.frame(i).unwrap().unwrap();
+            assert_eq!((decoded.width, decoded.height), (48, 40));
+            match decoded.planes {
+                ycbcr_image::OwnedPlanes::Mono(y) => assert_eq!(y.data, datas[i]),
+                _ => panic!("expected monochrome planes"),
+            }
+        }
+        assert!(rdr.frame(5).unwrap().is_none());
+        assert_eq!(
+            IndexedReader::build(std::io::Cursor::new(&stream))
+                .unwrap()
+                .index(),
+            &index
+        );
+
+        // The index written while recording matches the one built afterwards.
+        #[derive(Clone, Default)]
+        struct SharedBuf(std::sync::Arc<std::sync::Mutex<Vec<u8>>>);
+        impl std::io::Write for SharedBuf {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.0.lock().unwrap().write(buf)
+            }
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+        let sidecar = SharedBuf::default();
+        let mut wtr = H264Writer::new_with_config(Vec::new(), config.clone()).unwrap();
+        wtr.set_index_sidecar(sidecar.clone(), IndexFormat::Binary)
+            .unwrap();
+        let mut infos = vec![wtr.write(&images[0]).unwrap()];
+        infos.extend(wtr.write_batch(&images[1..3]).unwrap());
+        infos.push(wtr.write(&images[3]).unwrap());
+        infos.push(wtr.write(&images[4]).unwrap());
+        assert!(wtr
+            .set_index_sidecar(SharedBuf::default(), IndexFormat::Binary)
+            .is_err());
+        let written = wtr.into_inner();
+        let mut unindexed = H264Writer::new_with_config(Vec::new(), config.clone()).unwrap();
+        unindexed.write_batch(&images).unwrap();
+        assert_eq!(unindexed.into_inner(), written);
+        let index = FrameIndex::from_annex_b(&written).unwrap();
+        let sidecar = sidecar.0.lock().unwrap();
+        assert_eq!(FrameIndex::read_from(&sidecar[..]).unwrap(), index);
+        let mut expected = Vec::new();
+        index.write_to(&mut expected, IndexFormat::Binary).unwrap();
+        assert_eq!(*sidecar, expected);
+        for (info, entry) in infos.iter().zip(index.entries()) {
+            assert_eq!(info.offset, entry.offset);
+            assert_eq!(info.size, u64::from(entry.size));
+            assert_eq!(entry.nal_sizes.iter().sum::<u32>(), entry.size);
+        }
+        // SPS, PPS and 2 slices
+        assert_eq!(index.entries()[0].nal_sizes.len(), 4);
+
+        // User time stamps in CSV format
+        let sidecar = SharedBuf::default();
+        let mut wtr = H264Writer::new_with_config(Vec::new(), config).unwrap();
+        wtr.set_index_sidecar(sidecar.clone(), IndexFormat::Csv)
+            .unwrap();
+        for (i, image) in images.iter().enumerate() {
+            wtr.write_with_timestamp(image, 100 * i as u64).unwrap();
+        }
+        assert_eq!(wtr.into_inner(), written);
+        let csv_index = FrameIndex::read_from(&sidecar.0.lock().unwrap()[..]).unwrap();
+        assert_eq!(csv_index.len(), index.len());
+        for (i, (actual, expected)) in csv_index.entries().iter().zip(index.entries()).enumerate() {
+            assert_eq!(actual.timestamp, Some(100 * i as u64));
+            assert_eq!(
+                FrameIndexEntry {
+                    timestamp: None,
+                    ..actual.clone()
+                },
+                *expected
+            );
+        }
+    }
+
+    #[test]
+    fn test_sink_error() {
+        /// A sink which is always full.
+        struct FullSink;
+        impl NalSink for FullSink {
+            fn write_nal_bytes(&mut self, _data: &[u8]) -> Result<()> {
+                Err(Error::sink_error("sink full"))
+            }
+        }
+
+        let data = vec![0u8; 16 * 16];
+        let image = mono8_image(&data, 16, 16);
+        let result = LessEncoder::new_to_sink(&image, EncoderConfig::default(), &mut FullSink);
+        assert!(matches!(
+            result,
+            Err(Error::SinkError {
+                msg: "sink full",
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn test_encode_to_sink() {
+        let datas = test_frames(4);
+        let images = test_images(&datas);
+        let config = gop_config(2, 2);
+
+        let (initial, mut expected_encoder) =
+            LessEncoder::new_with_config(&images[0], config.clone()).unwrap();
+        let mut expected: Vec<u8> = initial
+            .into_iter()
+            .flat_map(|nal_unit| nal_unit.to_annex_b_data())
+            .collect();
+        let mut actual = Vec::new();
+        let mut encoder = LessEncoder::new_to_sink(&images[0], config, &mut actual).unwrap();
+        assert_eq!(actual, expected);
+
+        for image in images[1..].iter() {
+            expected.extend(expected_encoder.encode(image).unwrap().to_annex_b_data());
+            encoder.encode_to_sink(image, &mut actual).unwrap();
+        }
+        assert_eq!(actual, expected);
+
+        // Errors from the sink are returned.
+        let mut full = [0u8; 100];
+        let mut sink = &mut full[..];
+        assert!(matches!(
+            encoder.encode_to_sink(&images[0], &mut sink),
+            Err(Error::IoError { .. })
+        ));
+    }
+
+    #[test]
+    fn test_encode_into_slice() {
+        use nal_unit::NalFraming;
+
+        // Buffers can be sized at compile time.
+        const MAX_SIZE: usize = LessEncoder::MAX_PARAMETER_SETS_SIZE
+            + LessEncoder::max_frame_size(48, 40, BitDepth::Depth8, true, 3);
+
+        let datas = test_frames(4);
+        let images = test_images(&datas);
+        let config = gop_config(3, 1);
+
+        for framing in [NalFraming::AnnexB, NalFraming::LengthPrefixed] {
+            let (initial, mut expected_encoder) =
+                LessEncoder::new_with_config(&images[0], config.clone()).unwrap();
+            let mut expected = Vec::new();
+            for nal_unit in initial {
+                nal_unit.write_into(framing, &mut expected);
+            }
+
+            let mut buf = [0u8; MAX_SIZE];
+            let (size, mut encoder) =
+                LessEncoder::new_into_slice(&images[0], config.clone(), framing, &mut buf).unwrap();
+            assert_eq!(&buf[..size], &expected[..]);
+
+            // Too small a buffer is an error which leaves the encoder unchanged.
+            let needed = encoder.max_encoded_size();
+            assert!(matches!(
+                encoder.encode_into_slice(&images[1], framing, &mut buf[..needed - 1]),
+                Err(Error::BufferTooSmall { .. })
+            ));
+
+            for image in images.iter() {
+                expected.clear();
+                let expected_idr = expected_encoder
+                    .encode_into(image, framing, &mut expected)
+                    .unwrap();
+                let (size, is_idr) = encoder
+                    .encode_into_slice(image, framing, &mut buf[..needed])
+                    .unwrap();
+                assert_eq!(is_idr, expected_idr);
+                assert_eq!(&buf[..size], &expected[..]);
+            }
+        }
+    }
+
+    #[test]
+    fn test_max_frame_size() {
+        // Zeros require the most emulation prevention.
+        let (width, height) = (40, 40);
+        let y = vec![0u8; 48 * 48];
+        let uv = vec![0u8; 24 * 24];
+        let image = YCbCrImage {
+            planes: Planes::YCbCr((
+                DataPlane {
+                    data: &y,
+                    stride: 48,
+                    bit_depth: BitDepth::Depth8,
+                },
+                DataPlane {
+                    data: &uv,
+                    stride: 24,
+                    bit_depth: BitDepth::Depth8,
+                },
+                DataPlane {
+                    data: &uv,
+                    stride: 24,
+                    bit_depth: BitDepth::Depth8,
+                },
+            )),
+            width,
+            height,
+        };
+        let config = EncoderConfig {
+            gop: GopConfig {
+                idr_interval: None,
+                recovery_point_sei: true,
+            },
+            slice_mode: SliceMode::MacroblockRows(core::num::NonZeroU32::new(1).unwrap()),
+            ..Default::default()
+        };
+        let (_, mut encoder) = LessEncoder::new_with_config(&image, config).unwrap();
+        let max_size = encoder.max_encoded_size();
+        assert_eq!(
+            max_size,
+            LessEncoder::max_frame_size(width, height, BitDepth::Depth8, false, 3)
+        );
+        let mut buf = Vec::new();
+        encoder
+            .encode_into(&image, nal_unit::NalFraming::AnnexB, &mut buf)
+            .unwrap();
+        assert!(buf.len() <= max_size);
+
+        // Evaluated at compile time, zero slices count as one.
+        const ZERO_SLICES: usize = LessEncoder::max_frame_size(40, 40, BitDepth::Depth8, false, 0);
+        assert_eq!(
+            ZERO_SLICES,
+            LessEncoder::max_frame_size(width, height, BitDepth::Depth8, false, 1)
+        );
+    }
+
+    #[test]
+    fn test_write_batch() {
+        let (width, height) = (48, 32);
+        let datas: Vec<Vec<u8>> = (0..5u8).map(|i| vec![i; 48 * 32]).collect();
+        let images: Vec<YCbCrImage> = datas
+            .iter()
+            .map(|data| mono8_image(data, width, height))
+            .collect();
+
+        let mut expected = H264Writer::new(Vec::new()).unwrap();
+        for image in images.iter() {
+            expected.write(image).unwrap();
+        }
+        let mut actual = H264Writer::new(Vec::new()).unwrap();
+        let infos = actual.write_batch(&images[..3]).unwrap();
+        assert_eq!(infos.len(), 3);
+        assert!(infos[0].is_sync);
+        let infos = actual.write_batch(&images[3..]).unwrap();
+        assert_eq!(infos[1].frame_number, 4);
+        assert_eq!(actual.into_inner(), expected.into_inner());
+    }
+
+    #[test]
+    fn test_threaded_writer() {
+        let (width, height) = (48, 32);
+        let datas: Vec<Vec<u8>> = (0..6u8).map(|i| vec![i; 48 * 32]).collect();
+        let images: Vec<YCbCrImage> = datas
+            .iter()
+            .map(|data| mono8_image(data, width, height))
+            .collect();
+
+        let mut expected = H264Writer::new(Vec::new()).unwrap();
+        for image in images.iter() {
+            expected.write(image).unwrap();
+        }
+
+        let wtr = H264Writer::new(Vec::new()).unwrap();
+        let mut threaded = ThreadedH264Writer::new(wtr, 2, QueueFullPolicy::Block).unwrap();
+        for image in images.iter() {
+            assert!(threaded.write(image.into()).unwrap());
+        }
+        let stats = threaded.stats();
+        assert_eq!(stats.frames_dropped, 0);
+        let (data, result) = threaded.finish();
+        result.unwrap();
+        assert_eq!(data, expected.into_inner());
+    }
+
+    #[test]
+    fn test_threaded_writer_drop() {
+        use std::sync::{mpsc, Arc, Mutex};
+
+        /// Blocks the first write until released.
+        struct GatedWriter {
+            entered: Option<mpsc::Sender<()>>,
+            gate: Arc<Mutex<()>>,
+            buf: Vec<u8>,
+        }
+        impl std::io::Write for GatedWriter {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                if let Some(entered) = self.entered.take() {
+                    entered.send(()).unwrap();
+                    let _guard = self.gate.lock().unwrap();
+                }
+                self.buf.write(buf)
+            }
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let (width, height) = (48, 32);
+        let datas: Vec<Vec<u8>> = (0..4u8).map(|i| vec![i; 48 * 32]).collect();
+        let images: Vec<YCbCrImage> = datas
+            .iter()
+            .map(|data| mono8_image(data, width, height))
+            .collect();
+
+        let gate = Arc::new(Mutex::new(()));
+        let guard = gate.lock().unwrap();
+        let (entered_tx, entered_rx) = mpsc::channel();
+        let wtr = H264Writer::new(GatedWriter {
+            entered: Some(entered_tx),
+            gate: gate.clone(),
+            buf: Vec::new(),
+        })
+        .unwrap();
+        let mut threaded = ThreadedH264Writer::new(wtr, 1, QueueFullPolicy::Drop).unwrap();
+
+        // The worker takes the first frame and blocks while writing it.
+        assert!(threaded.write((&images[0]).into()).unwrap());
+        entered_rx.recv().unwrap();
+        // The second frame fills the queue, the rest are dropped.
+        assert!(threaded.write((&images[1]).into()).unwrap());
+        assert!(!threaded.write((&images[2]).into()).unwrap());
+        assert!(!threaded.write((&images[3]).into()).unwrap());
+        let stats = threaded.stats();
+        assert_eq!(stats.frames_dropped, 2);
+        assert_eq!(stats.queue_depth, 1);
+
+        drop(guard);
+        let (gated, result) = threaded.finish();
+        result.unwrap();
+
+        let mut expected = H264Writer::new(Vec::new()).unwrap();
+        expected.write(&images[0]).unwrap();
+        expected.write(&images[1]).unwrap();
+        assert_eq!(gated.buf, expected.into_inner());
+    }
+
+    #[test]
+    fn test_writer_first_frame_error() {
+        /// Fails the first write.
+        struct FailFirst {
+            buf: Vec<u8>,
+            failed: bool,
+        }
+        impl std::io::Write for FailFirst {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                if !self.failed {
+                    self.failed = true;
+                    return Err(std::io::ErrorKind::WriteZero.into());
+                }
+                self.buf.write(buf)
+            }
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let data = vec![0u8; 48 * 32];
+        let image = mono8_image(&data, 48, 32);
+        let mut wtr = H264Writer::new(FailFirst {
+            buf: Vec::new(),
+            failed: false,
+        })
+        .unwrap();
+        assert!(matches!(wtr.write(&image), Err(Error::IoError { .. })));
+        // The next frame starts the stream again.
+        wtr.write(&image).unwrap();
+        let mut expected = H264Writer::new(Vec::new()).unwrap();
+        expected.write(&image).unwrap();
+        assert_eq!(wtr.into_inner().buf, expected.into_inner());
+    }
+
+    #[test]
+    fn test_threaded_writer_error() {
+        /// Fails writes once `limit` bytes have been written.
+        struct LimitedWriter {
+            buf: Vec<u8>,
+            limit: usize,
+        }
+        impl std::io::Write for LimitedWriter {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                if self.buf.len() + buf.len() > self.limit {
+                    return Err(std::io::ErrorKind::WriteZero.into());
+                }
+                self.buf.write(buf)
+            }
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let data = vec![0u8; 48 * 32];
+        let image = mono8_image(&data, 48, 32);
+        let mut expected = H264Writer::new(Vec::new()).unwrap();
+        expected.write(&image).unwrap();
+        let expected = expected.into_inner();
+
+        // Only the first frame fits.
+        let wtr = H264Writer::new(LimitedWriter {
+            buf: Vec::new(),
+            limit: expected.len() * 3 / 2,
+        })
+        .unwrap();
+        let mut threaded = ThreadedH264Writer::new(wtr, 10, QueueFullPolicy::Block).unwrap();
+        for _ in 0..3 {
+            threaded.write((&image).into()).unwrap();
+        }
+
+        // The writer is returned with the error and the frame before it.
+        let (limited, result) = threaded.finish();
+        assert!(matches!(result, Err(Error::IoError { .. })));
+        assert!(limited.buf == expected);
+
+        // Also when the very first frame fails.
+        let wtr = H264Writer::new(LimitedWriter {
+            buf: Vec::new(),
+            limit: 0,
+        })
+        .unwrap();
+        let mut threaded = ThreadedH264Writer::new(wtr, 10, QueueFullPolicy::Block).unwrap();
+        threaded.write((&image).into()).unwrap();
+        let (limited, result) = threaded.finish();
+        assert!(matches!(result, Err(Error::IoError { .. })));
+        assert!(limited.buf.is_empty());
+    }
+
+    #[cfg(feature = "rayon")]
+    #[test]
+    fn test_parallel_deterministic() {
+        let (width, height) = (100, 80);
+        let data: Vec<u8> = (0..(112 * height)).map(|i| (i % 251) as u8).collect();
+        let image = mono8_image(&data, 112, height);
+        let image = YCbCrImage { width, ..image };
+
+        let encode_with_threads = |num_threads, slice_mode: &SliceMode| {
+            let pool = rayon::ThreadPoolBuilder::new()
+                .num_threads(num_threads)
+                .build()
+                .unwrap();
+            pool.install(|| {
+                let config = EncoderConfig {
+                    slice_mode: slice_mode.clone(),
+                    ..Default::default()
+                };
+                let (initial, mut encoder) = LessEncoder::new_with_config(&image, config).unwrap();
+                let mut result = initial.frame.to_annex_b_data();
+                result.extend(encoder.encode(&image).unwrap().to_annex_b_data());
+                result
+            })
+        };
+
+        for slice_mode in [
+            SliceMode::Single,
+            SliceMode::MacroblockRows(core::num::NonZeroU32::new(2).unwrap()),
+            SliceMode::MaxBytes(1000),
+        ] {
+            let expected = encode_with_threads(1, &slice_mode);
+            for num_threads in [2, 3, 8] {
+                assert_eq!(encode_with_threads(num_threads, &slice_mode), expected);
+            }
+        }
+    }
+
+    #[test]
+    fn test_low_latency_sps() {
+        use h264_reader::nal::sps::PicOrderCntType as RdrPicOrderCntType;
+
+        let data = vec![0u8; 32 * 32];
+        let image = mono8_image(&data, 32, 32);
+        let config = EncoderConfig {
+            low_latency: true,
+            pic_order_cnt_type: PicOrderCntType::Two,
+            ..Default::default()
+        };
+        let (initial, _encoder) = LessEncoder::new_with_config(&image, config).unwrap();
+
+        let ctx = parse_parameter_sets(&initial);
+        let sps = ctx.sps().next().unwrap();
+        assert_eq!(sps.pic_order_cnt, RdrPicOrderCntType::TypeTwo);
+        let restrictions = sps
+            .vui_parameters
+            .as_ref()
+            .unwrap()
+            .bitstream_restrictions
+            .as_ref()
+            .unwrap();
+        assert_eq!(restrictions.max_num_reorder_frames, 0);
+        assert_eq!(restrictions.max_dec_frame_buffering, 0);
+
+        let slice_header = parse_slice_header(&ctx, &initial.frame.nal_units[0]);
+        assert_eq!(slice_header.pic_order_cnt_lsb, None);
+    }
+
     #[test]
     fn test_macroblock() {
-        let mut bv: BitVec<u8, Msb0> = BitVec::new();
-        bv.extend_exp_golomb(MacroblockType::I_PCM.mb_type());
-        let macroblock_header = bv.as_raw_slice();
+        let mut bv = BitWriter::new(Vec::new());
+        bv.write_ue(MacroblockType::I_PCM.mb_type());
+        let macroblock_header = bv.finish();
 
         assert_eq!(macroblock_header, HELLO_MACROBLOCK_HEADER);
     }
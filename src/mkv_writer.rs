@@ -0,0 +1,468 @@
+// Copyright 2022-2023 Andrew D. Straw.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT
+// or http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Writes encoded frames to a Matroska file.
+
+use std::{
+    io::{Seek, SeekFrom, Write},
+    time::Duration,
+};
+
+use super::{
+    nal_unit::{InitialNalUnits, NalFraming},
+    EncoderConfig, Error, LessEncoder, Result, YCbCrImage,
+};
+
+// EBML element IDs, including their length marker bits.
+const EBML: u32 = 0x1a45_dfa3;
+const EBML_VERSION: u32 = 0x4286;
+const EBML_READ_VERSION: u32 = 0x42f7;
+const EBML_MAX_ID_LENGTH: u32 = 0x42f2;
+const EBML_MAX_SIZE_LENGTH: u32 = 0x42f3;
+const DOC_TYPE: u32 = 0x4282;
+const DOC_TYPE_VERSION: u32 = 0x4287;
+const DOC_TYPE_READ_VERSION: u32 = 0x4285;
+const SEGMENT: u32 = 0x1853_8067;
+const SEEK_HEAD: u32 = 0x114d_9b74;
+const SEEK: u32 = 0x4dbb;
+const SEEK_ID: u32 = 0x53ab;
+const SEEK_POSITION: u32 = 0x53ac;
+const INFO: u32 = 0x1549_a966;
+const TIMESTAMP_SCALE: u32 = 0x2ad7b1;
+const DURATION: u32 = 0x4489;
+const MUXING_APP: u32 = 0x4d80;
+const WRITING_APP: u32 = 0x5741;
+const TRACKS: u32 = 0x1654_ae6b;
+const TRACK_ENTRY: u32 = 0xae;
+const TRACK_NUMBER: u32 = 0xd7;
+const TRACK_UID: u32 = 0x73c5;
+const TRACK_TYPE: u32 = 0x83;
+const FLAG_LACING: u32 = 0x9c;
+const CODEC_ID: u32 = 0x86;
+const CODEC_PRIVATE: u32 = 0x63a2;
+const VIDEO: u32 = 0xe0;
+const PIXEL_WIDTH: u32 = 0xb0;
+const PIXEL_HEIGHT: u32 = 0xba;
+const CLUSTER: u32 = 0x1f43_b675;
+const TIMESTAMP: u32 = 0xe7;
+const SIMPLE_BLOCK: u32 = 0xa3;
+const CUES: u32 = 0x1c53_bb6b;
+const CUE_POINT: u32 = 0xbb;
+const CUE_TIME: u32 = 0xb3;
+const CUE_TRACK_POSITIONS: u32 = 0xb7;
+const CUE_TRACK: u32 = 0xf7;
+const CUE_CLUSTER_POSITION: u32 = 0xf1;
+
+/// An element data size of 8 bytes meaning "unknown".
+const UNKNOWN_SIZE: [u8; 8] = [0x01, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff];
+
+/// Options for [MkvWriter].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MkvConfig {
+    /// Options for the encoder.
+    pub encoder: EncoderConfig,
+    /// The duration of one time stamp tick in nanoseconds (the Matroska
+    /// `TimestampScale`).
+    ///
+    /// Frame time stamps are rounded to this resolution. The default is
+    /// 1000000, giving millisecond time stamps.
+    pub timestamp_scale: u32,
+}
+
+impl Default for MkvConfig {
+    fn default() -> Self {
+        Self {
+            encoder: EncoderConfig::default(),
+            timestamp_scale: 1_000_000,
+        }
+    }
+}
+
+/// A cluster being written.
+struct Cluster {
+    /// The file position of the size of the cluster element.
+    size_pos: u64,
+    /// The time stamp of the cluster in ticks.
+    timestamp: u64,
+}
+
+/// A keyframe to be listed in the cues.
+struct CuePoint {
+    timestamp: u64,
+    /// The position of the cluster relative to the segment data.
+    cluster_position: u64,
+}
+
+/// File positions of values filled in by [MkvWriter::finish].
+struct Header {
+    /// The position of the segment data.
+    segment_data_pos: u64,
+    /// The position of the `SeekPosition` value of the cues.
+    cues_seek_pos: u64,
+    /// The position of the `Duration` value.
+    duration_pos: u64,
+}
+
+/// Write images to an [std::io::Write] implementation in Matroska format.
+///
+/// The file holds a single `V_MPEG4/ISO/AVC` track whose `CodecPrivate`
+/// element contains the parameter sets (see
+/// [InitialNalUnits::avc_decoder_configuration_record]). Frames are stored as
+/// length-prefixed `SimpleBlock` elements with their time stamps, and IDR
+/// pictures are flagged as keyframes. Each IDR picture starts a new cluster
+/// and is listed in the cues for seeking.
+///
+/// Clusters are written with unknown size and completed when the next cluster
+/// starts, so everything written before an interruption remains playable.
+/// [Self::finish] writes the cues and the duration, which requires
+/// [std::io::Seek].
+pub struct MkvWriter<W> {
+    wtr: W,
+    config: MkvConfig,
+    encoder: Option<LessEncoder>,
+    header: Option<Header>,
+    /// The current position of `wtr`.
+    position: u64,
+    first_timestamp: Duration,
+    cluster: Option<Cluster>,
+    cues: Vec<CuePoint>,
+    /// The time stamp of the last frame in ticks.
+    last_timestamp: u64,
+    /// The duration of the frame before the last frame, in ticks.
+    last_duration: u64,
+    /// Buffer for elements, reused across frames.
+    buf: Vec<u8>,
+    frame_data: Vec<u8>,
+}
+
+impl<W: Write + Seek> MkvWriter<W> {
+    /// Create a new [MkvWriter] from an [std::io::Write] implementation.
+    pub fn new(wtr: W) -> Result<Self> {
+        Self::new_with_config(wtr, MkvConfig::default())
+    }
+
+    /// Create a new [MkvWriter] which encodes and writes with the given
+    /// options.
+    pub fn new_with_config(wtr: W, config: MkvConfig) -> Result<Self> {
+        if config.timestamp_scale == 0 {
+            return Err(Error::InvalidConfiguration {
+                msg: "Matroska timestamp scale is zero",
+                #[cfg(feature = "backtrace")]
+                backtrace: std::backtrace::Backtrace::capture(),
+            });
+        }
+        Ok(Self {
+            wtr,
+            config,
+            encoder: None,
+            header: None,
+            position: 0,
+            first_timestamp: Duration::ZERO,
+            cluster: None,
+            cues: Vec::new(),
+            last_timestamp: 0,
+            last_duration: 1,
+            buf: Vec::new(),
+            frame_data: Vec::new(),
+        })
+    }
+
+    /// Encode and write a frame with the given time stamp
+    ///
+    /// Time stamps are relative to an arbitrary origin and must increase from
+    /// frame to frame, even after rounding to the timestamp scale. The first
+    /// frame is placed at time zero.
+    pub fn write(&mut self, frame: &YCbCrImage, timestamp: Duration) -> Result<()> {
+        self.frame_data.clear();
+        let (timestamp, is_keyframe) = if let Some(encoder) = &mut self.encoder {
+            let since_first = timestamp.saturating_sub(self.first_timestamp);
+            let ticks = (since_first.as_nanos() / u128::from(self.config.timestamp_scale))
+                .try_into()
+                .unwrap_or(u64::MAX);
+            if timestamp < self.first_timestamp || ticks <= self.last_timestamp {
+                return Err(Error::DataShapeProblem {
+                    msg: "frame time stamp not after previous frame",
+                    #[cfg(feature = "backtrace")]
+                    backtrace: std::backtrace::Backtrace::capture(),
+                });
+            }
+            let is_keyframe =
+                encoder.encode_into(frame, NalFraming::LengthPrefixed, &mut self.frame_data)?;
+            self.last_duration = ticks - self.last_timestamp;
+            (ticks, is_keyframe)
+        } else {
+            let (initial_nal_data, encoder) =
+                LessEncoder::new_with_config(frame, self.config.encoder.clone())?;
+            self.write_header(frame, &initial_nal_data)?;
+            for nal_unit in initial_nal_data.frame.nal_units.iter() {
+                self.frame_data.extend(nal_unit.to_avcc_data(4)?);
+            }
+            self.encoder = Some(encoder);
+            self.first_timestamp = timestamp;
+            (0, true)
+        };
+        self.last_timestamp = timestamp;
+
+        // Keyframes start a cluster. Block time stamps are signed 16 bit
+        // offsets from the cluster, so long gaps also start one.
+        let relative = self
+            .cluster
+            .as_ref()
+            .map(|cluster| timestamp - cluster.timestamp)
+            .filter(|&relative| !is_keyframe && relative <= i16::MAX as u64);
+        let relative = match relative {
+            Some(relative) => relative as i16,
+            None => {
+                self.start_cluster(timestamp, is_keyframe)?;
+                0
+            }
+        };
+
+        let buf = &mut self.buf;
+        buf.clear();
+        put_id(buf, SIMPLE_BLOCK);
+        put_size(buf, 4 + self.frame_data.len() as u64);
+        // track number 1 as a variable length integer
+        buf.push(0x81);
+        buf.extend(relative.to_be_bytes());
+        buf.push(if is_keyframe { 0x80 } else { 0 });
+        self.write_buf()?;
+        self.wtr.write_all(&self.frame_data)?;
+        self.position += self.frame_data.len() as u64;
+        Ok(())
+    }
+
+    /// Complete the file and return the underlying [std::io::Write]
+    /// implementation.
+    ///
+    /// This writes the cues and fills in the sizes and the duration. The last
+    /// frame is given the duration of the frame before it, or one tick if it
+    /// is the only frame.
+    pub fn finish(mut self) -> Result<W> {
+        let Some(header) = self.header.take() else {
+            self.wtr.flush()?;
+            return Ok(self.wtr);
+        };
+        self.end_cluster()?;
+
+        let cues_position = self.position - header.segment_data_pos;
+        let buf = &mut self.buf;
+        buf.clear();
+        let cues = begin_element(buf, CUES);
+        for cue in self.cues.iter() {
+            let cue_point = begin_element(buf, CUE_POINT);
+            put_uint(buf, CUE_TIME, cue.timestamp);
+            let positions = begin_element(buf, CUE_TRACK_POSITIONS);
+            put_uint(buf, CUE_TRACK, 1);
+            put_uint(buf, CUE_CLUSTER_POSITION, cue.cluster_position);
+            end_element(buf, positions);
+            end_element(buf, cue_point);
+        }
+        end_element(buf, cues);
+        self.write_buf()?;
+
+        let duration = (self.last_timestamp + self.last_duration) as f64;
+        self.patch(header.cues_seek_pos, &cues_position.to_be_bytes())?;
+        self.patch(header.duration_pos, &duration.to_be_bytes())?;
+        let segment_size = self.position - header.segment_data_pos;
+        self.patch(header.segment_data_pos - 8, &size_8_bytes(segment_size))?;
+        self.wtr.flush()?;
+        Ok(self.wtr)
+    }
+
+    /// Write the EBML header and the start of the segment with its seek head,
+    /// info and tracks.
+    fn write_header(
+        &mut self,
+        frame: &YCbCrImage,
+        initial_nal_data: &InitialNalUnits,
+    ) -> Result<()> {
+        let codec_private = initial_nal_data.avc_decoder_configuration_record(4)?;
+        self.position = self.wtr.stream_position()?;
+        let buf = &mut self.buf;
+        buf.clear();
+
+        let ebml = begin_element(buf, EBML);
+        put_uint(buf, EBML_VERSION, 1);
+        put_uint(buf, EBML_READ_VERSION, 1);
+        put_uint(buf, EBML_MAX_ID_LENGTH, 4);
+        put_uint(buf, EBML_MAX_SIZE_LENGTH, 8);
+        put_element(buf, DOC_TYPE, b"matroska");
+        put_uint(buf, DOC_TYPE_VERSION, 4);
+        put_uint(buf, DOC_TYPE_READ_VERSION, 2);
+        end_element(buf, ebml);
+
+        // The segment size is filled in by `finish`.
+        put_id(buf, SEGMENT);
+        buf.extend(UNKNOWN_SIZE);
+        let segment_data = buf.len();
+
+        let seek_head = begin_element(buf, SEEK_HEAD);
+        let mut seek_positions = [0; 3];
+        for (id, seek_position) in [INFO, TRACKS, CUES].into_iter().zip(&mut seek_positions) {
+            let seek = begin_element(buf, SEEK);
+            put_element(buf, SEEK_ID, &id.to_be_bytes());
+            // Positions are written with 8 bytes to be filled in later.
+            put_element(buf, SEEK_POSITION, &[0; 8]);
+            *seek_position = buf.len() - 8;
+            end_element(buf, seek);
+        }
+        end_element(buf, seek_head);
+
+        let info = begin_element(buf, INFO);
+        put_uint(buf, TIMESTAMP_SCALE, self.config.timestamp_scale.into());
+        put_element(buf, DURATION, &0f64.to_be_bytes());
+        let duration = buf.len() - 8;
+        put_element(buf, MUXING_APP, b"less-avc");
+        put_element(buf, WRITING_APP, b"less-avc");
+        end_element(buf, info);
+
+        let tracks = begin_element(buf, TRACKS);
+        let track_entry = begin_element(buf, TRACK_ENTRY);
+        put_uint(buf, TRACK_NUMBER, 1);
+        put_uint(buf, TRACK_UID, 1);
+        // video
+        put_uint(buf, TRACK_TYPE, 1);
+        put_uint(buf, FLAG_LACING, 0);
+        put_element(buf, CODEC_ID, b"V_MPEG4/ISO/AVC");
+        put_element(buf, CODEC_PRIVATE, &codec_private);
+        let video = begin_element(buf, VIDEO);
+        put_uint(buf, PIXEL_WIDTH, frame.width.into());
+        put_uint(buf, PIXEL_HEIGHT, frame.height.into());
+        end_element(buf, video);
+        end_element(buf, track_entry);
+        end_element(buf, tracks);
+
+        for (seek_position, element) in seek_positions[..2].iter().zip([info, tracks]) {
+            let relative = (element - segment_data) as u64;
+            buf[*seek_position..*seek_position + 8].copy_from_slice(&relative.to_be_bytes());
+        }
+
+        let start = self.position;
+        self.header = Some(Header {
+            segment_data_pos: start + segment_data as u64,
+            cues_seek_pos: start + seek_positions[2] as u64,
+            duration_pos: start + duration as u64,
+        });
+        self.write_buf()
+    }
+
+    /// Complete the current cluster and start a new one at `timestamp`.
+    fn start_cluster(&mut self, timestamp: u64, is_keyframe: bool) -> Result<()> {
+        self.end_cluster()?;
+        let segment_data_pos = self.header.as_ref().unwrap().segment_data_pos;
+        if is_keyframe {
+            self.cues.push(CuePoint {
+                timestamp,
+                cluster_position: self.position - segment_data_pos,
+            });
+        }
+        let buf = &mut self.buf;
+        buf.clear();
+        put_id(buf, CLUSTER);
+        let size_pos = self.position + buf.len() as u64;
+        buf.extend(UNKNOWN_SIZE);
+        put_uint(buf, TIMESTAMP, timestamp);
+        self.write_buf()?;
+        self.cluster = Some(Cluster {
+            size_pos,
+            timestamp,
+        });
+        Ok(())
+    }
+
+    /// Fill in the size of the current cluster, if any.
+    fn end_cluster(&mut self) -> Result<()> {
+        if let Some(cluster) = self.cluster.take() {
+            let size = self.position - (cluster.size_pos + 8);
+            self.patch(cluster.size_pos, &size_8_bytes(size))?;
+        }
+        Ok(())
+    }
+
+    /// Write the contents of `buf`.
+    fn write_buf(&mut self) -> Result<()> {
+        self.wtr.write_all(&self.buf)?;
+        self.position += self.buf.len() as u64;
+        Ok(())
+    }
+
+    /// Overwrite previously written data at `pos` and return to the end.
+    fn patch(&mut self, pos: u64, data: &[u8]) -> Result<()> {
+        self.wtr.seek(SeekFrom::Start(pos))?;
+        self.wtr.write_all(data)?;
+        self.wtr.seek(SeekFrom::Start(self.position))?;
+        Ok(())
+    }
+}
+
+/// Write an element ID, which includes its length marker.
+fn put_id(buf: &mut Vec<u8>, id: u32) {
+    let bytes = id.to_be_bytes();
+    let skip = (id.leading_zeros() / 8) as usize;
+    buf.extend(&bytes[skip..]);
+}
+
+/// Write an element data size as a variable length integer of minimal length.
+fn put_size(buf: &mut Vec<u8>, size: u64) {
+    // Sizes with all value bits set are reserved for "unknown".
+    let len = (1..8).find(|&len| size < (1 << (7 * len)) - 1).unwrap_or(8);
+    let marked = size | 1 << (7 * len);
+    buf.extend(&marked.to_be_bytes()[8 - len..]);
+}
+
+/// Encode an element data size in 8 bytes.
+fn size_8_bytes(size: u64) -> [u8; 8] {
+    (size | 1 << 56).to_be_bytes()
+}
+
+fn put_element(buf: &mut Vec<u8>, id: u32, data: &[u8]) {
+    put_id(buf, id);
+    put_size(buf, data.len() as u64);
+    buf.extend(data);
+}
+
+/// Write an unsigned integer element with the minimal number of bytes.
+fn put_uint(buf: &mut Vec<u8>, id: u32, value: u64) {
+    let bytes = value.to_be_bytes();
+    let skip = ((value.leading_zeros() / 8) as usize).min(7);
+    put_element(buf, id, &bytes[skip..]);
+}
+
+/// Start a master element, returning its position for [end_element].
+fn begin_element(buf: &mut Vec<u8>, id: u32) -> usize {
+    let start = buf.len();
+    put_id(buf, id);
+    buf.extend(UNKNOWN_SIZE);
+    start
+}
+
+/// Fill in the size of the master element started at `start`.
+fn end_element(buf: &mut [u8], start: usize) {
+    // The length of an ID is given by its leading zero bits.
+    let id_len = buf[start].leading_zeros() as usize + 1;
+    let size_pos = start + id_len;
+    let size = (buf.len() - size_pos - 8) as u64;
+    buf[size_pos..size_pos + 8].copy_from_slice(&size_8_bytes(size));
+}
+
+#[test]
+fn test_put_size() {
+    let mut buf = Vec::new();
+    for (size, expected) in [
+        (0, &[0x80][..]),
+        (126, &[0xfe]),
+        (127, &[0x40, 0x7f]),
+        (16382, &[0x7f, 0xfe]),
+        (16383, &[0x20, 0x3f, 0xff]),
+    ] {
+        buf.clear();
+        put_size(&mut buf, size);
+        assert_eq!(buf, expected);
+    }
+    assert_eq!(size_8_bytes(5), [1, 0, 0, 0, 0, 0, 0, 5]);
+}
@@ -0,0 +1,418 @@
+// Copyright 2022-2023 Andrew D. Straw.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT
+// or http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Writes encoded frames to a fragmented MP4 (ISO base media) file.
+
+use std::{io::Write, num::NonZeroU32, time::Duration};
+
+use super::{
+    nal_unit::{InitialNalUnits, NalFraming},
+    EncoderConfig, Error, LessEncoder, Result, YCbCrImage,
+};
+
+/// Options for [Mp4Writer].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mp4Config {
+    /// Options for the encoder.
+    pub encoder: EncoderConfig,
+    /// The number of time units per second of the track.
+    ///
+    /// Frame time stamps are rounded to this resolution. The default is
+    /// 90000.
+    pub timescale: u32,
+    /// The number of frames in each movie fragment.
+    ///
+    /// The default of 1 writes each frame as soon as the time stamp of the
+    /// next frame gives its duration.
+    pub frames_per_fragment: NonZeroU32,
+}
+
+impl Default for Mp4Config {
+    fn default() -> Self {
+        Self {
+            encoder: EncoderConfig::default(),
+            timescale: 90_000,
+            frames_per_fragment: NonZeroU32::MIN,
+        }
+    }
+}
+
+/// A frame waiting to be written in a fragment.
+struct Sample {
+    /// The decode (and presentation) time in track time units.
+    decode_time: u64,
+    size: u32,
+    is_sync: bool,
+}
+
+/// Write images to an [std::io::Write] implementation in fragmented MP4
+/// format.
+///
+/// The file starts with an `ftyp` box and a `moov` box holding a single video
+/// track whose `avcC` box contains the parameter sets. Frames are stored as
+/// length-prefixed samples in pairs of `moof` and `mdat` boxes, so everything
+/// written before an interruption remains playable. IDR pictures are marked
+/// as sync samples.
+///
+/// As the duration of a frame is only known once the next frame arrives, the
+/// last frames are held back until [Self::finish] is called.
+pub struct Mp4Writer<W> {
+    wtr: W,
+    config: Mp4Config,
+    encoder: Option<LessEncoder>,
+    first_timestamp: Duration,
+    /// Frames not yet written, with their data in `sample_data`.
+    samples: Vec<Sample>,
+    sample_data: Vec<u8>,
+    sequence_number: u32,
+    /// The duration of the last frame written, in track time units.
+    last_duration: u64,
+    /// Buffer for boxes, reused across fragments.
+    buf: Vec<u8>,
+}
+
+impl<W: Write> Mp4Writer<W> {
+    /// Create a new [Mp4Writer] from an [std::io::Write] implementation.
+    pub fn new(wtr: W) -> Result<Self> {
+        Self::new_with_config(wtr, Mp4Config::default())
+    }
+
+    /// Create a new [Mp4Writer] which encodes and writes with the given
+    /// options.
+    pub fn new_with_config(wtr: W, config: Mp4Config) -> Result<Self> {
+        if config.timescale == 0 {
+            return Err(Error::InvalidConfiguration {
+                msg: "MP4 timescale is zero",
+                #[cfg(feature = "backtrace")]
+                backtrace: std::backtrace::Backtrace::capture(),
+            });
+        }
+        Ok(Self {
+            wtr,
+            config,
+            encoder: None,
+            first_timestamp: Duration::ZERO,
+            samples: Vec::new(),
+            sample_data: Vec::new(),
+            sequence_number: 0,
+            last_duration: 1,
+            buf: Vec::new(),
+        })
+    }
+
+    /// Encode and write a frame with the given time stamp
+    ///
+    /// Time stamps are relative to an arbitrary origin and must increase from
+    /// frame to frame, even after rounding to the timescale. The first frame
+    /// is placed at time zero.
+    pub fn write(&mut self, frame: &YCbCrImage, timestamp: Duration) -> Result<()> {
+        let decode_time = match &self.encoder {
+            Some(_) => {
+                let since_first = timestamp.saturating_sub(self.first_timestamp);
+                let decode_time = (since_first.as_nanos() * u128::from(self.config.timescale)
+                    / 1_000_000_000)
+                    .try_into()
+                    .unwrap_or(u64::MAX);
+                let previous = self.samples.last().map_or(0, |s| s.decode_time);
+                if timestamp < self.first_timestamp
+                    || (!self.samples.is_empty() && decode_time <= previous)
+                {
+                    return Err(Error::DataShapeProblem {
+                        msg: "frame time stamp not after previous frame",
+                        #[cfg(feature = "backtrace")]
+                        backtrace: std::backtrace::Backtrace::capture(),
+                    });
+                }
+                decode_time
+            }
+            None => 0,
+        };
+
+        let start = self.sample_data.len();
+        let is_sync = if let Some(encoder) = &mut self.encoder {
+            encoder.encode_into(frame, NalFraming::LengthPrefixed, &mut self.sample_data)?
+        } else {
+            let (initial_nal_data, encoder) =
+                LessEncoder::new_with_config(frame, self.config.encoder.clone())?;
+            self.write_header(frame, &initial_nal_data)?;
+            for nal_unit in initial_nal_data.frame.nal_units.iter() {
+                self.sample_data.extend(nal_unit.to_avcc_data(4)?);
+            }
+            self.encoder = Some(encoder);
+            self.first_timestamp = timestamp;
+            true
+        };
+        self.samples.push(Sample {
+            decode_time,
+            // Frames are at most `LessEncoder::max_frame_size()` bytes.
+            size: (self.sample_data.len() - start) as u32,
+            is_sync,
+        });
+
+        let frames_per_fragment = self.config.frames_per_fragment.get() as usize;
+        if self.samples.len() > frames_per_fragment {
+            self.write_fragment(frames_per_fragment)?;
+        }
+        Ok(())
+    }
+
+    /// Write all remaining frames and return the underlying
+    /// [std::io::Write] implementation.
+    ///
+    /// The last frame is given the duration of the frame before it, or one
+    /// time unit if it is the only frame.
+    pub fn finish(mut self) -> Result<W> {
+        if !self.samples.is_empty() {
+            self.write_fragment(self.samples.len())?;
+        }
+        self.wtr.flush()?;
+        Ok(self.wtr)
+    }
+
+    /// Write the `ftyp` and `moov` boxes.
+    fn write_header(
+        &mut self,
+        frame: &YCbCrImage,
+        initial_nal_data: &InitialNalUnits,
+    ) -> Result<()> {
+        let (Ok(width), Ok(height)) = (u16::try_from(frame.width), u16::try_from(frame.height))
+        else {
+            return Err(Error::UnsupportedImageSize {
+                #[cfg(feature = "backtrace")]
+                backtrace: std::backtrace::Backtrace::capture(),
+            });
+        };
+        let timescale = self.config.timescale;
+        let avcc = initial_nal_data.avc_decoder_configuration_record(4)?;
+        let buf = &mut self.buf;
+        buf.clear();
+
+        let ftyp = begin_box(buf, b"ftyp");
+        buf.extend(b"isom");
+        buf.extend(0x200u32.to_be_bytes());
+        buf.extend(b"isomiso6avc1mp41");
+        end_box(buf, ftyp);
+
+        let moov = begin_box(buf, b"moov");
+        let mvhd = begin_full_box(buf, b"mvhd", 0, 0);
+        // creation and modification time
+        buf.extend([0; 8]);
+        buf.extend(timescale.to_be_bytes());
+        // duration, unknown for fragmented files
+        buf.extend(0u32.to_be_bytes());
+        // rate 1.0, volume 1.0 and reserved
+        buf.extend(0x0001_0000u32.to_be_bytes());
+        buf.extend(0x0100u16.to_be_bytes());
+        buf.extend([0; 10]);
+        put_unity_matrix(buf);
+        // pre_defined
+        buf.extend([0; 24]);
+        // next_track_ID
+        buf.extend(2u32.to_be_bytes());
+        end_box(buf, mvhd);
+
+        let trak = begin_box(buf, b"trak");
+        // track_enabled | track_in_movie
+        let tkhd = begin_full_box(buf, b"tkhd", 0, 0x3);
+        // creation and modification time
+        buf.extend([0; 8]);
+        // track_ID, reserved and duration
+        buf.extend(1u32.to_be_bytes());
+        buf.extend([0; 8]);
+        // reserved, layer, alternate_group, volume and reserved
+        buf.extend([0; 16]);
+        put_unity_matrix(buf);
+        buf.extend((u32::from(width) << 16).to_be_bytes());
+        buf.extend((u32::from(height) << 16).to_be_bytes());
+        end_box(buf, tkhd);
+
+        let mdia = begin_box(buf, b"mdia");
+        let mdhd = begin_full_box(buf, b"mdhd", 0, 0);
+        buf.extend([0; 8]);
+        buf.extend(timescale.to_be_bytes());
+        buf.extend(0u32.to_be_bytes());
+        // language "und" and pre_defined
+        buf.extend(0x55c4u16.to_be_bytes());
+        buf.extend([0; 2]);
+        end_box(buf, mdhd);
+        let hdlr = begin_full_box(buf, b"hdlr", 0, 0);
+        buf.extend([0; 4]);
+        buf.extend(b"vide");
+        buf.extend([0; 12]);
+        buf.extend(b"VideoHandler\0");
+        end_box(buf, hdlr);
+
+        let minf = begin_box(buf, b"minf");
+        let vmhd = begin_full_box(buf, b"vmhd", 0, 0x1);
+        // graphicsmode and opcolor
+        buf.extend([0; 8]);
+        end_box(buf, vmhd);
+        let dinf = begin_box(buf, b"dinf");
+        let dref = begin_full_box(buf, b"dref", 0, 0);
+        buf.extend(1u32.to_be_bytes());
+        // The media data is in the same file.
+        let url = begin_full_box(buf, b"url ", 0, 0x1);
+        end_box(buf, url);
+        end_box(buf, dref);
+        end_box(buf, dinf);
+
+        let stbl = begin_box(buf, b"stbl");
+        let stsd = begin_full_box(buf, b"stsd", 0, 0);
+        buf.extend(1u32.to_be_bytes());
+        let avc1 = begin_box(buf, b"avc1");
+        // reserved and data_reference_index
+        buf.extend([0; 6]);
+        buf.extend(1u16.to_be_bytes());
+        // pre_defined and reserved
+        buf.extend([0; 16]);
+        buf.extend(width.to_be_bytes());
+        buf.extend(height.to_be_bytes());
+        // 72 dpi horizontal and vertical resolution
+        buf.extend(0x0048_0000u32.to_be_bytes());
+        buf.extend(0x0048_0000u32.to_be_bytes());
+        // reserved and frame_count
+        buf.extend([0; 4]);
+        buf.extend(1u16.to_be_bytes());
+        let mut compressorname = [0u8; 32];
+        let name = b"less-avc";
+        compressorname[0] = name.len() as u8;
+        compressorname[1..=name.len()].copy_from_slice(name);
+        buf.extend(compressorname);
+        // depth and pre_defined
+        buf.extend(0x0018u16.to_be_bytes());
+        buf.extend((-1i16).to_be_bytes());
+        let avcc_box = begin_box(buf, b"avcC");
+        buf.extend(avcc);
+        end_box(buf, avcc_box);
+        end_box(buf, avc1);
+        end_box(buf, stsd);
+        // The samples are described in the movie fragments.
+        for box_type in [b"stts", b"stsc", b"stco"] {
+            let empty = begin_full_box(buf, box_type, 0, 0);
+            buf.extend(0u32.to_be_bytes());
+            end_box(buf, empty);
+        }
+        let stsz = begin_full_box(buf, b"stsz", 0, 0);
+        buf.extend([0; 8]);
+        end_box(buf, stsz);
+        end_box(buf, stbl);
+        end_box(buf, minf);
+        end_box(buf, mdia);
+        end_box(buf, trak);
+
+        let mvex = begin_box(buf, b"mvex");
+        let trex = begin_full_box(buf, b"trex", 0, 0);
+        // track_ID and default_sample_description_index
+        buf.extend(1u32.to_be_bytes());
+        buf.extend(1u32.to_be_bytes());
+        // default sample duration, size and flags
+        buf.extend([0; 12]);
+        end_box(buf, trex);
+        end_box(buf, mvex);
+        end_box(buf, moov);
+
+        self.wtr.write_all(buf)?;
+        Ok(())
+    }
+
+    /// Write the first `n_samples` samples as a movie fragment.
+    fn write_fragment(&mut self, n_samples: usize) -> Result<()> {
+        let samples = &self.samples;
+        let data_len: usize = samples[..n_samples].iter().map(|s| s.size as usize).sum();
+        let Ok(mdat_size) = u32::try_from(data_len + 8) else {
+            return Err(Error::InvalidConfiguration {
+                msg: "MP4 fragment larger than 4 GiB",
+                #[cfg(feature = "backtrace")]
+                backtrace: std::backtrace::Backtrace::capture(),
+            });
+        };
+        self.sequence_number += 1;
+        let buf = &mut self.buf;
+        buf.clear();
+
+        let moof = begin_box(buf, b"moof");
+        let mfhd = begin_full_box(buf, b"mfhd", 0, 0);
+        buf.extend(self.sequence_number.to_be_bytes());
+        end_box(buf, mfhd);
+        let traf = begin_box(buf, b"traf");
+        // default-base-is-moof
+        let tfhd = begin_full_box(buf, b"tfhd", 0, 0x02_0000);
+        buf.extend(1u32.to_be_bytes());
+        end_box(buf, tfhd);
+        let tfdt = begin_full_box(buf, b"tfdt", 1, 0);
+        buf.extend(samples[0].decode_time.to_be_bytes());
+        end_box(buf, tfdt);
+        // data-offset, sample-duration, sample-size and sample-flags present
+        let trun = begin_full_box(buf, b"trun", 0, 0x00_0701);
+        buf.extend((n_samples as u32).to_be_bytes());
+        let data_offset_pos = buf.len();
+        buf.extend([0; 4]);
+        for (i, sample) in samples[..n_samples].iter().enumerate() {
+            if let Some(next) = samples.get(i + 1) {
+                self.last_duration = next.decode_time - sample.decode_time;
+            }
+            let Ok(duration) = u32::try_from(self.last_duration) else {
+                return Err(Error::DataShapeProblem {
+                    msg: "frame duration too long for MP4 timescale",
+                    #[cfg(feature = "backtrace")]
+                    backtrace: std::backtrace::Backtrace::capture(),
+                });
+            };
+            buf.extend(duration.to_be_bytes());
+            buf.extend(sample.size.to_be_bytes());
+            let flags: u32 = if sample.is_sync {
+                // sample_depends_on = 2 (does not depend on others)
+                0x0200_0000
+            } else {
+                // sample_is_non_sync_sample
+                0x0001_0000
+            };
+            buf.extend(flags.to_be_bytes());
+        }
+        end_box(buf, trun);
+        end_box(buf, traf);
+        end_box(buf, moof);
+        // The sample data follows the 8 byte `mdat` header.
+        let data_offset = (buf.len() - moof + 8) as u32;
+        buf[data_offset_pos..data_offset_pos + 4].copy_from_slice(&data_offset.to_be_bytes());
+        buf.extend(mdat_size.to_be_bytes());
+        buf.extend(b"mdat");
+
+        self.wtr.write_all(buf)?;
+        self.wtr.write_all(&self.sample_data[..data_len])?;
+        self.sample_data.drain(..data_len);
+        self.samples.drain(..n_samples);
+        Ok(())
+    }
+}
+
+/// Start a box, returning its position for [end_box].
+fn begin_box(buf: &mut Vec<u8>, box_type: &[u8; 4]) -> usize {
+    let start = buf.len();
+    buf.extend([0; 4]);
+    buf.extend(box_type);
+    start
+}
+
+/// Start a full box with the given version and flags.
+fn begin_full_box(buf: &mut Vec<u8>, box_type: &[u8; 4], version: u8, flags: u32) -> usize {
+    let start = begin_box(buf, box_type);
+    buf.extend((u32::from(version) << 24 | flags).to_be_bytes());
+    start
+}
+
+/// Fill in the size of the box started at `start`.
+fn end_box(buf: &mut [u8], start: usize) {
+    let size = (buf.len() - start) as u32;
+    buf[start..start + 4].copy_from_slice(&size.to_be_bytes());
+}
+
+fn put_unity_matrix(buf: &mut Vec<u8>) {
+    for value in [0x0001_0000u32, 0, 0, 0, 0x0001_0000, 0, 0, 0, 0x4000_0000] {
+        buf.extend(value.to_be_bytes());
+    }
+}
@@ -0,0 +1,134 @@
+// Copyright 2022-2023 Andrew D. Straw.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT
+// or http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Writes one stream per camera for cameras triggered together.
+
+use std::io::Write;
+
+use super::{
+    sei::{SupplementalEnhancementInformation, UserDataUnregistered},
+    writer::sei_nal_units,
+    Error, FrameInfo, H264Writer, Result, YCbCrImage,
+};
+
+/// Statistics of one camera of a [MultiCameraWriter].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct CameraStats {
+    /// Number of frames written for this camera.
+    pub frames_written: u64,
+    /// Number of frame sets without a frame from this camera.
+    pub frames_missing: u64,
+    /// Number of frames from this camera which could not be written.
+    pub frames_failed: u64,
+}
+
+/// Writes frames from several synchronized cameras, one [H264Writer] per
+/// camera.
+///
+/// Frames captured at the same trigger are written together as a frame set.
+/// Each frame is preceded by a frame synchronization SEI message
+/// ([UserDataUnregistered::new_frame_sync]) with the number of the frame set
+/// and its time stamp, so frames can be matched across streams even when a
+/// camera missed a trigger. The time stamp is also recorded in the index
+/// sidecar of each writer, if one is set.
+pub struct MultiCameraWriter<W> {
+    writers: Vec<H264Writer<W>>,
+    stats: Vec<CameraStats>,
+    frame_set_count: u64,
+}
+
+impl<W: Write> MultiCameraWriter<W> {
+    /// Create a new [MultiCameraWriter] with one writer per camera.
+    pub fn new(writers: Vec<H264Writer<W>>) -> Self {
+        let stats = vec![CameraStats::default(); writers.len()];
+        Self {
+            writers,
+            stats,
+            frame_set_count: 0,
+        }
+    }
+
+    /// Write the frames captured at one trigger, with `None` for cameras
+    /// which did not deliver a frame.
+    ///
+    /// `frames` must have one entry per camera. The unit of `timestamp` is
+    /// chosen by the caller. Returns information about the frame written for
+    /// each camera. The frame set number is incremented even if writing
+    /// fails, so that numbers are never reused.
+    ///
+    /// A failure for one camera does not prevent writing the frames of the
+    /// others. Each failure is counted in [CameraStats::frames_failed], and the
+    /// first error is returned after the whole frame set has been attempted.
+    pub fn write_frame_set(
+        &mut self,
+        timestamp: u64,
+        frames: &[Option<YCbCrImage>],
+    ) -> Result<Vec<Option<FrameInfo>>> {
+        if frames.len() != self.writers.len() {
+            return Err(Error::DataShapeProblem {
+                msg: "number of frames differs from number of cameras",
+                #[cfg(feature = "backtrace")]
+                backtrace: std::backtrace::Backtrace::capture(),
+            });
+        }
+        let frame_set_number = self.frame_set_count;
+        self.frame_set_count += 1;
+        let sei = sei_nal_units(&[SupplementalEnhancementInformation::UserDataUnregistered(
+            UserDataUnregistered::new_frame_sync(frame_set_number, timestamp),
+        )]);
+
+        let mut infos = Vec::with_capacity(frames.len());
+        let mut error = None;
+        for ((wtr, stats), frame) in self
+            .writers
+            .iter_mut()
+            .zip(self.stats.iter_mut())
+            .zip(frames)
+        {
+            let info = match frame {
+                Some(frame) => match wtr.write_frame(frame, Some(timestamp), &sei) {
+                    Ok(info) => {
+                        stats.frames_written += 1;
+                        Some(info)
+                    }
+                    Err(e) => {
+                        stats.frames_failed += 1;
+                        error.get_or_insert(e);
+                        None
+                    }
+                },
+                None => {
+                    stats.frames_missing += 1;
+                    None
+                }
+            };
+            infos.push(info);
+        }
+        match error {
+            Some(e) => Err(e),
+            None => Ok(infos),
+        }
+    }
+
+    /// The number of frame sets written so far.
+    pub fn frame_set_count(&self) -> u64 {
+        self.frame_set_count
+    }
+
+    /// Get the statistics of each camera.
+    pub fn stats(&self) -> &[CameraStats] {
+        &self.stats
+    }
+
+    /// Retrieve the underlying [std::io::Write] implementation of each camera.
+    pub fn into_inner(self) -> Vec<W> {
+        self.writers
+            .into_iter()
+            .map(H264Writer::into_inner)
+            .collect()
+    }
+}
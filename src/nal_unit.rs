@@ -7,8 +7,15 @@
 
 //! Network Abstraction Layer (NAL) encoding
 
+#[cfg_attr(not(feature = "alloc"), allow(unused_imports))]
 use super::*;
+#[cfg(feature = "alloc")]
+use crate::bitstream::invalid_bitstream;
+#[cfg(feature = "alloc")]
+use crate::sink::EbspWriter;
+use crate::sink::NalBuffer;
 
+#[cfg(feature = "alloc")]
 /// Data to save a NAL unit
 ///
 /// The data is in the raw byte sequence payload (RBSP) representation and gets
@@ -19,6 +26,7 @@ pub struct NalUnit {
     rbsp_data: RbspData,
 }
 
+#[cfg(feature = "alloc")]
 impl NalUnit {
     /// Create new [NalUnit].
     pub fn new(ref_idc: NalRefIdc, unit_type: NalUnitType, rbsp_data: RbspData) -> Self {
@@ -29,10 +37,47 @@ impl NalUnit {
         }
     }
 
+    /// Parse a single "naked" NAL unit, as returned by [Self::to_nal_unit].
+    ///
+    /// `data` starts with the NAL unit header and has no start code or length
+    /// prefix. Emulation prevention bytes are removed. For NAL unit types
+    /// whose header has extension bytes, these are the first bytes of the
+    /// RBSP.
+    pub fn from_nal_unit(data: &[u8]) -> Result<Self> {
+        let (&nal_byte, ebsp) = data
+            .split_first()
+            .ok_or_else(|| invalid_bitstream("empty NAL unit"))?;
+        if nal_byte & 0x80 != 0 {
+            return Err(invalid_bitstream("forbidden_zero_bit is set"));
+        }
+        Ok(Self {
+            ref_idc: NalRefIdc::from_nal_ref_idc(nal_byte >> 5),
+            unit_type: NalUnitType::from_nal_unit_type(nal_byte),
+            rbsp_data: ebsp_to_rbsp(ebsp)?,
+        })
+    }
+
+    /// The `nal_ref_idc` field of the NAL unit header.
+    pub fn ref_idc(&self) -> NalRefIdc {
+        self.ref_idc
+    }
+
+    /// The `nal_unit_type` field of the NAL unit header.
+    pub fn unit_type(&self) -> NalUnitType {
+        self.unit_type
+    }
+
+    /// The raw byte sequence payload (RBSP), without the NAL unit header.
+    pub fn rbsp_data(&self) -> &RbspData {
+        &self.rbsp_data
+    }
+
+    fn nal_byte(&self) -> u8 {
+        nal_header_byte(&self.ref_idc, &self.unit_type)
+    }
+
     fn to_buf(&self, with_frame: bool) -> Vec<u8> {
-        #[allow(clippy::identity_op)]
-        // forbidden_zero_bit = 0
-        let nal_byte = 0x00 | (self.ref_idc.nal_ref_idc() << 5) | self.unit_type.nal_unit_type();
+        let nal_byte = self.nal_byte();
 
         let rbsp_buf = &self.rbsp_data.data;
         let rbsp_size = rbsp_buf.len();
@@ -63,8 +108,103 @@ impl NalUnit {
     pub fn to_annex_b_data(&self) -> Vec<u8> {
         self.to_buf(true)
     }
+
+    /// Return a single NAL unit preceded by its big-endian length in
+    /// `length_size` bytes.
+    ///
+    /// This is the "AVCC" format of MP4 and Matroska samples, described by an
+    /// AVCDecoderConfigurationRecord (see
+    /// [InitialNalUnits::avc_decoder_configuration_record]). `length_size`
+    /// must be 1, 2 or 4, and the NAL unit must fit, otherwise
+    /// [Error::InvalidConfiguration] is returned.
+    pub fn to_avcc_data(&self, length_size: u8) -> Result<Vec<u8>> {
+        let length_size = check_length_size(length_size)?;
+        let nal_unit = self.to_nal_unit();
+        let length = (nal_unit.len() as u64).to_be_bytes();
+        let (high, prefix) = length.split_at(8 - length_size);
+        if high.iter().any(|&byte| byte != 0) {
+            return Err(Error::InvalidConfiguration {
+                msg: "NAL unit too long for length size",
+                #[cfg(feature = "backtrace")]
+                backtrace: Backtrace::capture(),
+            });
+        }
+        let mut result = Vec::with_capacity(length_size + nal_unit.len());
+        result.extend_from_slice(prefix);
+        result.extend(nal_unit);
+        Ok(result)
+    }
+
+    /// Append the NAL unit with the given framing to `dest`.
+    ///
+    /// With [NalFraming::AnnexB], this appends the same bytes as
+    /// [Self::to_annex_b_data] but without an intermediate buffer.
+    pub fn write_into(&self, framing: NalFraming, dest: &mut Vec<u8>) {
+        dest.reserve(framing.prefix_len() + 1 + calc_max_nal_buf_size(self.rbsp_data.data.len()));
+        let start = begin_nal(framing, self.nal_byte(), dest);
+        EbspWriter::new(dest).put(&self.rbsp_data.data);
+        end_nal(framing, start, dest);
+    }
+
+    /// Put the NAL unit in Annex B format into `dest`.
+    pub(crate) fn put_annex_b<S: ByteSink>(&self, dest: &mut S) {
+        dest.put(&[0x00, 0x00, 0x00, 0x01, self.nal_byte()]);
+        EbspWriter::new(dest).put(&self.rbsp_data.data);
+    }
 }
 
+/// How NAL units are delimited when written one after another.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum NalFraming {
+    /// Each NAL unit is preceded by the start code `00 00 00 01`, as in `.h264`
+    /// files.
+    #[default]
+    AnnexB,
+    /// Each NAL unit is preceded by its size as a 4 byte big endian integer, as
+    /// in MP4 files with `lengthSizeMinusOne = 3`.
+    LengthPrefixed,
+}
+
+impl NalFraming {
+    fn prefix_len(&self) -> usize {
+        4
+    }
+}
+
+#[allow(clippy::identity_op)]
+pub(crate) fn nal_header_byte(ref_idc: &NalRefIdc, unit_type: &NalUnitType) -> u8 {
+    // forbidden_zero_bit = 0
+    0x00 | (ref_idc.nal_ref_idc() << 5) | unit_type.nal_unit_type()
+}
+
+/// Put the framing prefix and NAL unit header into `dest`.
+///
+/// Returns the start position of the NAL unit to pass to [end_nal] once the
+/// payload is written.
+pub(crate) fn begin_nal<S: NalBuffer>(framing: NalFraming, nal_byte: u8, dest: &mut S) -> usize {
+    let start = dest.position();
+    match framing {
+        NalFraming::AnnexB => dest.put(&[0x00, 0x00, 0x00, 0x01, nal_byte]),
+        // Filled in by `end_nal`.
+        NalFraming::LengthPrefixed => dest.put(&[0x00, 0x00, 0x00, 0x00, nal_byte]),
+    }
+    start
+}
+
+/// Complete the NAL unit started at `start` by [begin_nal].
+pub(crate) fn end_nal<S: NalBuffer>(framing: NalFraming, start: usize, dest: &mut S) {
+    match framing {
+        NalFraming::AnnexB => {}
+        NalFraming::LengthPrefixed => {
+            let nal = dest.bytes_since(start);
+            let prefix_len = framing.prefix_len();
+            let size: u32 = (nal.len() - prefix_len).try_into().unwrap();
+            nal[..prefix_len].copy_from_slice(&size.to_be_bytes());
+        }
+    }
+}
+
+#[cfg(feature = "alloc")]
 /// Calculate the maximum possible NAL buffer size for a given RBSP size.
 #[inline]
 fn calc_max_nal_buf_size(rbsp_size: usize) -> usize {
@@ -73,6 +213,7 @@ fn calc_max_nal_buf_size(rbsp_size: usize) -> usize {
         .unwrap()
 }
 
+#[cfg(feature = "alloc")]
 /// Convert Raw byte sequence payload (RBSP) data to Encapsulated Byte Sequence
 /// Payload (EBSP) bytes.
 pub(crate) fn rbsp_to_ebsp(rbsp_buf: &[u8], nal_buf: &mut [u8]) -> usize {
@@ -129,12 +270,57 @@ pub(crate) fn rbsp_to_ebsp(rbsp_buf: &[u8], nal_buf: &mut [u8]) -> usize {
     dest_len
 }
 
+#[cfg(feature = "alloc")]
 #[inline]
 /// Returns true if byte is 0x00, 0x01, 0x02 or 0x03.
 fn needs_protecting_in_pos3(byte: u8) -> bool {
     matches!(byte, 0x00..=0x03)
 }
 
+#[cfg(feature = "alloc")]
+/// Convert Encapsulated Byte Sequence Payload (EBSP) bytes to Raw byte sequence
+/// payload (RBSP) data by removing emulation prevention bytes.
+///
+/// This reverses the emulation prevention applied when encoding. `ebsp` is the
+/// NAL unit without start code or length prefix. Three-byte sequences which may
+/// not occur within a NAL unit result in [Error::InvalidBitstream].
+pub fn ebsp_to_rbsp(ebsp: &[u8]) -> Result<RbspData> {
+    let mut data = Vec::with_capacity(ebsp.len());
+    let mut input_buf = ebsp;
+
+    while let Some(first_idx) = memchr::memchr(0x00, input_buf) {
+        // use input up to and including null
+        data.extend_from_slice(&input_buf[..first_idx + 1]);
+        input_buf = &input_buf[first_idx + 1..];
+        if input_buf.first() != Some(&0x00) {
+            continue;
+        }
+        // two nulls in a row
+        data.push(0x00);
+        input_buf = &input_buf[1..];
+        match input_buf.first() {
+            Some(0x03) => {
+                // emulation_prevention_three_byte, which is discarded
+                input_buf = &input_buf[1..];
+                if let Some(next) = input_buf.first() {
+                    if !needs_protecting_in_pos3(*next) {
+                        return Err(invalid_bitstream(
+                            "emulation prevention byte followed by byte greater than 0x03",
+                        ));
+                    }
+                }
+            }
+            Some(0x00..=0x02) => {
+                return Err(invalid_bitstream("forbidden three-byte sequence"));
+            }
+            _ => {}
+        }
+    }
+    data.extend_from_slice(input_buf);
+
+    Ok(RbspData::new(data))
+}
+
 #[test]
 fn test_bad_byte() {
     assert!(needs_protecting_in_pos3(0x00));
@@ -203,9 +389,36 @@ fn test_nal_encoding_roundtrip() {
     }
 }
 
-/// Possible values for the `nal_ref_idc` field in the `nal_unit`.
+#[test]
+fn test_ebsp_writer() {
+    let inputs: [&[u8]; 6] = [
+        &[0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01],
+        &[0x00, 0x00, 0x03, 0x00, 0x00, 0x02, 0x00],
+        &[0x11, 0x00, 0x00, 0x04, 0x00, 0x00, 0x00],
+        &[0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x03, 0x00],
+        &[0x80, 0x00, 0x00],
+        &[0x0d, 0x00, 0x00, 0x00, 0x0d, 0x00, 0x00, 0x01],
+    ];
+    for input in inputs.iter() {
+        let mut expected = vec![0u8; calc_max_nal_buf_size(input.len())];
+        let sz = rbsp_to_ebsp(input, &mut expected);
+        expected.truncate(sz);
+
+        // The result must not depend on how the input is split.
+        for split in 0..=input.len() {
+            let mut actual = Vec::new();
+            let mut wtr = EbspWriter::new(&mut actual);
+            wtr.put(&input[..split]);
+            wtr.put(&input[split..]);
+            assert_eq!(actual, expected, "input {input:?} split at {split}");
+        }
+    }
+}
+
+/// Possible values for the `nal_ref_idc` field in `nal_unit`.
 ///
 /// Encodes to 2 bits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum NalRefIdc {
     // TODO: could these have better names?
     Zero,
@@ -215,7 +428,8 @@ pub enum NalRefIdc {
 }
 
 impl NalRefIdc {
-    pub(crate) fn nal_ref_idc(&self) -> u8 {
+    /// The value of the `nal_ref_idc` field.
+    pub fn nal_ref_idc(&self) -> u8 {
         match self {
             Self::Zero => 0,
             Self::One => 1,
@@ -223,13 +437,22 @@ impl NalRefIdc {
             Self::Three => 3,
         }
     }
+
+    /// Interpret the two least significant bits of `value`.
+    pub fn from_nal_ref_idc(value: u8) -> Self {
+        match value & 0x03 {
+            0 => Self::Zero,
+            1 => Self::One,
+            2 => Self::Two,
+            _ => Self::Three,
+        }
+    }
 }
 
 /// Possible values for the `nal_unit_type` field in `nal_unit`.
 ///
 /// Encodes to 5 bits.
-#[allow(dead_code)]
-#[derive(PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[non_exhaustive]
 pub enum NalUnitType {
     /// Unspecified
@@ -250,11 +473,45 @@ pub enum NalUnitType {
     SequenceParameterSet,
     /// Picture parameter set
     PictureParameterSet,
-    // There are more, which is why this is marked `non_exhaustive`.
+    /// Access unit delimiter
+    AccessUnitDelimiter,
+    /// End of sequence
+    EndOfSequence,
+    /// End of stream
+    EndOfStream,
+    /// Filler data
+    FillerData,
+    /// Sequence parameter set extension
+    SequenceParameterSetExtension,
+    /// Prefix NAL unit
+    PrefixNalUnit,
+    /// Subset sequence parameter set
+    SubsetSequenceParameterSet,
+    /// Coded slice of an auxiliary coded picture without partitioning
+    CodedSliceOfAnAuxiliaryCodedPicture,
+    /// Coded slice extension
+    CodedSliceExtension,
+    /// Any other value, which is reserved or unspecified.
+    Other(OtherNalUnitType),
+}
+
+/// A reserved or unspecified `nal_unit_type` value of [NalUnitType::Other].
+///
+/// This is only created by [NalUnitType::from_nal_unit_type], so it never
+/// holds the value of another [NalUnitType] variant.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OtherNalUnitType(u8);
+
+impl OtherNalUnitType {
+    /// The value of the `nal_unit_type` field.
+    pub fn value(&self) -> u8 {
+        self.0
+    }
 }
 
 impl NalUnitType {
-    pub(crate) fn nal_unit_type(&self) -> u8 {
+    /// The value of the `nal_unit_type` field.
+    pub fn nal_unit_type(&self) -> u8 {
         match self {
             Self::Unspecified => 0,
             Self::CodedSliceOfANonIDRPicture => 1,
@@ -265,24 +522,264 @@ impl NalUnitType {
             Self::SupplementalEnhancementInformation => 6,
             Self::SequenceParameterSet => 7,
             Self::PictureParameterSet => 8,
+            Self::AccessUnitDelimiter => 9,
+            Self::EndOfSequence => 10,
+            Self::EndOfStream => 11,
+            Self::FillerData => 12,
+            Self::SequenceParameterSetExtension => 13,
+            Self::PrefixNalUnit => 14,
+            Self::SubsetSequenceParameterSet => 15,
+            Self::CodedSliceOfAnAuxiliaryCodedPicture => 19,
+            Self::CodedSliceExtension => 20,
+            Self::Other(other) => other.0,
+        }
+    }
+
+    /// Interpret the five least significant bits of `value`.
+    pub fn from_nal_unit_type(value: u8) -> Self {
+        match value & 0x1f {
+            0 => Self::Unspecified,
+            1 => Self::CodedSliceOfANonIDRPicture,
+            2 => Self::CodedSliceDataPartitionA,
+            3 => Self::CodedSliceDataPartitionB,
+            4 => Self::CodedSliceDataPartitionC,
+            5 => Self::CodedSliceOfAnIDRPicture,
+            6 => Self::SupplementalEnhancementInformation,
+            7 => Self::SequenceParameterSet,
+            8 => Self::PictureParameterSet,
+            9 => Self::AccessUnitDelimiter,
+            10 => Self::EndOfSequence,
+            11 => Self::EndOfStream,
+            12 => Self::FillerData,
+            13 => Self::SequenceParameterSetExtension,
+            14 => Self::PrefixNalUnit,
+            15 => Self::SubsetSequenceParameterSet,
+            19 => Self::CodedSliceOfAnAuxiliaryCodedPicture,
+            20 => Self::CodedSliceExtension,
+            other => Self::Other(OtherNalUnitType(other)),
         }
     }
+
+    /// Whether this is a coded slice of a picture.
+    pub fn is_slice(&self) -> bool {
+        matches!(
+            self,
+            Self::CodedSliceOfANonIDRPicture
+                | Self::CodedSliceDataPartitionA
+                | Self::CodedSliceOfAnIDRPicture
+        )
+    }
 }
 
-/// The initial [NalUnit] returned when starting a [LessEncoder].
+#[test]
+fn test_nal_unit_type() {
+    for value in 0..32 {
+        assert_eq!(
+            NalUnitType::from_nal_unit_type(value).nal_unit_type(),
+            value
+        );
+    }
+    assert_eq!(
+        NalUnitType::from_nal_unit_type(7),
+        NalUnitType::SequenceParameterSet
+    );
+    assert_eq!(
+        NalUnitType::from_nal_unit_type(23),
+        NalUnitType::Other(OtherNalUnitType(23))
+    );
+}
+
+/// The [NalUnit]s encoding a single frame.
+#[cfg(feature = "alloc")]
+pub struct EncodedFrame {
+    /// The NAL units, in decoding order.
+    ///
+    /// The last is the coded slice. It may be preceded by supplemental
+    /// enhancement information (SEI).
+    pub nal_units: Vec<NalUnit>,
+    /// Whether the frame is an instantaneous decoding refresh (IDR) picture.
+    ///
+    /// IDR pictures are sync samples: decoding can start at such a frame.
+    pub is_idr: bool,
+}
+
+#[cfg(feature = "alloc")]
+impl EncodedFrame {
+    /// Return all NAL units encoded for direct saving to `.h264` file.
+    pub fn to_annex_b_data(&self) -> Vec<u8> {
+        #[cfg(feature = "rayon")]
+        {
+            use rayon::prelude::*;
+            let nal_units: Vec<Vec<u8>> = self
+                .nal_units
+                .par_iter()
+                .map(NalUnit::to_annex_b_data)
+                .collect();
+            nal_units.concat()
+        }
+        #[cfg(not(feature = "rayon"))]
+        {
+            let mut result = Vec::new();
+            for nal_unit in self.nal_units.iter() {
+                result.extend(nal_unit.to_annex_b_data());
+            }
+            result
+        }
+    }
+}
+
+/// The initial [NalUnit]s returned when starting a [LessEncoder].
+#[cfg(feature = "alloc")]
 pub struct InitialNalUnits {
     /// sequence parameter set NAL unit
     pub sps: NalUnit,
     /// picture parameter set NAL unit
     pub pps: NalUnit,
-    /// frame NAL unit
-    pub frame: NalUnit,
+    /// first frame
+    pub frame: EncodedFrame,
+}
+
+#[cfg(feature = "alloc")]
+impl InitialNalUnits {
+    /// Build the AVCDecoderConfigurationRecord for these parameter sets.
+    ///
+    /// This is the contents of the `avcC` box of MP4 files and the
+    /// `CodecPrivate` element of Matroska tracks. It specifies that NAL units
+    /// are preceded by their length in `length_size` bytes, as written by
+    /// [NalUnit::to_avcc_data]. For High profiles, the chroma format and bit
+    /// depths are included. `length_size` must be 1, 2 or 4.
+    pub fn avc_decoder_configuration_record(&self, length_size: u8) -> Result<Vec<u8>> {
+        let length_size = check_length_size(length_size)? as u8;
+        let sps_rbsp = &self.sps.rbsp_data.data;
+        let sps_data = self.sps.to_nal_unit();
+        let pps_data = self.pps.to_nal_unit();
+        if sps_rbsp.len() < 3 {
+            return Err(invalid_bitstream("sequence parameter set too short"));
+        }
+        let mut record = vec![
+            1, // configurationVersion
+            sps_rbsp[0],
+            sps_rbsp[1],
+            sps_rbsp[2],
+            0xfc | (length_size - 1),
+            0xe0 | 1, // numOfSequenceParameterSets
+        ];
+        // Parameter sets are far shorter than 64 KiB.
+        record.extend((sps_data.len() as u16).to_be_bytes());
+        record.extend(sps_data);
+        record.push(1); // numOfPictureParameterSets
+        record.extend((pps_data.len() as u16).to_be_bytes());
+        record.extend(pps_data);
+        if crate::decoder::HIGH_PROFILE_IDCS.contains(&sps_rbsp[0]) {
+            let format = crate::decoder::PictureFormat::from_sps(sps_rbsp)?;
+            let bit_depth_minus8 = format.bit_depth.num_bits() - 8;
+            record.extend([
+                0xfc | format.chroma_format_idc as u8,
+                0xf8 | bit_depth_minus8,
+                0xf8 | bit_depth_minus8,
+                0, // numOfSequenceParameterSetExt
+            ]);
+        }
+        Ok(record)
+    }
+}
+
+/// Check that `length_size` is a valid AVCC NAL unit length size.
+#[cfg(feature = "alloc")]
+fn check_length_size(length_size: u8) -> Result<usize> {
+    match length_size {
+        1 | 2 | 4 => Ok(length_size.into()),
+        _ => Err(Error::InvalidConfiguration {
+            msg: "NAL unit length size must be 1, 2 or 4",
+            #[cfg(feature = "backtrace")]
+            backtrace: Backtrace::capture(),
+        }),
+    }
 }
 
+#[cfg(feature = "alloc")]
 impl std::iter::IntoIterator for InitialNalUnits {
     type Item = NalUnit;
     type IntoIter = alloc::vec::IntoIter<Self::Item>;
     fn into_iter(self) -> Self::IntoIter {
-        vec![self.sps, self.pps, self.frame].into_iter()
+        let mut result = vec![self.sps, self.pps];
+        result.extend(self.frame.nal_units);
+        result.into_iter()
+    }
+}
+
+#[test]
+fn test_ebsp_to_rbsp() {
+    let decoded = ebsp_to_rbsp(&[0x68, 0x00, 0x00, 0x03, 0x00, 0x00, 0x03]).unwrap();
+    assert_eq!(decoded.data, [0x68, 0x00, 0x00, 0x00, 0x00]);
+    let decoded = ebsp_to_rbsp(&[0x00, 0x00, 0x03, 0x03, 0x00, 0x00]).unwrap();
+    assert_eq!(decoded.data, [0x00, 0x00, 0x03, 0x00, 0x00]);
+
+    for invalid in [
+        &[0x68, 0x00, 0x00, 0x00][..],
+        &[0x68, 0x00, 0x00, 0x01, 0x05],
+        &[0x00, 0x00, 0x02],
+        &[0x00, 0x00, 0x03, 0x04],
+        &[0x00, 0x00, 0x03, 0xff, 0x00],
+    ] {
+        assert!(matches!(
+            ebsp_to_rbsp(invalid),
+            Err(Error::InvalidBitstream { .. })
+        ));
+    }
+}
+
+#[test]
+fn test_to_avcc_data() {
+    let nal_unit = NalUnit::new(
+        NalRefIdc::Three,
+        NalUnitType::PictureParameterSet,
+        RbspData::new(vec![0x00, 0x00, 0x01]),
+    );
+    let ebsp = [0x68, 0x00, 0x00, 0x03, 0x01];
+    assert_eq!(
+        nal_unit.to_avcc_data(1).unwrap(),
+        [&[5][..], &ebsp].concat()
+    );
+    assert_eq!(
+        nal_unit.to_avcc_data(2).unwrap(),
+        [&[0, 5][..], &ebsp].concat()
+    );
+    assert_eq!(
+        nal_unit.to_avcc_data(4).unwrap(),
+        [&[0, 0, 0, 5][..], &ebsp].concat()
+    );
+    assert!(matches!(
+        nal_unit.to_avcc_data(3),
+        Err(Error::InvalidConfiguration { .. })
+    ));
+
+    let long = NalUnit::new(
+        NalRefIdc::Three,
+        NalUnitType::CodedSliceOfAnIDRPicture,
+        RbspData::new(vec![0xff; 255]),
+    );
+    assert!(matches!(
+        long.to_avcc_data(1),
+        Err(Error::InvalidConfiguration { .. })
+    ));
+    assert_eq!(long.to_avcc_data(2).unwrap().len(), 2 + 256);
+}
+
+#[cfg(test)]
+proptest::proptest! {
+    #[test]
+    fn prop_ebsp_round_trip(rbsp in proptest::collection::vec(
+        proptest::sample::select(&[0x00u8, 0x01, 0x02, 0x03, 0x04, 0x80, 0xff][..]),
+        0..64,
+    )) {
+        let mut ebsp = vec![0u8; calc_max_nal_buf_size(rbsp.len())];
+        let sz = rbsp_to_ebsp(&rbsp, &mut ebsp);
+        ebsp.truncate(sz);
+        proptest::prop_assert_eq!(ebsp_to_rbsp(&ebsp).unwrap().data, rbsp.clone());
+
+        let mut streamed = Vec::new();
+        EbspWriter::new(&mut streamed).put(&rbsp);
+        proptest::prop_assert_eq!(ebsp_to_rbsp(&streamed).unwrap().data, rbsp);
     }
 }
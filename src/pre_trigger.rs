@@ -0,0 +1,256 @@
+// Copyright 2022-2023 Andrew D. Straw.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT
+// or http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Keeps recent encoded frames in memory to record the time before an event.
+
+use std::{
+    collections::VecDeque,
+    io::Write,
+    num::NonZeroUsize,
+    time::{Duration, Instant},
+};
+
+use super::{
+    nal_unit::NalFraming,
+    writer::{Periodic, WriteInterval},
+    EncoderConfig, Error, LessEncoder, Result, YCbCrImage,
+};
+
+/// How many frames [PreTriggerRecorder] keeps before the trigger.
+///
+/// The oldest frames are discarded once any limit is exceeded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PreTriggerLimits {
+    /// The maximum memory in bytes used by the buffered frames.
+    ///
+    /// In addition, a spare buffer holds the frame being encoded. Each buffer
+    /// has room for the largest possible frame.
+    pub max_bytes: usize,
+    /// The maximum number of buffered frames.
+    pub max_frames: Option<NonZeroUsize>,
+    /// The maximum age of a buffered frame, measured from when it was written.
+    pub max_age: Option<Duration>,
+}
+
+/// Statistics of a [PreTriggerRecorder].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct PreTriggerStats {
+    /// Number of frames currently buffered.
+    pub buffered_frames: usize,
+    /// Number of bytes of encoded data currently buffered.
+    pub buffered_bytes: usize,
+    /// Number of bytes allocated for buffered frames, including a spare
+    /// buffer for the next frame.
+    pub memory_bytes: usize,
+    /// Number of frames discarded from the buffer without being written.
+    pub frames_discarded: u64,
+}
+
+/// An encoded frame waiting in the buffer.
+struct BufferedFrame {
+    data: Vec<u8>,
+    written: Instant,
+}
+
+/// The recording started by [PreTriggerRecorder::trigger].
+struct LiveRecording<W> {
+    wtr: W,
+    stop: Option<Periodic>,
+    /// Whether the parameter sets have been written, which is only not the
+    /// case if there was no frame before the trigger.
+    parameter_sets_written: bool,
+}
+
+/// Records frames around a trigger event.
+///
+/// Until [Self::trigger] is called, encoded frames are kept in a buffer limited
+/// by [PreTriggerLimits]. These frames are encoded as IDR pictures so that
+/// the oldest buffered frame can always start a stream. On trigger, the
+/// parameter sets and buffered frames are written, and subsequent frames are
+/// written as they arrive, following the group of pictures configuration,
+/// until the stop condition. The recorder then returns to buffering.
+pub struct PreTriggerRecorder<W> {
+    config: EncoderConfig,
+    limits: PreTriggerLimits,
+    /// The encoder, with the Annex B parameter sets, once the first frame is
+    /// written.
+    encoder: Option<(LessEncoder, Vec<u8>)>,
+    frames: VecDeque<BufferedFrame>,
+    buffered_bytes: usize,
+    memory_bytes: usize,
+    /// A buffer from a discarded frame, reused for the next one.
+    spare: Vec<u8>,
+    frames_discarded: u64,
+    live: Option<LiveRecording<W>>,
+}
+
+impl<W: Write> PreTriggerRecorder<W> {
+    /// Create a new [PreTriggerRecorder] which encodes with the given options.
+    pub fn new(config: EncoderConfig, limits: PreTriggerLimits) -> Self {
+        Self {
+            config,
+            limits,
+            encoder: None,
+            frames: VecDeque::new(),
+            buffered_bytes: 0,
+            memory_bytes: 0,
+            spare: Vec::new(),
+            frames_discarded: 0,
+            live: None,
+        }
+    }
+
+    /// Encode a frame and either buffer it or, after a trigger, write it.
+    ///
+    /// Returns the writer passed to [Self::trigger] when the recording stops
+    /// after this frame.
+    pub fn write(&mut self, frame: &YCbCrImage) -> Result<Option<W>> {
+        let mut buf = std::mem::take(&mut self.spare);
+        buf.clear();
+        let parameter_sets = match &mut self.encoder {
+            Some((encoder, parameter_sets)) => {
+                if self.live.is_none() {
+                    encoder.force_keyframe();
+                }
+                // Allocate exactly once per buffer, as buffers are kept
+                // around.
+                buf.reserve_exact(encoder.max_encoded_size());
+                encoder.encode_into(frame, NalFraming::AnnexB, &mut buf)?;
+                parameter_sets
+            }
+            encoder @ None => {
+                let (initial_nal_data, new_encoder) =
+                    LessEncoder::new_with_config(frame, self.config.clone())?;
+                let mut parameter_sets = initial_nal_data.sps.to_annex_b_data();
+                parameter_sets.extend(initial_nal_data.pps.to_annex_b_data());
+                buf.extend(initial_nal_data.frame.to_annex_b_data());
+                &mut encoder.insert((new_encoder, parameter_sets)).1
+            }
+        };
+
+        let Some(live) = self.live.as_mut() else {
+            self.push_frame(buf);
+            return Ok(None);
+        };
+        let result = if live.parameter_sets_written {
+            live.wtr.write_all(&buf)
+        } else {
+            live.parameter_sets_written = true;
+            live.wtr
+                .write_all(parameter_sets)
+                .and_then(|()| live.wtr.write_all(&buf))
+        };
+        self.spare = buf;
+        result?;
+        let stop = live.stop.as_mut().is_some_and(|stop| {
+            stop.add_frames(1);
+            stop.is_due()
+        });
+        Ok(if stop { self.stop() } else { None })
+    }
+
+    /// Write the buffered frames to `wtr` and record the following frames to
+    /// it until `post_trigger` has passed.
+    ///
+    /// The parameter sets are written first, followed by the buffered frames
+    /// from oldest to newest. With `None` for `post_trigger`, frames are
+    /// recorded until [Self::stop] is called. It is an error to trigger while
+    /// already recording.
+    pub fn trigger(&mut self, mut wtr: W, post_trigger: Option<WriteInterval>) -> Result<()> {
+        if self.live.is_some() {
+            return Err(Error::InvalidConfiguration {
+                msg: "recorder already triggered",
+                #[cfg(feature = "backtrace")]
+                backtrace: std::backtrace::Backtrace::capture(),
+            });
+        }
+        self.discard_old_frames(None);
+        if let Some((encoder, parameter_sets)) = &mut self.encoder {
+            wtr.write_all(parameter_sets)?;
+            for frame in self.frames.iter() {
+                wtr.write_all(&frame.data)?;
+            }
+            if self.frames.is_empty() {
+                // All buffered frames were discarded, so the recording must
+                // start with an IDR picture.
+                encoder.force_keyframe();
+            }
+        }
+        while let Some(frame) = self.frames.pop_front() {
+            self.memory_bytes -= frame.data.capacity();
+            self.spare = frame.data;
+        }
+        self.buffered_bytes = 0;
+        self.live = Some(LiveRecording {
+            wtr,
+            stop: post_trigger.map(Periodic::new),
+            parameter_sets_written: self.encoder.is_some(),
+        });
+        Ok(())
+    }
+
+    /// Stop recording and return the writer, if recording.
+    pub fn stop(&mut self) -> Option<W> {
+        self.live.take().map(|live| live.wtr)
+    }
+
+    /// Whether frames are being recorded after a trigger.
+    pub fn is_recording(&self) -> bool {
+        self.live.is_some()
+    }
+
+    /// Get the current statistics.
+    pub fn stats(&self) -> PreTriggerStats {
+        PreTriggerStats {
+            buffered_frames: self.frames.len(),
+            buffered_bytes: self.buffered_bytes,
+            memory_bytes: self.memory_bytes + self.spare.capacity(),
+            frames_discarded: self.frames_discarded,
+        }
+    }
+
+    fn push_frame(&mut self, data: Vec<u8>) {
+        let new_bytes = data.capacity();
+        self.discard_old_frames(Some(new_bytes));
+        if new_bytes > self.limits.max_bytes {
+            self.frames_discarded += 1;
+            self.spare = data;
+            return;
+        }
+        self.buffered_bytes += data.len();
+        self.memory_bytes += new_bytes;
+        self.frames.push_back(BufferedFrame {
+            data,
+            written: Instant::now(),
+        });
+    }
+
+    /// Discard frames older than the age limit and, if a frame of `new_bytes`
+    /// is to be added, the oldest frames until it fits within the limits.
+    fn discard_old_frames(&mut self, new_bytes: Option<usize>) {
+        let limits = self.limits;
+        while let Some(oldest) = self.frames.front() {
+            let too_many = limits
+                .max_frames
+                .is_some_and(|max| new_bytes.is_some() && self.frames.len() >= max.get());
+            let too_large = self.memory_bytes + new_bytes.unwrap_or(0) > limits.max_bytes;
+            let too_old = limits
+                .max_age
+                .is_some_and(|max| oldest.written.elapsed() > max);
+            if !(too_many || too_large || too_old) {
+                break;
+            }
+            let frame = self.frames.pop_front().unwrap();
+            self.buffered_bytes -= frame.data.len();
+            self.memory_bytes -= frame.data.capacity();
+            self.frames_discarded += 1;
+            if frame.data.capacity() > self.spare.capacity() {
+                self.spare = frame.data;
+            }
+        }
+    }
+}
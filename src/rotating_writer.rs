@@ -0,0 +1,241 @@
+// Copyright 2022-2023 Andrew D. Straw.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT
+// or http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Splits a recording into several `.h264` files.
+
+use std::{
+    fs::File,
+    num::NonZeroU64,
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+use super::{EncoderConfig, Error, FrameInfo, H264Writer, Result, YCbCrImage};
+
+/// When [RotatingH264Writer] starts a new segment.
+///
+/// A new segment is started before a frame if any of the limits which are set
+/// would otherwise be exceeded. The default has no limits, so all frames are
+/// written to one segment.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct RotationPolicy {
+    /// The maximum size of a segment in bytes.
+    ///
+    /// This is exceeded only if a single frame is larger.
+    pub max_bytes: Option<u64>,
+    /// The maximum time from the first frame of a segment to the last.
+    pub max_duration: Option<Duration>,
+    /// The maximum number of frames in a segment.
+    pub max_frames: Option<NonZeroU64>,
+}
+
+/// A segment written by [RotatingH264Writer].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SegmentInfo {
+    /// The zero-based number of the segment.
+    pub segment_number: u64,
+    /// The path of the segment file.
+    pub path: PathBuf,
+    /// The frame number, counted over all segments, of the first frame.
+    pub first_frame_number: u64,
+    /// The number of frames in the segment.
+    pub frame_count: u64,
+    /// The size of the segment file in bytes.
+    pub size: u64,
+}
+
+/// Called by [RotatingH264Writer] when a segment is finished.
+type RotationCallback = Box<dyn FnMut(&SegmentInfo) + Send>;
+
+/// The segment being written.
+struct Segment {
+    wtr: H264Writer<File>,
+    info: SegmentInfo,
+    started: Instant,
+}
+
+/// Writes frames to a sequence of `.h264` files, starting a new file when a
+/// [RotationPolicy] limit is reached.
+///
+/// Each segment is written by its own [H264Writer], so it starts with the
+/// parameter sets and an IDR picture and can be decoded independently. Every
+/// frame is written to exactly one segment: a new segment is started before
+/// the frame which would exceed a limit. If creating the new segment file or
+/// writing its first frame fails, the error is returned, the file is removed,
+/// and the next call to [Self::write] tries again.
+pub struct RotatingH264Writer {
+    template: String,
+    config: EncoderConfig,
+    policy: RotationPolicy,
+    on_rotate: Option<RotationCallback>,
+    segment: Option<Segment>,
+    next_segment_number: u64,
+    frame_count: u64,
+}
+
+impl RotatingH264Writer {
+    /// Create a new [RotatingH264Writer] naming segment files after
+    /// `template`.
+    ///
+    /// `{segment}` in the template is replaced with the segment number, and
+    /// `{segment:0N}` with the segment number padded with zeros to `N`
+    /// digits, as in `"recording-{segment:04}.h264"`. A template without the
+    /// placeholder results in [Error::InvalidConfiguration]. Existing files are
+    /// overwritten.
+    pub fn new<T: Into<String>>(
+        template: T,
+        config: EncoderConfig,
+        policy: RotationPolicy,
+    ) -> Result<Self> {
+        let template = template.into();
+        if segment_path(&template, 0).is_none() {
+            return Err(Error::InvalidConfiguration {
+                msg: "filename template without {segment} placeholder",
+                #[cfg(feature = "backtrace")]
+                backtrace: std::backtrace::Backtrace::capture(),
+            });
+        }
+        Ok(Self {
+            template,
+            config,
+            policy,
+            on_rotate: None,
+            segment: None,
+            next_segment_number: 0,
+            frame_count: 0,
+        })
+    }
+
+    /// Call `on_rotate` after each segment file is closed, including the last
+    /// one when the writer is finished or dropped.
+    pub fn set_rotation_callback<F: FnMut(&SegmentInfo) + Send + 'static>(&mut self, on_rotate: F) {
+        self.on_rotate = Some(Box::new(on_rotate));
+    }
+
+    /// Encode and write a frame, first starting a new segment if required.
+    ///
+    /// The returned [FrameInfo::frame_number] counts frames over all segments,
+    /// while [FrameInfo::offset] is relative to the start of the current
+    /// segment.
+    pub fn write(&mut self, frame: &YCbCrImage) -> Result<FrameInfo> {
+        if self.segment.as_ref().is_some_and(|s| self.is_full(s)) {
+            self.close_segment();
+        }
+        let segment = match &mut self.segment {
+            Some(segment) => segment,
+            segment @ None => {
+                let path = segment_path(&self.template, self.next_segment_number).unwrap();
+                let wtr = H264Writer::new_with_config(File::create(&path)?, self.config.clone())?;
+                segment.insert(Segment {
+                    wtr,
+                    info: SegmentInfo {
+                        segment_number: self.next_segment_number,
+                        path,
+                        first_frame_number: self.frame_count,
+                        frame_count: 0,
+                        size: 0,
+                    },
+                    started: Instant::now(),
+                })
+            }
+        };
+        let mut info = match segment.wtr.write(frame) {
+            Ok(info) => info,
+            Err(e) => {
+                if segment.info.frame_count == 0 {
+                    // Start the segment again with the next frame.
+                    let segment = self.segment.take().unwrap();
+                    drop(segment.wtr);
+                    let _ = std::fs::remove_file(&segment.info.path);
+                }
+                return Err(e);
+            }
+        };
+        segment.info.frame_count += 1;
+        segment.info.size = info.offset + info.size;
+        if segment.info.frame_count == 1 {
+            self.next_segment_number += 1;
+        }
+        info.frame_number = self.frame_count;
+        self.frame_count += 1;
+        Ok(info)
+    }
+
+    /// The segment which the last frame was written to, if it is still open.
+    pub fn current_segment(&self) -> Option<&SegmentInfo> {
+        self.segment.as_ref().map(|segment| &segment.info)
+    }
+
+    /// Close the current segment and return the number of frames written.
+    pub fn finish(mut self) -> u64 {
+        self.close_segment();
+        self.frame_count
+    }
+
+    /// Whether the next frame must go to a new segment.
+    fn is_full(&self, segment: &Segment) -> bool {
+        let policy = &self.policy;
+        segment.info.frame_count > 0
+            && (policy
+                .max_frames
+                .is_some_and(|max| segment.info.frame_count >= max.get())
+                || policy
+                    .max_duration
+                    .is_some_and(|max| segment.started.elapsed() >= max)
+                || policy.max_bytes.is_some_and(|max| {
+                    let max_frame_size = segment.wtr.max_frame_size().unwrap_or(0) as u64;
+                    segment.info.size + max_frame_size > max
+                }))
+    }
+
+    fn close_segment(&mut self) {
+        if let Some(segment) = self.segment.take() {
+            // Close the file before the callback, which may move it.
+            drop(segment.wtr);
+            if let Some(on_rotate) = self.on_rotate.as_mut() {
+                if segment.info.frame_count > 0 {
+                    on_rotate(&segment.info);
+                }
+            }
+        }
+    }
+}
+
+impl Drop for RotatingH264Writer {
+    fn drop(&mut self) {
+        self.close_segment();
+    }
+}
+
+/// The path of segment `segment_number` for `template`, or `None` if the
+/// template has no valid placeholder.
+fn segment_path(template: &str, segment_number: u64) -> Option<PathBuf> {
+    let start = template.find("{segment")?;
+    let end = start + template[start..].find('}')?;
+    let width = match &template[start + "{segment".len()..end] {
+        "" => 0,
+        spec => spec.strip_prefix(":0")?.parse().ok()?,
+    };
+    Some(PathBuf::from(format!(
+        "{}{:0width$}{}",
+        &template[..start],
+        segment_number,
+        &template[end + 1..],
+    )))
+}
+
+#[test]
+fn test_segment_path() {
+    assert_eq!(segment_path("a{segment}.h264", 12), Some("a12.h264".into()));
+    assert_eq!(
+        segment_path("out/{segment:04}.h264", 12),
+        Some("out/0012.h264".into())
+    );
+    assert_eq!(segment_path("a.h264", 12), None);
+    assert_eq!(segment_path("a{segment:4}.h264", 12), None);
+    assert_eq!(segment_path("a{segment", 12), None);
+}
@@ -7,21 +7,87 @@
 
 //! Supplemental Enhancement Information (SEI) encoding
 
+#[cfg(feature = "alloc")]
 use alloc::{vec, vec::Vec};
 
-use super::RbspData;
+use super::bitstream::BitWriter;
+#[cfg(feature = "alloc")]
+use super::bitstream::{invalid_bitstream, BitReader};
+use super::sink::ByteArray;
+use super::ByteSink;
+#[cfg(feature = "alloc")]
+use super::{RbspData, Result};
+
+/// The UUID of [UserDataUnregistered] messages carrying a precision time stamp
+/// as described in MISB Standard 0604.
+pub const MISP_MICROSECTIME_UUID: [u8; 16] = *b"MISPmicrosectime";
+
+/// The UUID of [UserDataUnregistered] messages carrying a frame number and time
+/// stamp shared by synchronized streams, as written by
+/// [crate::MultiCameraWriter].
+pub const FRAME_SYNC_UUID: [u8; 16] = *b"lessavcframesync";
 
 /// User data unregistered [SupplementalEnhancementInformation] message
+#[cfg(feature = "alloc")]
 #[derive(Debug, PartialEq, Eq)]
 pub struct UserDataUnregistered {
     pub uuid: [u8; 16],
     pub payload: Vec<u8>,
 }
 
+#[cfg(feature = "alloc")]
 impl UserDataUnregistered {
     pub fn new(uuid: [u8; 16], payload: Vec<u8>) -> Self {
         Self { uuid, payload }
     }
+
+    /// Create a precision time stamp message as described in MISB Standard
+    /// 0604 with the number of microseconds since the UNIX epoch.
+    pub fn new_precision_time_stamp(micros: u64) -> Self {
+        let t = micros.to_be_bytes();
+        // The status byte is followed by the time stamp with a 0xff byte
+        // inserted after every two bytes to prevent start code emulation.
+        let payload = vec![
+            0x0f, t[0], t[1], 0xff, t[2], t[3], 0xff, t[4], t[5], 0xff, t[6], t[7],
+        ];
+        Self::new(MISP_MICROSECTIME_UUID, payload)
+    }
+
+    /// The number of microseconds since the UNIX epoch if this is a precision
+    /// time stamp message as described in MISB Standard 0604.
+    pub fn precision_time_stamp(&self) -> Option<u64> {
+        if self.uuid != MISP_MICROSECTIME_UUID || self.payload.len() != 12 {
+            return None;
+        }
+        let p = &self.payload;
+        let t = [p[1], p[2], p[4], p[5], p[7], p[8], p[10], p[11]];
+        Some(u64::from_be_bytes(t))
+    }
+
+    /// Create a frame synchronization message with a frame number and time
+    /// stamp shared by several streams.
+    ///
+    /// The payload is the frame number followed by the time stamp, each as 8
+    /// big-endian bytes.
+    pub fn new_frame_sync(frame_number: u64, timestamp: u64) -> Self {
+        let mut payload = frame_number.to_be_bytes().to_vec();
+        payload.extend(timestamp.to_be_bytes());
+        Self::new(FRAME_SYNC_UUID, payload)
+    }
+
+    /// The frame number and time stamp if this is a frame synchronization
+    /// message created by [Self::new_frame_sync].
+    pub fn frame_sync(&self) -> Option<(u64, u64)> {
+        if self.uuid != FRAME_SYNC_UUID || self.payload.len() != 16 {
+            return None;
+        }
+        let (frame_number, timestamp) = self.payload.split_at(8);
+        Some((
+            u64::from_be_bytes(frame_number.try_into().unwrap()),
+            u64::from_be_bytes(timestamp.try_into().unwrap()),
+        ))
+    }
+
     fn to_sei_payload(&self) -> Vec<u8> {
         let mut result = self.uuid.to_vec();
         result.extend(self.payload.clone());
@@ -29,19 +95,74 @@ impl UserDataUnregistered {
     }
 }
 
+/// Recovery point [SupplementalEnhancementInformation] message
+///
+/// This indicates that decoding starting at the associated picture results in
+/// correct output pictures after `recovery_frame_cnt` frames.
+#[derive(Debug, PartialEq, Eq)]
+pub struct RecoveryPoint {
+    pub recovery_frame_cnt: u32,
+    pub exact_match_flag: bool,
+    pub broken_link_flag: bool,
+}
+
+impl RecoveryPoint {
+    /// Create a recovery point with exact match and no broken link.
+    pub fn new(recovery_frame_cnt: u32) -> Self {
+        Self {
+            recovery_frame_cnt,
+            exact_match_flag: true,
+            broken_link_flag: false,
+        }
+    }
+    fn to_sei_payload(&self) -> ByteArray<16> {
+        let mut bv = BitWriter::new(ByteArray::new());
+        bv.write_ue(self.recovery_frame_cnt);
+        bv.write_bit(self.exact_match_flag);
+        bv.write_bit(self.broken_link_flag);
+        // changing_slice_group_idc = 0
+        bv.write_bits(2, 0);
+        if !bv.is_byte_aligned() {
+            // bit_equal_to_one followed by bit_equal_to_zero bits until byte
+            // aligned.
+            bv.write_bit(true);
+        }
+        bv.finish()
+    }
+
+    /// Put the raw byte sequence payload of an SEI message containing only
+    /// this recovery point into `dest`.
+    ///
+    /// This is the same as [SupplementalEnhancementInformation::to_rbsp] but
+    /// does not allocate.
+    pub(crate) fn put_rbsp<S: ByteSink>(&self, dest: &mut S) {
+        let payload = self.to_sei_payload();
+        let payload = payload.as_bytes();
+        // The payload is at most 9 bytes long, so its size fits in one byte.
+        dest.put(&[6u8, payload.len().try_into().unwrap()]);
+        dest.put(payload);
+        dest.put(&[0x80]); // rbsp_trailing_bits
+    }
+}
+
 /// Supplemental Enhancement Information
+#[cfg(feature = "alloc")]
 #[derive(Debug, PartialEq, Eq)]
 #[non_exhaustive]
 pub enum SupplementalEnhancementInformation {
     /// User data unregistered message
     UserDataUnregistered(UserDataUnregistered),
+    /// Recovery point message
+    RecoveryPoint(RecoveryPoint),
 }
 
+#[cfg(feature = "alloc")]
 impl SupplementalEnhancementInformation {
     /// Encode into raw byte sequence payload
     pub fn to_rbsp(&self) -> RbspData {
         let (payload_type, payload) = match &self {
             Self::UserDataUnregistered(udr) => (5u8, udr.to_sei_payload()),
+            Self::RecoveryPoint(rp) => (6u8, rp.to_sei_payload().as_bytes().to_vec()),
         };
         let mut payload_size = payload.len();
         let mut num_ff_bytes = 0;
@@ -58,4 +179,100 @@ impl SupplementalEnhancementInformation {
         result.push(0x80); // rbsp_trailing_bits
         RbspData { data: result }
     }
+
+    /// Parse the messages in the raw byte sequence payload of an SEI NAL unit
+    ///
+    /// Messages of types other than those in this enum are skipped.
+    pub fn from_rbsp(rbsp: &[u8]) -> Result<Vec<Self>> {
+        let mut messages = Vec::new();
+        let mut data = rbsp;
+        // The last byte holds rbsp_trailing_bits.
+        while data.len() > 1 {
+            let payload_type = read_sei_value(&mut data)?;
+            let payload_size = read_sei_value(&mut data)?;
+            if payload_size >= data.len() {
+                return Err(invalid_bitstream("SEI payload exceeds NAL unit"));
+            }
+            let (payload, rest) = data.split_at(payload_size);
+            data = rest;
+            match payload_type {
+                5 => {
+                    if payload.len() < 16 {
+                        return Err(invalid_bitstream("user data unregistered too short"));
+                    }
+                    let (uuid, payload) = payload.split_at(16);
+                    messages.push(Self::UserDataUnregistered(UserDataUnregistered::new(
+                        uuid.try_into().unwrap(),
+                        payload.to_vec(),
+                    )));
+                }
+                6 => {
+                    let mut r = BitReader::new(payload);
+                    messages.push(Self::RecoveryPoint(RecoveryPoint {
+                        recovery_frame_cnt: r.read_ue()?,
+                        exact_match_flag: r.read_bit()?,
+                        broken_link_flag: r.read_bit()?,
+                    }));
+                }
+                _ => {}
+            }
+        }
+        if data != [0x80] {
+            return Err(invalid_bitstream("missing rbsp_trailing_bits"));
+        }
+        Ok(messages)
+    }
+}
+
+/// Read an SEI payload type or size, coded as a sequence of 0xff bytes whose
+/// values are added to the final byte.
+#[cfg(feature = "alloc")]
+fn read_sei_value(data: &mut &[u8]) -> Result<usize> {
+    let mut value = 0;
+    loop {
+        let (&byte, rest) = data
+            .split_first()
+            .ok_or_else(|| invalid_bitstream("truncated SEI message"))?;
+        *data = rest;
+        value += usize::from(byte);
+        if byte != 0xff {
+            return Ok(value);
+        }
+    }
+}
+
+#[test]
+#[cfg(feature = "alloc")]
+fn test_sei_round_trip() {
+    let messages = [
+        SupplementalEnhancementInformation::UserDataUnregistered(
+            UserDataUnregistered::new_precision_time_stamp(1_668_861_296_123_456),
+        ),
+        SupplementalEnhancementInformation::UserDataUnregistered(UserDataUnregistered::new(
+            [7; 16],
+            vec![1; 300],
+        )),
+        SupplementalEnhancementInformation::RecoveryPoint(RecoveryPoint::new(3)),
+    ];
+    for message in messages {
+        let rbsp = message.to_rbsp();
+        let parsed = SupplementalEnhancementInformation::from_rbsp(&rbsp.data).unwrap();
+        assert_eq!(parsed, [message]);
+    }
+
+    let udu = UserDataUnregistered::new_precision_time_stamp(0x0102_0304_0506_0708);
+    assert_eq!(
+        udu.payload,
+        [0x0f, 1, 2, 0xff, 3, 4, 0xff, 5, 6, 0xff, 7, 8]
+    );
+    assert_eq!(udu.precision_time_stamp(), Some(0x0102_0304_0506_0708));
+    assert_eq!(
+        UserDataUnregistered::new([0; 16], vec![0; 12]).precision_time_stamp(),
+        None
+    );
+    let udu = UserDataUnregistered::new_frame_sync(12, 0x0102_0304_0506_0708);
+    assert_eq!(udu.frame_sync(), Some((12, 0x0102_0304_0506_0708)));
+    assert_eq!(udu.precision_time_stamp(), None);
+
+    assert!(SupplementalEnhancementInformation::from_rbsp(&[5, 20, 0x80]).is_err());
 }
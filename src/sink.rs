@@ -0,0 +1,254 @@
+// Copyright 2022-2023 Andrew D. Straw.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT
+// or http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Destinations for encoded bytes.
+
+#[cfg(feature = "alloc")]
+use alloc::vec::Vec;
+
+use crate::Result;
+
+/// A destination to which bytes are appended.
+pub(crate) trait ByteSink {
+    fn put(&mut self, data: &[u8]);
+}
+
+/// A [ByteSink] in memory, whose bytes can be modified after being put.
+pub(crate) trait NalBuffer: ByteSink {
+    /// The number of bytes put so far.
+    fn position(&self) -> usize;
+    /// The bytes put since position `start`.
+    fn bytes_since(&mut self, start: usize) -> &mut [u8];
+    /// Prepare for `additional` more bytes to be put.
+    fn reserve(&mut self, _additional: usize) {}
+}
+
+#[cfg(feature = "alloc")]
+impl ByteSink for Vec<u8> {
+    #[inline]
+    fn put(&mut self, data: &[u8]) {
+        self.extend_from_slice(data);
+    }
+}
+
+#[cfg(feature = "alloc")]
+impl NalBuffer for Vec<u8> {
+    fn position(&self) -> usize {
+        self.len()
+    }
+    fn bytes_since(&mut self, start: usize) -> &mut [u8] {
+        &mut self[start..]
+    }
+    fn reserve(&mut self, additional: usize) {
+        Vec::reserve(self, additional)
+    }
+}
+
+/// A destination to which encoded NAL units are streamed.
+///
+/// With the `std` feature, this is implemented for every [std::io::Write]
+/// implementation. Otherwise, it is implemented for `Vec<u8>` and can be
+/// implemented for other destinations such as a serial port or flash memory.
+pub trait NalSink {
+    /// Write all of `data`.
+    ///
+    /// Implementations outside this crate should report failure with an
+    /// error from [crate::Error::sink_error].
+    fn write_nal_bytes(&mut self, data: &[u8]) -> Result<()>;
+}
+
+#[cfg(feature = "std")]
+impl<W: std::io::Write + ?Sized> NalSink for W {
+    fn write_nal_bytes(&mut self, data: &[u8]) -> Result<()> {
+        std::io::Write::write_all(self, data)?;
+        Ok(())
+    }
+}
+
+#[cfg(all(feature = "alloc", not(feature = "std")))]
+impl NalSink for Vec<u8> {
+    fn write_nal_bytes(&mut self, data: &[u8]) -> Result<()> {
+        self.extend_from_slice(data);
+        Ok(())
+    }
+}
+
+/// Collects bytes and writes them to a [NalSink] in pieces of about
+/// `capacity` bytes.
+///
+/// The first error is kept and returned by [Self::finish]. Further data is
+/// discarded after an error.
+#[cfg(feature = "alloc")]
+pub(crate) struct BufferedSink<'a, S: ?Sized> {
+    inner: &'a mut S,
+    buf: Vec<u8>,
+    capacity: usize,
+    result: Result<()>,
+}
+
+#[cfg(feature = "alloc")]
+impl<'a, S: NalSink + ?Sized> BufferedSink<'a, S> {
+    pub(crate) fn new(inner: &'a mut S, capacity: usize) -> Self {
+        Self {
+            inner,
+            buf: Vec::with_capacity(capacity),
+            capacity,
+            result: Ok(()),
+        }
+    }
+
+    fn flush(&mut self) {
+        if self.result.is_ok() && !self.buf.is_empty() {
+            self.result = self.inner.write_nal_bytes(&self.buf);
+        }
+        self.buf.clear();
+    }
+
+    /// Write any remaining data and return the first error, if any.
+    pub(crate) fn finish(mut self) -> Result<()> {
+        self.flush();
+        self.result
+    }
+}
+
+#[cfg(feature = "alloc")]
+impl<S: NalSink + ?Sized> ByteSink for BufferedSink<'_, S> {
+    fn put(&mut self, data: &[u8]) {
+        if self.buf.len() + data.len() > self.capacity {
+            self.flush();
+        }
+        if self.result.is_err() {
+            return;
+        }
+        if data.len() > self.capacity {
+            self.result = self.inner.write_nal_bytes(data);
+        } else {
+            self.buf.extend_from_slice(data);
+        }
+    }
+}
+
+/// Fills a pre-sized buffer from the start.
+///
+/// Panics if more bytes are put than fit in the buffer.
+pub(crate) struct SliceCursor<'a> {
+    buf: &'a mut [u8],
+    pos: usize,
+}
+
+impl<'a> SliceCursor<'a> {
+    pub(crate) fn new(buf: &'a mut [u8]) -> Self {
+        Self { buf, pos: 0 }
+    }
+}
+
+impl NalBuffer for SliceCursor<'_> {
+    fn position(&self) -> usize {
+        self.pos
+    }
+    fn bytes_since(&mut self, start: usize) -> &mut [u8] {
+        &mut self.buf[start..self.pos]
+    }
+}
+
+impl ByteSink for SliceCursor<'_> {
+    #[inline]
+    fn put(&mut self, data: &[u8]) {
+        self.buf[self.pos..self.pos + data.len()].copy_from_slice(data);
+        self.pos += data.len();
+    }
+}
+
+/// A fixed capacity byte buffer for short pieces of data such as headers.
+///
+/// Panics if more than `N` bytes are put.
+pub(crate) struct ByteArray<const N: usize> {
+    buf: [u8; N],
+    len: usize,
+}
+
+impl<const N: usize> ByteArray<N> {
+    pub(crate) const fn new() -> Self {
+        Self {
+            buf: [0; N],
+            len: 0,
+        }
+    }
+
+    pub(crate) fn as_bytes(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+}
+
+impl<const N: usize> ByteSink for ByteArray<N> {
+    #[inline]
+    fn put(&mut self, data: &[u8]) {
+        self.buf[self.len..self.len + data.len()].copy_from_slice(data);
+        self.len += data.len();
+    }
+}
+
+/// Applies emulation prevention to the bytes put, converting raw byte sequence
+/// payload (RBSP) to encapsulated byte sequence payload (EBSP) as it goes.
+///
+/// The state is carried across calls to [ByteSink::put], so data may be put in
+/// arbitrary pieces.
+pub(crate) struct EbspWriter<'a, S> {
+    inner: &'a mut S,
+    /// The number of consecutive zero bytes most recently written.
+    zeros: u8,
+}
+
+impl<'a, S: ByteSink> EbspWriter<'a, S> {
+    pub(crate) fn new(inner: &'a mut S) -> Self {
+        Self { inner, zeros: 0 }
+    }
+
+    /// Put data to which emulation prevention has already been applied.
+    ///
+    /// The data must not start with a byte which would need protecting after
+    /// the data already written.
+    #[cfg_attr(not(feature = "rayon"), allow(dead_code))]
+    pub(crate) fn put_escaped(&mut self, data: &[u8]) {
+        debug_assert!(self.zeros < 2 || data.first().map_or(true, |b| *b > 0x03));
+        self.inner.put(data);
+        let trailing = data.iter().rev().take(2).take_while(|b| **b == 0).count();
+        self.zeros = if trailing == data.len() {
+            (self.zeros + trailing as u8).min(2)
+        } else {
+            trailing as u8
+        };
+    }
+}
+
+impl<S: ByteSink> ByteSink for EbspWriter<'_, S> {
+    fn put(&mut self, mut data: &[u8]) {
+        while let Some(&first) = data.first() {
+            if self.zeros >= 2 {
+                // Two zero bytes followed by 0x00, 0x01, 0x02 or 0x03 would
+                // emulate a start code.
+                if first <= 0x03 {
+                    self.inner.put(&[0x03]);
+                }
+                self.zeros = 0;
+            }
+            match memchr::memchr(0x00, data) {
+                None => {
+                    self.inner.put(data);
+                    self.zeros = 0;
+                    return;
+                }
+                Some(idx) => {
+                    // Copy up to and including the zero byte.
+                    self.inner.put(&data[..=idx]);
+                    self.zeros = if idx == 0 { self.zeros + 1 } else { 1 };
+                    data = &data[idx + 1..];
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,196 @@
+// Copyright 2022-2023 Andrew D. Straw.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT
+// or http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Encodes and writes frames on a background thread.
+
+use std::{
+    io::Write,
+    sync::{
+        atomic::{AtomicIsize, AtomicU64, Ordering},
+        mpsc::{sync_channel, Receiver, SyncSender, TrySendError},
+        Arc,
+    },
+    thread::JoinHandle,
+};
+
+use super::{ycbcr_image::OwnedYCbCrImage, Error, H264Writer, Result, YCbCrImage};
+
+/// What [ThreadedH264Writer::write] does when the queue is full.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum QueueFullPolicy {
+    /// Wait until the worker thread has taken a frame from the queue.
+    #[default]
+    Block,
+    /// Discard the new frame and count it as dropped.
+    Drop,
+}
+
+/// Statistics of a [ThreadedH264Writer].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ThreadedWriterStats {
+    /// Number of frames encoded and written so far.
+    pub frames_written: u64,
+    /// Number of frames discarded because the queue was full.
+    pub frames_dropped: u64,
+    /// Number of frames currently waiting in the queue, at most the queue
+    /// capacity.
+    pub queue_depth: usize,
+}
+
+/// The writer of the worker thread and the error which stopped it, if any.
+type WorkerResult<W> = (H264Writer<W>, Option<Error>);
+
+#[derive(Default)]
+struct Counters {
+    frames_written: AtomicU64,
+    frames_dropped: AtomicU64,
+    /// Incremented after a frame is sent, so the worker may take the frame
+    /// first and the value may briefly be negative.
+    queue_depth: AtomicIsize,
+}
+
+/// Wraps an [H264Writer] to encode and write frames on a worker thread.
+///
+/// Frames are passed by value into a bounded queue. The worker thread takes
+/// all queued frames at once and writes them with [H264Writer::write_batch],
+/// so with the `rayon` feature the frames are also encoded in parallel. The
+/// output is identical to writing the same frames with [H264Writer::write].
+pub struct ThreadedH264Writer<W> {
+    tx: Option<SyncSender<OwnedYCbCrImage>>,
+    worker: Option<JoinHandle<WorkerResult<W>>>,
+    /// The result of the worker thread once it has stopped.
+    stopped: Option<WorkerResult<W>>,
+    policy: QueueFullPolicy,
+    counters: Arc<Counters>,
+}
+
+impl<W: Write + Send + 'static> ThreadedH264Writer<W> {
+    /// Start a worker thread writing to `writer`.
+    ///
+    /// At most `queue_capacity` frames wait in the queue. When the queue is
+    /// full, `policy` determines whether [Self::write] blocks or drops the
+    /// frame.
+    pub fn new(
+        writer: H264Writer<W>,
+        queue_capacity: usize,
+        policy: QueueFullPolicy,
+    ) -> Result<Self> {
+        let (tx, rx) = sync_channel(queue_capacity);
+        let counters = Arc::new(Counters::default());
+        let worker_counters = counters.clone();
+        let worker = std::thread::Builder::new()
+            .name("less-avc-writer".into())
+            .spawn(move || run_worker(writer, rx, &worker_counters))?;
+        Ok(Self {
+            tx: Some(tx),
+            worker: Some(worker),
+            stopped: None,
+            policy,
+            counters,
+        })
+    }
+
+    /// Queue a frame for encoding and writing
+    ///
+    /// Returns `false` if the frame was dropped because the queue was full.
+    /// If the worker thread has failed, its error is returned.
+    pub fn write(&mut self, frame: OwnedYCbCrImage) -> Result<bool> {
+        let tx = self.tx.as_ref().ok_or(Error::InconsistentState {
+            #[cfg(feature = "backtrace")]
+            backtrace: std::backtrace::Backtrace::capture(),
+        })?;
+        let sent = match self.policy {
+            QueueFullPolicy::Block => tx.send(frame).map_err(|_| ()),
+            QueueFullPolicy::Drop => match tx.try_send(frame) {
+                Ok(()) => Ok(()),
+                Err(TrySendError::Full(_)) => {
+                    self.counters.frames_dropped.fetch_add(1, Ordering::SeqCst);
+                    return Ok(false);
+                }
+                Err(TrySendError::Disconnected(_)) => Err(()),
+            },
+        };
+        if sent.is_err() {
+            // The worker stopped early, which only happens on error.
+            self.tx = None;
+            let error = self.join().1.take();
+            return Err(error.unwrap_or(Error::InconsistentState {
+                #[cfg(feature = "backtrace")]
+                backtrace: std::backtrace::Backtrace::capture(),
+            }));
+        }
+        self.counters.queue_depth.fetch_add(1, Ordering::SeqCst);
+        Ok(true)
+    }
+
+    /// Get the current statistics.
+    pub fn stats(&self) -> ThreadedWriterStats {
+        ThreadedWriterStats {
+            frames_written: self.counters.frames_written.load(Ordering::SeqCst),
+            frames_dropped: self.counters.frames_dropped.load(Ordering::SeqCst),
+            queue_depth: self.counters.queue_depth.load(Ordering::SeqCst).max(0) as usize,
+        }
+    }
+
+    /// Write all queued frames, stop the worker thread and return the
+    /// underlying [std::io::Write] implementation with the result of writing.
+    ///
+    /// The writer is returned even if the worker thread failed, in which case
+    /// it holds the frames written before the error. An error already
+    /// returned by [Self::write] is not returned again.
+    pub fn finish(mut self) -> (W, Result<()>) {
+        self.tx = None;
+        self.join();
+        let (writer, error) = self.stopped.take().unwrap();
+        (writer.into_inner(), error.map_or(Ok(()), Err))
+    }
+
+    /// Wait for the worker thread to stop and return its result.
+    fn join(&mut self) -> &mut WorkerResult<W> {
+        if let Some(worker) = self.worker.take() {
+            match worker.join() {
+                Ok(result) => self.stopped = Some(result),
+                Err(panic) => std::panic::resume_unwind(panic),
+            }
+        }
+        // The worker is only taken here, so it has stopped.
+        self.stopped.as_mut().unwrap()
+    }
+}
+
+impl<W> Drop for ThreadedH264Writer<W> {
+    fn drop(&mut self) {
+        // Closing the queue lets the worker finish; errors are lost unless
+        // `finish` was called.
+        self.tx = None;
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+    }
+}
+
+fn run_worker<W: Write>(
+    mut writer: H264Writer<W>,
+    rx: Receiver<OwnedYCbCrImage>,
+    counters: &Counters,
+) -> WorkerResult<W> {
+    while let Ok(first) = rx.recv() {
+        let mut frames = vec![first];
+        frames.extend(rx.try_iter());
+        counters
+            .queue_depth
+            .fetch_sub(frames.len() as isize, Ordering::SeqCst);
+        let views: Vec<YCbCrImage> = frames.iter().map(|frame| frame.view()).collect();
+        match writer.write_batch(&views) {
+            Ok(infos) => counters
+                .frames_written
+                .fetch_add(infos.len() as u64, Ordering::SeqCst),
+            Err(error) => return (writer, Some(error)),
+        };
+    }
+    (writer, None)
+}
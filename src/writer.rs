@@ -7,95 +7,806 @@
 
 //! Associates an encoder with a writer to allow writing encoded frames.
 
-use std::io::Write;
+use std::{
+    fs::{File, OpenOptions},
+    io::{BufWriter, Read, Seek, SeekFrom, Write},
+    num::NonZeroU32,
+    path::Path,
+    time::{Duration, Instant},
+};
 
-use super::{Error, LessEncoder, Result, YCbCrImage};
+use super::{
+    annex_b::AnnexBSliceReader,
+    bitstream::invalid_bitstream,
+    decoder::PictureFormat,
+    index::{FrameIndex, FrameIndexEntry, FrameIndexWriter, IndexFormat, IndexedReader},
+    nal_unit::{EncodedFrame, NalFraming, NalRefIdc, NalUnit, NalUnitType},
+    sei::SupplementalEnhancementInformation,
+    EncoderConfig, Error, LessEncoder, Result, YCbCrImage,
+};
 
-/// An encoding session ready to start but which has not yet necessarily encoded
-/// its first frame.
+/// Wrap each SEI message in a NAL unit.
+pub(crate) fn sei_nal_units(sei: &[SupplementalEnhancementInformation]) -> Vec<NalUnit> {
+    sei.iter()
+        .map(|message| {
+            NalUnit::new(
+                NalRefIdc::Zero,
+                NalUnitType::SupplementalEnhancementInformation,
+                message.to_rbsp(),
+            )
+        })
+        .collect()
+}
+
+/// A frame index sidecar file written while recording.
+type IndexSidecar = FrameIndexWriter<Box<dyn Write + Send>>;
+
+/// How often [H264Writer] performs a periodic action.
+///
+/// Intervals are checked as frames are written, so a [Self::Duration] is
+/// only acted upon at the first frame written after it has passed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WriteInterval {
+    /// Every given number of frames.
+    Frames(NonZeroU32),
+    /// At the first frame after the given time has passed.
+    Duration(Duration),
+}
+
+/// Tracks the frames and time since a periodic action.
+pub(crate) struct Periodic {
+    interval: WriteInterval,
+    frames: u32,
+    last: Instant,
+}
+
+impl Periodic {
+    pub(crate) fn new(interval: WriteInterval) -> Self {
+        Self {
+            interval,
+            frames: 0,
+            last: Instant::now(),
+        }
+    }
+
+    pub(crate) fn is_due(&self) -> bool {
+        match self.interval {
+            WriteInterval::Frames(n) => self.frames >= n.get(),
+            WriteInterval::Duration(duration) => self.last.elapsed() >= duration,
+        }
+    }
+
+    /// The number of frames which can be written before checking
+    /// [Self::is_due] again, provided the action is taken at the first of
+    /// them if it is due now. `None` if this does not depend on frames.
+    fn frames_until_due(&self) -> Option<usize> {
+        match self.interval {
+            WriteInterval::Frames(n) if self.is_due() => Some(n.get() as usize),
+            WriteInterval::Frames(n) => Some((n.get() - self.frames) as usize),
+            WriteInterval::Duration(_) => None,
+        }
+    }
+
+    pub(crate) fn add_frames(&mut self, n: usize) {
+        self.frames = self.frames.saturating_add(n.try_into().unwrap_or(u32::MAX));
+    }
+
+    fn restart(&mut self) {
+        self.frames = 0;
+        self.last = Instant::now();
+    }
+}
+
+/// A [Write] implementation whose data can be flushed to durable storage.
+///
+/// This allows [H264Writer::set_sync_interval].
+pub trait SyncWrite: Write {
+    /// Flush any buffered data and wait until it has reached the storage
+    /// device.
+    fn sync(&mut self) -> std::io::Result<()>;
+}
+
+impl SyncWrite for File {
+    fn sync(&mut self) -> std::io::Result<()> {
+        self.sync_data()
+    }
+}
+
+impl<W: SyncWrite> SyncWrite for BufWriter<W> {
+    fn sync(&mut self) -> std::io::Result<()> {
+        self.flush()?;
+        self.get_mut().sync()
+    }
+}
+
+/// [SyncWrite::sync] of a writer, kept by [H264Writer] for any writer type.
+type SyncFn<W> = fn(&mut W) -> std::io::Result<()>;
+
+/// Options applied to every frame written by [H264Writer].
+#[derive(Default)]
+struct WriteOptions {
+    index: Option<IndexSidecar>,
+    /// If set, the parameter sets precede every IDR picture and an IDR
+    /// picture is forced at this interval.
+    parameter_sets: Option<Periodic>,
+}
+
+impl WriteOptions {
+    /// Force an IDR picture if the parameter sets are due to be repeated.
+    fn before_frame(&self, encoder: &mut LessEncoder) {
+        if self.parameter_sets.as_ref().is_some_and(Periodic::is_due) {
+            encoder.force_keyframe();
+        }
+    }
+}
+
+/// An encoding session, apart from its writer.
 ///
-/// This mainly exists to hold the writer but defer writing until we have the
-/// first frame (in the `Configured` variant). After the first frame is written,
-/// it will be in the `Recording` variant. (The `MovedOut` variant should never
-/// be observed and represents a temporary internal state.)
-enum WriteState<W> {
-    Configured(W),
-    Recording(RecordingState<W>),
-    MovedOut,
-}
-
-impl<W: Write> WriteState<W> {
-    fn write_frame(&mut self, frame: &YCbCrImage) -> Result<()> {
-        // Temporarily replace ourself with a dummy value.
-        let orig_state = std::mem::replace(self, WriteState::MovedOut);
-        let state = match orig_state {
-            WriteState::Configured(fd) => {
-                let (initial_nal_data, encoder) = LessEncoder::new(frame)?;
-                let mut state = RecordingState { wtr: fd, encoder };
-                state
-                    .wtr
-                    .write_all(&initial_nal_data.sps.to_annex_b_data())?;
-                state
-                    .wtr
-                    .write_all(&initial_nal_data.pps.to_annex_b_data())?;
-                state
-                    .wtr
-                    .write_all(&initial_nal_data.frame.to_annex_b_data())?;
-                state
+/// This mainly exists to defer writing until we have the first frame (in the
+/// `Configured` variant, which also holds the stream being appended to, if
+/// any). After the first frame is written, it will be in the `Recording`
+/// variant. If writing the first frame fails, it stays in the `Configured`
+/// variant, so the next frame starts the stream again.
+enum WriteState {
+    Configured(EncoderConfig, Option<ExistingStream>),
+    Recording(RecordingState),
+}
+
+impl WriteState {
+    fn write_frame<W: Write>(
+        &mut self,
+        wtr: &mut W,
+        frame: &YCbCrImage,
+        timestamp: Option<u64>,
+        sei: &[NalUnit],
+        options: &mut WriteOptions,
+    ) -> Result<FrameInfo> {
+        match self {
+            WriteState::Configured(config, existing) => {
+                let (state, info) = match existing {
+                    Some(existing) => RecordingState::resume(
+                        wtr,
+                        config.clone(),
+                        existing,
+                        frame,
+                        timestamp,
+                        sei,
+                        options,
+                    )?,
+                    None => {
+                        RecordingState::start(wtr, config.clone(), frame, timestamp, sei, options)?
+                    }
+                };
+                *self = WriteState::Recording(state);
+                Ok(info)
+            }
+            WriteState::Recording(state) => {
+                let start = state.position;
+                let mut buf = std::mem::take(&mut state.buf);
+                buf.clear();
+                options.before_frame(&mut state.encoder);
+                let is_idr = if options.index.is_some() {
+                    // Length prefixes give the NAL unit sizes without searching
+                    // for start codes.
+                    let is_idr =
+                        state
+                            .encoder
+                            .encode_into(frame, NalFraming::LengthPrefixed, &mut buf)?;
+                    state.write_repeated_parameter_sets(wtr, is_idr, options)?;
+                    state.write_sei(wtr, sei)?;
+                    length_prefixes_to_start_codes(&mut buf, &mut state.nal_sizes);
+                    is_idr
+                } else {
+                    let is_idr = state
+                        .encoder
+                        .encode_into(frame, NalFraming::AnnexB, &mut buf)?;
+                    state.write_repeated_parameter_sets(wtr, is_idr, options)?;
+                    state.write_sei(wtr, sei)?;
+                    is_idr
+                };
+                let result = state.write_all(wtr, &buf);
+                state.buf = buf;
+                result?;
+                state.frame_written(is_idr, start, timestamp, options)
             }
-            WriteState::Recording(mut state) => {
-                let encoded = state.encoder.encode(frame)?;
-                state.wtr.write_all(&encoded.to_annex_b_data())?;
-                state
+        }
+    }
+
+    fn write_frames<W: Write>(
+        &mut self,
+        wtr: &mut W,
+        frames: &[YCbCrImage],
+        options: &mut WriteOptions,
+    ) -> Result<Vec<FrameInfo>> {
+        let mut frames = frames;
+        let mut infos = Vec::with_capacity(frames.len());
+        if let WriteState::Configured(..) = self {
+            // The first frame is needed to start the encoder.
+            if let Some((first, rest)) = frames.split_first() {
+                infos.push(self.write_frame(wtr, first, None, &[], options)?);
+                frames = rest;
+            }
+        }
+        match self {
+            WriteState::Recording(state) => {
+                while !frames.is_empty() {
+                    // Split the batch where an IDR picture must be forced.
+                    let n_frames = options
+                        .parameter_sets
+                        .as_ref()
+                        .and_then(Periodic::frames_until_due)
+                        .map_or(frames.len(), |n| n.min(frames.len()));
+                    let (batch, rest) = frames.split_at(n_frames);
+                    options.before_frame(&mut state.encoder);
+                    for encoded in state.encoder.encode_batch(batch)?.iter() {
+                        let start = state.position;
+                        state.write_repeated_parameter_sets(wtr, encoded.is_idr, options)?;
+                        infos.push(state.write_encoded(wtr, encoded, start, None, options)?);
+                    }
+                    frames = rest;
+                }
+                Ok(infos)
             }
-            WriteState::MovedOut => {
-                return Err(Error::InconsistentState {
+            // There were no frames.
+            WriteState::Configured(..) => Ok(infos),
+        }
+    }
+}
+
+/// Replace the 4 byte size prefix of each NAL unit in `buf` with a start code,
+/// appending the size of each NAL unit, including its start code, to
+/// `nal_sizes`.
+fn length_prefixes_to_start_codes(buf: &mut [u8], nal_sizes: &mut Vec<u32>) {
+    let mut pos = 0;
+    while pos < buf.len() {
+        let prefix = &mut buf[pos..pos + 4];
+        let nal_size = 4 + u32::from_be_bytes(prefix.try_into().unwrap());
+        prefix.copy_from_slice(&[0x00, 0x00, 0x00, 0x01]);
+        nal_sizes.push(nal_size);
+        pos += nal_size as usize;
+    }
+}
+
+/// Information about a frame written by [H264Writer::write].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FrameInfo {
+    /// The zero-based index of the frame in the stream.
+    pub frame_number: u64,
+    /// Whether the frame is a sync sample (an IDR picture) at which decoding
+    /// can start.
+    pub is_sync: bool,
+    /// The position of the first byte of the frame, relative to the position
+    /// of the writer when the [H264Writer] was created. For the first frame,
+    /// this includes the parameter sets.
+    pub offset: u64,
+    /// The number of bytes written for the frame.
+    pub size: u64,
+}
+
+/// Small helper struct holding the encoder for an ongoing encoding session.
+struct RecordingState {
+    encoder: LessEncoder,
+    frame_count: u64,
+    /// The number of bytes written so far.
+    position: u64,
+    /// Buffer for the encoded frame, reused across frames.
+    buf: Vec<u8>,
+    /// The sizes of the NAL units of the frame being written, if there is an
+    /// index sidecar.
+    nal_sizes: Vec<u32>,
+}
+
+impl RecordingState {
+    /// Start a new stream with its first frame.
+    fn start<W: Write>(
+        wtr: &mut W,
+        config: EncoderConfig,
+        frame: &YCbCrImage,
+        timestamp: Option<u64>,
+        sei: &[NalUnit],
+        options: &mut WriteOptions,
+    ) -> Result<(Self, FrameInfo)> {
+        let (initial_nal_data, encoder) = LessEncoder::new_with_config(frame, config)?;
+        let mut state = RecordingState {
+            encoder,
+            frame_count: 0,
+            position: 0,
+            buf: Vec::new(),
+            nal_sizes: Vec::new(),
+        };
+        // The parameter sets belong to the access unit of the first
+        // frame.
+        state.write_nal_unit(wtr, &initial_nal_data.sps.to_annex_b_data())?;
+        state.write_nal_unit(wtr, &initial_nal_data.pps.to_annex_b_data())?;
+        state.write_sei(wtr, sei)?;
+        let info = state.write_encoded(wtr, &initial_nal_data.frame, 0, timestamp, options)?;
+        Ok((state, info))
+    }
+
+    /// Start recording at the end of an existing stream with the first new
+    /// frame.
+    #[allow(clippy::too_many_arguments)]
+    fn resume<W: Write>(
+        wtr: &mut W,
+        config: EncoderConfig,
+        existing: &ExistingStream,
+        frame: &YCbCrImage,
+        timestamp: Option<u64>,
+        sei: &[NalUnit],
+        options: &mut WriteOptions,
+    ) -> Result<(Self, FrameInfo)> {
+        let encoder = LessEncoder::new_continuing(
+            frame,
+            config,
+            existing.last_idr_pic_id,
+            existing.frames_since_idr,
+        )?;
+        let (sps, pps) = encoder.parameter_set_nal_units();
+        let mut state = RecordingState {
+            encoder,
+            frame_count: existing.frame_count,
+            position: existing.len,
+            buf: Vec::new(),
+            nal_sizes: Vec::new(),
+        };
+        let start = state.position;
+        let changed = sps.rbsp_data().data != existing.sps.rbsp_data().data
+            || pps.rbsp_data().data != existing.pps.rbsp_data().data;
+        if changed {
+            // Different parameter sets may only take effect at an IDR picture.
+            if PictureFormat::from_sps(&sps.rbsp_data().data)?
+                != PictureFormat::from_sps(&existing.sps.rbsp_data().data)?
+            {
+                return Err(Error::DataShapeProblem {
+                    msg: "frame format differs from the existing stream",
                     #[cfg(feature = "backtrace")]
                     backtrace: std::backtrace::Backtrace::capture(),
-                })
+                });
             }
-        };
+            state.encoder.force_keyframe();
+        }
+        options.before_frame(&mut state.encoder);
+        let encoded = state.encoder.encode(frame)?;
+        if changed {
+            state.write_nal_unit(wtr, &sps.to_annex_b_data())?;
+            state.write_nal_unit(wtr, &pps.to_annex_b_data())?;
+        } else {
+            state.write_repeated_parameter_sets(wtr, encoded.is_idr, options)?;
+        }
+        state.write_sei(wtr, sei)?;
+        let info = state.write_encoded(wtr, &encoded, start, timestamp, options)?;
+        Ok((state, info))
+    }
 
-        // Restore ourself to the correct state.
-        *self = WriteState::Recording(state);
+    fn write_all<W: Write>(&mut self, wtr: &mut W, data: &[u8]) -> Result<()> {
+        wtr.write_all(data)?;
+        self.position += data.len() as u64;
+        Ok(())
+    }
 
+    fn write_nal_unit<W: Write>(&mut self, wtr: &mut W, data: &[u8]) -> Result<()> {
+        self.write_all(wtr, data)?;
+        self.nal_sizes.push(data.len() as u32);
         Ok(())
     }
-}
 
-/// Small helper struct holding writer and encoder for an ongoing encoding
-/// session.
-struct RecordingState<W> {
-    wtr: W,
-    encoder: LessEncoder,
+    /// Write the parameter sets before an IDR picture if they are repeated.
+    fn write_repeated_parameter_sets<W: Write>(
+        &mut self,
+        wtr: &mut W,
+        is_idr: bool,
+        options: &WriteOptions,
+    ) -> Result<()> {
+        if is_idr && options.parameter_sets.is_some() {
+            let (sps, pps) = self.encoder.parameter_set_nal_units();
+            self.write_nal_unit(wtr, &sps.to_annex_b_data())?;
+            self.write_nal_unit(wtr, &pps.to_annex_b_data())?;
+        }
+        Ok(())
+    }
+
+    /// Write SEI NAL units belonging to the next frame.
+    fn write_sei<W: Write>(&mut self, wtr: &mut W, sei: &[NalUnit]) -> Result<()> {
+        for nal_unit in sei.iter() {
+            self.write_nal_unit(wtr, &nal_unit.to_annex_b_data())?;
+        }
+        Ok(())
+    }
+
+    /// Write a frame whose first byte is at position `start`.
+    fn write_encoded<W: Write>(
+        &mut self,
+        wtr: &mut W,
+        encoded: &EncodedFrame,
+        start: u64,
+        timestamp: Option<u64>,
+        options: &mut WriteOptions,
+    ) -> Result<FrameInfo> {
+        if options.index.is_some() {
+            for nal_unit in encoded.nal_units.iter() {
+                self.write_nal_unit(wtr, &nal_unit.to_annex_b_data())?;
+            }
+        } else {
+            self.write_all(wtr, &encoded.to_annex_b_data())?;
+        }
+        self.frame_written(encoded.is_idr, start, timestamp, options)
+    }
+
+    fn frame_written(
+        &mut self,
+        is_idr: bool,
+        start: u64,
+        timestamp: Option<u64>,
+        options: &mut WriteOptions,
+    ) -> Result<FrameInfo> {
+        let info = FrameInfo {
+            frame_number: self.frame_count,
+            is_sync: is_idr,
+            offset: start,
+            size: self.position - start,
+        };
+        self.frame_count += 1;
+        if let Some(parameter_sets) = options.parameter_sets.as_mut() {
+            if is_idr {
+                parameter_sets.restart();
+            }
+            parameter_sets.add_frames(1);
+        }
+        if let Some(index) = options.index.as_mut() {
+            let entry = FrameIndexEntry {
+                offset: info.offset,
+                // Frames are at most `LessEncoder::max_frame_size()` bytes.
+                size: info.size as u32,
+                is_sync: info.is_sync,
+                timestamp,
+                nal_sizes: std::mem::take(&mut self.nal_sizes),
+            };
+            let result = index.write_entry(&entry);
+            self.nal_sizes = entry.nal_sizes;
+            result?;
+        }
+        self.nal_sizes.clear();
+        Ok(info)
+    }
 }
 
 /// Write images to an [std::io::Write] implementation in `.h264` file format.
 pub struct H264Writer<W> {
-    inner: WriteState<W>,
+    wtr: W,
+    inner: WriteState,
+    options: WriteOptions,
+    /// When to call the function to flush the writer to durable storage.
+    sync: Option<(Periodic, SyncFn<W>)>,
 }
 
 impl<W: Write> H264Writer<W> {
     /// Create a new [H264Writer] from an [std::io::Write] implementation.
     pub fn new(wtr: W) -> Result<Self> {
+        Self::new_with_config(wtr, EncoderConfig::default())
+    }
+
+    /// Create a new [H264Writer] which encodes with the given options.
+    pub fn new_with_config(wtr: W, config: EncoderConfig) -> Result<Self> {
         Ok(Self {
-            inner: WriteState::Configured(wtr),
+            wtr,
+            inner: WriteState::Configured(config, None),
+            options: WriteOptions::default(),
+            sync: None,
         })
     }
 
+    /// Write a frame index sidecar file in the given format to `index_wtr`
+    /// while recording.
+    ///
+    /// An entry is written to `index_wtr` after each frame. Its time stamp is
+    /// the one given to [Self::write_with_timestamp], if any. Apart from the
+    /// time stamps, the result is identical to saving
+    /// [crate::index::FrameIndex::build] of the finished stream with
+    /// [crate::index::FrameIndex::write_to], provided the stream starts at
+    /// offset zero. This must be called before the first frame is written.
+    pub fn set_index_sidecar<I: Write + Send + 'static>(
+        &mut self,
+        index_wtr: I,
+        format: IndexFormat,
+    ) -> Result<()> {
+        let msg = match self.inner {
+            WriteState::Configured(_, None) => None,
+            WriteState::Configured(_, Some(_)) => {
+                Some("index sidecar not supported when appending")
+            }
+            WriteState::Recording(_) => Some("index sidecar set after the first frame"),
+        };
+        if let Some(msg) = msg {
+            return Err(Error::InvalidConfiguration {
+                msg,
+                #[cfg(feature = "backtrace")]
+                backtrace: std::backtrace::Backtrace::capture(),
+            });
+        }
+        let index_wtr: Box<dyn Write + Send> = Box::new(index_wtr);
+        self.options.index = Some(FrameIndexWriter::new(index_wtr, format)?);
+        Ok(())
+    }
+
+    /// Repeat the sequence and picture parameter sets at the given interval.
+    ///
+    /// When set, the parameter sets are written before every IDR picture, and
+    /// an IDR picture is forced once `interval` has passed since the last one.
+    /// Decoding can then start at any IDR picture, so a stream remains
+    /// decodable when its beginning is lost. With [Self::write_batch], a
+    /// [WriteInterval::Duration] is only checked at the start of each batch.
+    /// `None` disables the repetition for subsequent frames.
+    pub fn set_parameter_set_interval(&mut self, interval: Option<WriteInterval>) {
+        self.options.parameter_sets = interval.map(Periodic::new);
+    }
+
     /// Retrieve the underlying [std::io::Write] implementation.
     pub fn into_inner(self) -> W {
-        match self.inner {
-            WriteState::Configured(w) => w,
-            WriteState::Recording(state) => state.wtr,
-            WriteState::MovedOut => {
-                unreachable!("inconsistent internal state");
-            }
+        self.wtr
+    }
+
+    /// An upper bound on the number of bytes written for the next frame, or
+    /// `None` before the first frame.
+    pub(crate) fn max_frame_size(&self) -> Option<usize> {
+        match &self.inner {
+            WriteState::Recording(state) => Some(state.encoder.max_encoded_size()),
+            WriteState::Configured(..) => None,
         }
     }
 
     /// Encode and write a frame
-    pub fn write(&mut self, frame: &YCbCrImage) -> Result<()> {
-        self.inner.write_frame(frame)
+    ///
+    /// Returns information about the written frame, including whether it is a
+    /// sync sample.
+    pub fn write(&mut self, frame: &YCbCrImage) -> Result<FrameInfo> {
+        self.write_frame(frame, None, &[])
+    }
+
+    /// Encode and write a frame, recording `timestamp` in the index sidecar
+    ///
+    /// The time stamp is only stored in the sidecar file set with
+    /// [Self::set_index_sidecar], not in the stream itself. Its unit is chosen
+    /// by the caller.
+    pub fn write_with_timestamp(
+        &mut self,
+        frame: &YCbCrImage,
+        timestamp: u64,
+    ) -> Result<FrameInfo> {
+        self.write_frame(frame, Some(timestamp), &[])
+    }
+
+    /// Encode and write a frame preceded by the given SEI messages
+    ///
+    /// Each message is written in its own NAL unit, after any parameter sets
+    /// and before the coded slices of the frame.
+    pub fn write_with_sei(
+        &mut self,
+        frame: &YCbCrImage,
+        sei: &[SupplementalEnhancementInformation],
+    ) -> Result<FrameInfo> {
+        self.write_frame(frame, None, &sei_nal_units(sei))
+    }
+
+    /// Encode and write a frame with an optional index time stamp and SEI
+    /// NAL units.
+    pub(crate) fn write_frame(
+        &mut self,
+        frame: &YCbCrImage,
+        timestamp: Option<u64>,
+        sei: &[NalUnit],
+    ) -> Result<FrameInfo> {
+        let info =
+            self.inner
+                .write_frame(&mut self.wtr, frame, timestamp, sei, &mut self.options)?;
+        self.frames_written(1)?;
+        Ok(info)
+    }
+
+    /// Encode and write several frames
+    ///
+    /// The output is identical to calling [Self::write] for each frame, but
+    /// uses [LessEncoder::encode_batch] to encode the frames in parallel with
+    /// the `rayon` feature.
+    pub fn write_batch(&mut self, frames: &[YCbCrImage]) -> Result<Vec<FrameInfo>> {
+        let infos = self
+            .inner
+            .write_frames(&mut self.wtr, frames, &mut self.options)?;
+        self.frames_written(infos.len())?;
+        Ok(infos)
+    }
+
+    /// Flush the writer to durable storage if the sync interval has passed.
+    fn frames_written(&mut self, n_frames: usize) -> Result<()> {
+        let Some((periodic, sync)) = self.sync.as_mut() else {
+            return Ok(());
+        };
+        periodic.add_frames(n_frames);
+        if periodic.is_due() {
+            if let WriteState::Recording(_) = self.inner {
+                sync(&mut self.wtr)?;
+            }
+            periodic.restart();
+        }
+        Ok(())
+    }
+}
+
+impl<W: SyncWrite> H264Writer<W> {
+    /// Flush the written frames to durable storage at the given interval.
+    ///
+    /// After a power loss, at most the frames written since the last sync are
+    /// lost, and the stream can be repaired with [crate::recover_h264_file]. An
+    /// error from [SyncWrite::sync] is returned from the write call which
+    /// triggered it, after the frames have been written. The index sidecar
+    /// set with [Self::set_index_sidecar] is not synced; it can be rebuilt
+    /// from the stream with [crate::index::FrameIndex::build]. `None` disables
+    /// syncing.
+    pub fn set_sync_interval(&mut self, interval: Option<WriteInterval>) {
+        self.sync = interval.map(|interval| (Periodic::new(interval), W::sync as SyncFn<W>));
+    }
+}
+
+impl H264Writer<File> {
+    /// Open the `.h264` file at `path`, such as one written by [H264Writer],
+    /// to append frames to it.
+    ///
+    /// This is the same as [Self::append_with_config] with the default
+    /// configuration.
+    pub fn append<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::append_with_config(path, EncoderConfig::default())
+    }
+
+    /// Open the `.h264` file at `path` to append frames encoded with the given
+    /// options to it.
+    ///
+    /// The file is scanned for its frames and the last frame is decoded with
+    /// [crate::LessDecoder]. If it is incomplete, as after a crash during
+    /// writing, it is truncated together with any following data. New frames
+    /// continue the `idr_pic_id`, `frame_num` and picture order count
+    /// sequence of the existing stream.
+    ///
+    /// When the first new frame is written, the parameter sets for it and
+    /// `config` are compared to those of the existing stream. If they are
+    /// identical, they are not written again and the group of pictures
+    /// continues. Otherwise, they are written followed by an IDR picture,
+    /// provided the image size, bit depth and color format are unchanged. A
+    /// different format results in [Error::DataShapeProblem].
+    ///
+    /// A file without a complete frame is truncated to zero length and a new
+    /// stream is started.
+    pub fn append_with_config<P: AsRef<Path>>(path: P, config: EncoderConfig) -> Result<Self> {
+        let mut file = OpenOptions::new().read(true).write(true).open(path)?;
+        let existing = ExistingStream::scan(&mut file)?;
+        let len = existing.as_ref().map_or(0, |existing| existing.len);
+        file.set_len(len)?;
+        file.seek(SeekFrom::Start(len))?;
+        Ok(Self {
+            wtr: file,
+            inner: WriteState::Configured(config, existing),
+            options: WriteOptions::default(),
+            sync: None,
+        })
+    }
+}
+
+/// The result of [recover_h264_file].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RecoveryInfo {
+    /// The number of complete frames kept.
+    pub frame_count: u64,
+    /// The length of the file after recovery.
+    pub len: u64,
+    /// The number of bytes removed from the end of the file.
+    pub truncated: u64,
+}
+
+/// Repair the `.h264` file at `path` after a crash during writing.
+///
+/// The file is scanned as with [H264Writer::append] and truncated after its
+/// last complete frame, removing a torn frame and any incomplete NAL units
+/// following it. A file without a complete frame is truncated to zero length.
+/// A file which does not start with an Annex B start code is left untouched
+/// and [Error::InvalidBitstream] is returned.
+pub fn recover_h264_file<P: AsRef<Path>>(path: P) -> Result<RecoveryInfo> {
+    let mut file = OpenOptions::new().read(true).write(true).open(path)?;
+    let orig_len = file.metadata()?.len();
+    let existing = ExistingStream::scan(&mut file)?;
+    let (frame_count, len) = existing
+        .as_ref()
+        .map_or((0, 0), |existing| (existing.frame_count, existing.len));
+    file.set_len(len)?;
+    file.sync_all()?;
+    Ok(RecoveryInfo {
+        frame_count,
+        len,
+        truncated: orig_len - len,
+    })
+}
+
+/// The state at the end of an existing stream to which frames are appended.
+struct ExistingStream {
+    sps: NalUnit,
+    pps: NalUnit,
+    /// The `idr_pic_id` of the last IDR picture.
+    last_idr_pic_id: u32,
+    /// The number of frames from the last IDR picture to the end, inclusive.
+    frames_since_idr: u64,
+    frame_count: u64,
+    /// The length of the stream up to the end of the last complete frame.
+    len: u64,
+}
+
+impl ExistingStream {
+    /// Scan the stream in `file`, returning `None` if it has no complete frame.
+    fn scan(file: &mut File) -> Result<Option<Self>> {
+        let mut start = [0u8; 4];
+        let n_read = file.read(&mut start)?;
+        if n_read > 0 && !start.starts_with(&[0, 0, 1]) && start != [0, 0, 0, 1] {
+            // Do not truncate a file which is not an Annex B byte stream.
+            return Err(invalid_bitstream("no start code at beginning of file"));
+        }
+        file.seek(SeekFrom::Start(0))?;
+        let index = FrameIndex::build(&mut *file)?;
+        let mut rdr = IndexedReader::new(file, index);
+
+        let mut frame_count = rdr.len();
+        if frame_count == 0 {
+            return Ok(None);
+        }
+        // A crash may leave the last frame incomplete. Any other error means
+        // the stream cannot be continued, so it is left untouched.
+        match rdr.frame(frame_count - 1) {
+            Ok(_) => {}
+            Err(Error::InvalidBitstream { .. }) => {
+                frame_count -= 1;
+                if frame_count == 0 {
+                    return Ok(None);
+                }
+                rdr.frame(frame_count - 1)?;
+            }
+            Err(e) => return Err(e),
+        }
+        let last = &rdr.index().entries()[frame_count - 1];
+        let len = last.offset + u64::from(last.size);
+
+        let last_idr = rdr.index().entries()[..frame_count]
+            .iter()
+            .rposition(|entry| entry.is_sync)
+            .ok_or_else(|| invalid_bitstream("no IDR picture"))?;
+        rdr.frame(last_idr)?;
+        let last_idr_pic_id = rdr
+            .decoder()
+            .last_idr_pic_id()
+            .ok_or_else(|| invalid_bitstream("no IDR picture"))?;
+
+        // The parameter sets are in the first access unit and may be repeated
+        // later.
+        let (mut sps, mut pps) = (None, None);
+        for frame_number in [0, last_idr] {
+            let data = rdr.access_unit(frame_number)?.unwrap();
+            let mut nal_rdr = AnnexBSliceReader::new(data);
+            while let Some((_offset, ebsp)) = nal_rdr.next_ebsp() {
+                let Some(&header) = ebsp.first() else {
+                    continue;
+                };
+                match NalUnitType::from_nal_unit_type(header & 0x1f) {
+                    NalUnitType::SequenceParameterSet => sps = Some(NalUnit::from_nal_unit(ebsp)?),
+                    NalUnitType::PictureParameterSet => pps = Some(NalUnit::from_nal_unit(ebsp)?),
+                    _ => {}
+                }
+            }
+        }
+        let (Some(sps), Some(pps)) = (sps, pps) else {
+            return Err(invalid_bitstream("missing parameter sets"));
+        };
+
+        Ok(Some(Self {
+            sps,
+            pps,
+            last_idr_pic_id,
+            frames_since_idr: (frame_count - last_idr) as u64,
+            frame_count: frame_count as u64,
+            len,
+        }))
     }
 }
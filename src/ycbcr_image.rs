@@ -54,6 +54,99 @@ pub struct DataPlane<'a> {
     pub bit_depth: BitDepth,
 }
 
+#[cfg(feature = "alloc")]
+/// An image in YCbCr format which owns its data.
+///
+/// Use [Self::view] to obtain the [YCbCrImage] used for encoding.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OwnedYCbCrImage {
+    /// The data planes for the image
+    pub planes: OwnedPlanes,
+    /// The width of the image, in pixels
+    pub width: u32,
+    /// The height of the image, in pixels
+    pub height: u32,
+}
+
+#[cfg(feature = "alloc")]
+impl OwnedYCbCrImage {
+    /// Borrow the image as a [YCbCrImage].
+    pub fn view(&self) -> YCbCrImage<'_> {
+        let planes = match &self.planes {
+            OwnedPlanes::Mono(y) => Planes::Mono(y.view()),
+            OwnedPlanes::YCbCr((y, cb, cr)) => Planes::YCbCr((y.view(), cb.view(), cr.view())),
+        };
+        YCbCrImage {
+            planes,
+            width: self.width,
+            height: self.height,
+        }
+    }
+}
+
+#[cfg(feature = "alloc")]
+impl From<&YCbCrImage<'_>> for OwnedYCbCrImage {
+    /// Copy the image data.
+    fn from(orig: &YCbCrImage<'_>) -> Self {
+        let planes = match &orig.planes {
+            Planes::Mono(y) => OwnedPlanes::Mono(y.into()),
+            Planes::YCbCr((y, cb, cr)) => OwnedPlanes::YCbCr((y.into(), cb.into(), cr.into())),
+        };
+        Self {
+            planes,
+            width: orig.width,
+            height: orig.height,
+        }
+    }
+}
+
+#[cfg(feature = "alloc")]
+/// The data plane(s) within an [OwnedYCbCrImage].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OwnedPlanes {
+    //// Luminance only (monochrome) data.
+    Mono(OwnedDataPlane),
+    //// Luminance and chrominance data.
+    YCbCr((OwnedDataPlane, OwnedDataPlane, OwnedDataPlane)),
+}
+
+#[cfg(feature = "alloc")]
+/// Data for a single plane (luminance or chrominance) of an
+/// [OwnedYCbCrImage].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OwnedDataPlane {
+    /// The image data
+    pub data: Vec<u8>,
+    /// The row stride of the image data
+    pub stride: usize,
+    /// The bit depth of the image data
+    pub bit_depth: BitDepth,
+}
+
+#[cfg(feature = "alloc")]
+impl OwnedDataPlane {
+    /// Borrow the plane as a [DataPlane].
+    pub fn view(&self) -> DataPlane<'_> {
+        DataPlane {
+            data: &self.data,
+            stride: self.stride,
+            bit_depth: self.bit_depth,
+        }
+    }
+}
+
+#[cfg(feature = "alloc")]
+impl From<&DataPlane<'_>> for OwnedDataPlane {
+    /// Copy the plane data.
+    fn from(orig: &DataPlane<'_>) -> Self {
+        Self {
+            data: orig.data.to_vec(),
+            stride: orig.stride,
+            bit_depth: orig.bit_depth,
+        }
+    }
+}
+
 impl YCbCrImage<'_> {
     pub(crate) fn check_sizes(&self) -> Result<()> {
         match &self.planes {
@@ -51,6 +51,137 @@ mod bench {
         });
     }
 
+    #[bench]
+    fn mono8_1920x1080_slice_per_macroblock_encode(b: &mut Bencher) {
+        // Writing a slice header for every macroblock makes header generation
+        // a significant part of the encoding time.
+        let input_yuv = generate_image(&PixFmt::Mono8, 1920, 1080).unwrap();
+        let frame_view = input_yuv.view();
+        let config = less_avc::EncoderConfig {
+            slice_mode: less_avc::SliceMode::MaxBytes(600),
+            ..Default::default()
+        };
+        let (_initial, mut encoder) =
+            less_avc::LessEncoder::new_with_config(&frame_view, config).unwrap();
+        let mut buf = Vec::new();
+        b.iter(|| {
+            buf.clear();
+            encoder
+                .encode_into(
+                    &frame_view,
+                    less_avc::nal_unit::NalFraming::AnnexB,
+                    &mut buf,
+                )
+                .unwrap();
+        });
+    }
+
+    /// Write the syntax elements of a 1920x1080 High profile SPS and PPS and
+    /// of 68 slice headers, one per macroblock row.
+    fn write_header_syntax_elements(wtr: &mut less_avc::bitstream::RbspWriter) {
+        // sequence parameter set
+        wtr.write_bits(8, 100);
+        wtr.write_bits(8, 0);
+        wtr.write_bits(8, 40);
+        for value in [0, 1, 0, 0] {
+            wtr.write_ue(value);
+        }
+        wtr.write_bit(false);
+        wtr.write_bit(false);
+        for value in [12, 0, 12, 1] {
+            wtr.write_ue(value);
+        }
+        wtr.write_bit(false);
+        wtr.write_ue(119);
+        wtr.write_ue(67);
+        for _ in 0..3 {
+            wtr.write_bit(true);
+        }
+        for value in [0, 0, 0, 4] {
+            wtr.write_ue(value);
+        }
+        wtr.write_bit(false);
+        wtr.write_trailing_bits();
+
+        // picture parameter set
+        wtr.write_ue(0);
+        wtr.write_ue(0);
+        wtr.write_bit(false);
+        wtr.write_bit(false);
+        for _ in 0..3 {
+            wtr.write_ue(0);
+        }
+        wtr.write_bit(false);
+        wtr.write_bits(2, 0);
+        for _ in 0..3 {
+            wtr.write_se(0);
+        }
+        wtr.write_bit(true);
+        wtr.write_bit(false);
+        wtr.write_bit(false);
+        wtr.write_trailing_bits();
+
+        // slice headers
+        for first_mb in (0..68).map(|row| row * 120) {
+            wtr.write_ue(first_mb);
+            wtr.write_ue(7);
+            wtr.write_ue(0);
+            wtr.write_bits(16, 1234);
+            wtr.write_ue(5);
+            wtr.write_bits(16, 2468);
+            wtr.write_bit(false);
+            wtr.write_bit(false);
+            wtr.write_se(0);
+            wtr.write_ue(1);
+            wtr.align_with_zeros();
+        }
+    }
+
+    #[bench]
+    fn write_headers(b: &mut Bencher) {
+        // Isolates the bit writer used for parameter sets and slice headers.
+        b.iter(|| {
+            let mut wtr = less_avc::bitstream::RbspWriter::new();
+            write_header_syntax_elements(&mut wtr);
+            test::black_box(wtr.finish());
+        });
+    }
+
+    #[bench]
+    fn write_exp_golomb(b: &mut Bencher) {
+        b.iter(|| {
+            let mut wtr = less_avc::bitstream::RbspWriter::new();
+            for value in 0..1024 {
+                wtr.write_ue(value);
+                wtr.write_se(value as i32 - 512);
+            }
+            test::black_box(wtr.finish());
+        });
+    }
+
+    #[bench]
+    fn mono8_16x16_new_encoder(b: &mut Bencher) {
+        // With a single macroblock, writing the parameter sets and slice
+        // header is a large part of the time.
+        let input_yuv = generate_image(&PixFmt::Mono8, 16, 16).unwrap();
+        let frame_view = input_yuv.view();
+        b.iter(|| less_avc::LessEncoder::new(&frame_view).unwrap());
+    }
+
+    #[bench]
+    fn mono8_1920x1080_decode(b: &mut Bencher) {
+        let input_yuv = generate_image(&PixFmt::Mono8, 1920, 1080).unwrap();
+        let mut my_h264_writer = less_avc::H264Writer::new(vec![]).unwrap();
+        my_h264_writer.write(&input_yuv.view()).unwrap();
+        let h264_raw_buf = my_h264_writer.into_inner();
+        b.bytes = h264_raw_buf.len() as u64;
+        b.iter(|| {
+            less_avc::LessDecoder::new()
+                .decode_annex_b(&h264_raw_buf)
+                .unwrap();
+        });
+    }
+
     #[bench]
     fn rgb12_1920x1080_write(b: &mut Bencher) {
         bench_write(b, &PixFmt::Rgb12, 1920, 1080)
@@ -6,26 +6,9 @@ use less_avc::nal_unit::NalUnit;
 use less_avc::sei::*;
 use testbench::*;
 
-/// Create precision time stamp as descripted in MISB Standard 0604
-fn precision_time_stamp(timestamp: chrono::DateTime<chrono::Utc>) -> UserDataUnregistered {
-    let precision_time_stamp = timestamp.timestamp_micros();
-    let precision_time_stamp_bytes: [u8; 8] = precision_time_stamp.to_be_bytes();
-
-    let mut payload: Vec<u8> = vec![0u8; 12];
-    payload[0] = 0x0F;
-    payload[1..3].copy_from_slice(&precision_time_stamp_bytes[0..2]);
-    payload[3] = 0xff;
-    payload[4..6].copy_from_slice(&precision_time_stamp_bytes[2..4]);
-    payload[6] = 0xff;
-    payload[7..9].copy_from_slice(&precision_time_stamp_bytes[4..6]);
-    payload[9] = 0xff;
-    payload[9..11].copy_from_slice(&precision_time_stamp_bytes[6..8]);
-
-    UserDataUnregistered::new(*b"MISPmicrosectime", payload)
-}
-
 fn timestamp_to_nal_unit(timestamp: chrono::DateTime<chrono::Utc>) -> Vec<u8> {
-    to_annex_b(precision_time_stamp(timestamp))
+    let micros = timestamp.timestamp_micros().try_into().unwrap();
+    to_annex_b(UserDataUnregistered::new_precision_time_stamp(micros))
 }
 
 fn sei_comment(msg: Vec<u8>) -> Vec<u8> {
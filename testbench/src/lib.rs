@@ -1,7 +1,7 @@
 use anyhow::{Context, Result};
 use less_avc::{
     ycbcr_image::{DataPlane, Planes, YCbCrImage},
-    BitDepth,
+    BitDepth, EncoderConfig, GopConfig,
 };
 
 use tiff::decoder::Decoder as TiffDecoder;
@@ -311,6 +311,53 @@ pub fn next_multiple(a: u32, b: u32) -> u32 {
     a.div_ceil(b) * b
 }
 
+/// Convert the parameter sets in an AVC decoder configuration record (the
+/// `avcC` box contents or the Matroska `CodecPrivate`) and the
+/// length-prefixed NAL units in `samples` to an Annex B byte stream.
+pub fn avcc_to_annex_b<S: AsRef<[u8]>>(
+    parameter_sets: &[u8],
+    samples: impl IntoIterator<Item = S>,
+) -> Vec<u8> {
+    let length_size = usize::from(parameter_sets[4] & 0b11) + 1;
+    assert_eq!(parameter_sets[5], 0xe1, "expected one SPS");
+    let sps_len = u16::from_be_bytes([parameter_sets[6], parameter_sets[7]]) as usize;
+    let sps = &parameter_sets[8..8 + sps_len];
+    let rest = &parameter_sets[8 + sps_len..];
+    assert_eq!(rest[0], 1, "expected one PPS");
+    let pps_len = u16::from_be_bytes([rest[1], rest[2]]) as usize;
+    let pps = &rest[3..3 + pps_len];
+
+    let mut result = Vec::new();
+    for nal_unit in [sps, pps] {
+        result.extend([0, 0, 0, 1]);
+        result.extend(nal_unit);
+    }
+    for sample in samples {
+        let mut data = sample.as_ref();
+        while !data.is_empty() {
+            let len = data[..length_size]
+                .iter()
+                .fold(0, |len, byte| len << 8 | usize::from(*byte));
+            result.extend([0, 0, 0, 1]);
+            result.extend(&data[length_size..length_size + len]);
+            data = &data[length_size + len..];
+        }
+    }
+    result
+}
+
+/// An encoder configuration with an IDR picture every `idr_interval` frames
+/// and recovery point SEI messages.
+pub fn gop_config(idr_interval: u32) -> EncoderConfig {
+    EncoderConfig {
+        gop: GopConfig {
+            idr_interval: std::num::NonZeroU32::new(idr_interval),
+            recovery_point_sei: true,
+        },
+        ..Default::default()
+    }
+}
+
 pub fn generate_image(fmt: &PixFmt, width: u32, height: u32) -> Result<MyYCbCrImage> {
     if (fmt == &PixFmt::Mono12) || (fmt == &PixFmt::Rgb12) {
         // luma
@@ -0,0 +1,116 @@
+// Copyright 2022-2023 Andrew D. Straw.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT
+// or http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use anyhow::Result;
+use less_avc::{index::FrameIndex, EncoderConfig, H264Writer, LessDecoder};
+
+use testbench::*;
+
+/// Write `n_frames` frames to `path` in one session.
+fn write_frames(
+    path: &std::path::Path,
+    image: &MyYCbCrImage,
+    n_frames: usize,
+    config: EncoderConfig,
+) -> Result<()> {
+    let mut wtr = H264Writer::new_with_config(std::fs::File::create(path)?, config)?;
+    for _ in 0..n_frames {
+        wtr.write(&image.view())?;
+    }
+    Ok(())
+}
+
+#[test]
+fn test_append_continues_stream() -> Result<()> {
+    let tmpdir = tempfile::tempdir()?;
+    let image = generate_image(&PixFmt::Rgb8, 64, 48)?;
+
+    let expected_path = tmpdir.path().join("expected.h264");
+    write_frames(&expected_path, &image, 9, gop_config(3))?;
+    let expected = std::fs::read(&expected_path)?;
+
+    // Stop after 5 frames and continue.
+    let path = tmpdir.path().join("appended.h264");
+    write_frames(&path, &image, 5, gop_config(3))?;
+    let mut wtr = H264Writer::append_with_config(&path, gop_config(3))?;
+    let info = wtr.write(&image.view())?;
+    assert_eq!(info.frame_number, 5);
+    assert!(!info.is_sync);
+    for _ in 6..9 {
+        wtr.write(&image.view())?;
+    }
+    drop(wtr);
+    assert_eq!(std::fs::read(&path)?, expected);
+
+    // Tear the last frame and continue, replacing it.
+    write_frames(&path, &image, 5, gop_config(3))?;
+    let torn_len = std::fs::metadata(&path)?.len() - 100;
+    std::fs::OpenOptions::new()
+        .write(true)
+        .open(&path)?
+        .set_len(torn_len)?;
+    let mut wtr = H264Writer::append_with_config(&path, gop_config(3))?;
+    for _ in 4..9 {
+        wtr.write(&image.view())?;
+    }
+    drop(wtr);
+    assert_eq!(std::fs::read(&path)?, expected);
+
+    // An empty file starts a new stream.
+    std::fs::File::create(&path)?;
+    let mut wtr = H264Writer::append_with_config(&path, gop_config(3))?;
+    for _ in 0..9 {
+        wtr.write(&image.view())?;
+    }
+    drop(wtr);
+    assert_eq!(std::fs::read(&path)?, expected);
+    Ok(())
+}
+
+#[test]
+fn test_append_changed_config() -> Result<()> {
+    let tmpdir = tempfile::tempdir()?;
+    let image = generate_image(&PixFmt::Mono8, 64, 48)?;
+    let path = tmpdir.path().join("appended.h264");
+
+    // The default configuration has different parameter sets, so they are
+    // written again before an IDR picture.
+    write_frames(&path, &image, 2, gop_config(3))?;
+    let mut wtr = H264Writer::append(&path)?;
+    let info = wtr.write(&image.view())?;
+    assert_eq!(info.frame_number, 2);
+    assert!(info.is_sync);
+    wtr.write(&image.view())?;
+    drop(wtr);
+
+    let data = std::fs::read(&path)?;
+    let index = FrameIndex::from_annex_b(&data)?;
+    let sync: Vec<bool> = index.entries().iter().map(|e| e.is_sync).collect();
+    assert_eq!(sync, [true, false, true, true]);
+    // SPS, PPS and slice
+    assert_eq!(index.entries()[2].nal_sizes.len(), 3);
+    let decoded = LessDecoder::new().decode_annex_b(&data)?;
+    assert_eq!(decoded.len(), 4);
+
+    // Frames of a different format cannot be appended.
+    let len = data.len() as u64;
+    let other = generate_image(&PixFmt::Mono8, 32, 48)?;
+    let mut wtr = H264Writer::append(&path)?;
+    assert!(matches!(
+        wtr.write(&other.view()),
+        Err(less_avc::Error::DataShapeProblem { .. })
+    ));
+    drop(wtr);
+    assert_eq!(std::fs::metadata(&path)?.len(), len);
+
+    // Other files are left untouched.
+    let other_path = tmpdir.path().join("other.txt");
+    std::fs::write(&other_path, b"hello")?;
+    assert!(H264Writer::append(&other_path).is_err());
+    assert_eq!(std::fs::read(&other_path)?, b"hello");
+    Ok(())
+}
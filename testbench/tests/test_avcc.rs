@@ -0,0 +1,60 @@
+// Copyright 2022-2023 Andrew D. Straw.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT
+// or http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use anyhow::Result;
+use less_avc::{LessDecoder, LessEncoder};
+
+use testbench::*;
+
+#[test]
+fn test_avc_decoder_configuration_record() -> Result<()> {
+    // pixel format, profile_idc and expected High profile extension
+    let cases: [(_, _, &[u8]); 4] = [
+        (PixFmt::Mono8, 100, &[0xfc, 0xf8, 0xf8, 0]),
+        (PixFmt::Mono12, 244, &[0xfc, 0xf8 | 4, 0xf8 | 4, 0]),
+        // Baseline profile has no extension.
+        (PixFmt::Rgb8, 66, &[]),
+        (PixFmt::Rgb12, 244, &[0xfc | 1, 0xf8 | 4, 0xf8 | 4, 0]),
+    ];
+    for (pixfmt, profile_idc, extension) in cases {
+        let image = generate_image(&pixfmt, 32, 16)?;
+        let (initial, mut encoder) = LessEncoder::new(&image.view())?;
+        let record = initial.avc_decoder_configuration_record(2)?;
+        assert!(initial.avc_decoder_configuration_record(3).is_err());
+
+        let sps = initial.sps.to_nal_unit();
+        let pps = initial.pps.to_nal_unit();
+        assert_eq!(&record[..2], [1, profile_idc]);
+        assert_eq!(&record[1..4], &sps[1..4]);
+        assert_eq!(record[4], 0xfc | 1);
+        assert_eq!(record[5], 0xe1);
+        let sps_len = u16::from_be_bytes([record[6], record[7]]) as usize;
+        assert_eq!(&record[8..8 + sps_len], sps);
+        let rest = &record[8 + sps_len..];
+        assert_eq!(rest[0], 1);
+        let pps_len = u16::from_be_bytes([rest[1], rest[2]]) as usize;
+        assert_eq!(&rest[3..3 + pps_len], pps);
+        assert_eq!(&rest[3 + pps_len..], extension);
+
+        // Length-prefixed NAL units convert back to a decodable stream.
+        let mut samples = vec![initial.frame];
+        samples.push(encoder.encode(&image.view())?);
+        let samples = samples
+            .iter()
+            .map(|frame| {
+                let mut data = Vec::new();
+                for nal_unit in frame.nal_units.iter() {
+                    data.extend(nal_unit.to_avcc_data(2)?);
+                }
+                Ok(data)
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let annex_b = avcc_to_annex_b(&record, &samples);
+        assert_eq!(LessDecoder::new().decode_annex_b(&annex_b)?.len(), 2);
+    }
+    Ok(())
+}
@@ -0,0 +1,221 @@
+// Copyright 2022-2023 Andrew D. Straw.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT
+// or http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use std::{io::Cursor, time::Duration};
+
+use anyhow::Result;
+use less_avc::{H264Writer, LessDecoder, LessEncoder, MkvConfig, MkvWriter};
+
+use testbench::*;
+
+const SEGMENT: u32 = 0x1853_8067;
+const SEEK_HEAD: u32 = 0x114d_9b74;
+const INFO: u32 = 0x1549_a966;
+const TRACKS: u32 = 0x1654_ae6b;
+const CLUSTER: u32 = 0x1f43_b675;
+const CUES: u32 = 0x1c53_bb6b;
+
+/// Read a variable length integer, returning it with its length marker
+/// removed (unless `keep_marker`) and its length.
+fn read_vint(data: &[u8], keep_marker: bool) -> (u64, usize) {
+    let len = data[0].leading_zeros() as usize + 1;
+    let mut value = 0;
+    for byte in &data[..len] {
+        value = value << 8 | u64::from(*byte);
+    }
+    if !keep_marker {
+        value &= (1 << (7 * len)) - 1;
+    }
+    (value, len)
+}
+
+/// The elements in `data` as (id, offset, contents) tuples.
+fn elements(data: &[u8]) -> Vec<(u32, usize, &[u8])> {
+    let mut result = Vec::new();
+    let mut pos = 0;
+    while pos < data.len() {
+        let (id, id_len) = read_vint(&data[pos..], true);
+        let (size, size_len) = read_vint(&data[pos + id_len..], false);
+        let start = pos + id_len + size_len;
+        let end = start + size as usize;
+        result.push((id as u32, pos, &data[start..end]));
+        pos = end;
+    }
+    result
+}
+
+fn child(data: &[u8], id: u32) -> &[u8] {
+    elements(data)
+        .into_iter()
+        .find(|(i, _, _)| *i == id)
+        .unwrap()
+        .2
+}
+
+fn uint(data: &[u8]) -> u64 {
+    data.iter()
+        .fold(0, |value, byte| value << 8 | u64::from(*byte))
+}
+
+/// A frame stored in a `SimpleBlock`.
+#[derive(Debug, PartialEq)]
+struct Block {
+    timestamp: u64,
+    is_keyframe: bool,
+    data: Vec<u8>,
+}
+
+/// The contents of a Matroska file as written by [MkvWriter].
+struct Mkv {
+    timestamp_scale: u64,
+    duration: f64,
+    codec_private: Vec<u8>,
+    blocks: Vec<Block>,
+    /// Cue times with the time stamp of the first block of their cluster.
+    cues: Vec<(u64, u64)>,
+}
+
+fn parse_mkv(data: &[u8]) -> Mkv {
+    let top = elements(data);
+    assert_eq!(top.len(), 2);
+    assert_eq!(child(top[0].2, 0x4282), b"matroska");
+    assert_eq!(top[1].0, SEGMENT);
+    let segment = top[1].2;
+    let segment_elements = elements(segment);
+    let ids: Vec<u32> = segment_elements.iter().map(|(id, _, _)| *id).collect();
+    assert_eq!(&ids[..3], [SEEK_HEAD, INFO, TRACKS]);
+    assert_eq!(ids.last(), Some(&CUES));
+
+    // The seek head points to the top level elements.
+    for (_, _, seek) in elements(segment_elements[0].2) {
+        let id = uint(child(seek, 0x53ab)) as u32;
+        let position = uint(child(seek, 0x53ac)) as usize;
+        assert_eq!(read_vint(&segment[position..], true).0 as u32, id);
+    }
+
+    let info = segment_elements[1].2;
+    let timestamp_scale = uint(child(info, 0x2ad7b1));
+    let duration = f64::from_be_bytes(child(info, 0x4489).try_into().unwrap());
+    let track = child(segment_elements[2].2, 0xae);
+    assert_eq!(child(track, 0x86), b"V_MPEG4/ISO/AVC");
+    let codec_private = child(track, 0x63a2).to_vec();
+
+    let mut blocks = Vec::new();
+    let mut cluster_timestamps = Vec::new();
+    for (_, offset, cluster) in segment_elements.iter().filter(|(id, _, _)| *id == CLUSTER) {
+        let cluster_timestamp = uint(child(cluster, 0xe7));
+        cluster_timestamps.push((*offset, cluster_timestamp));
+        for (_, _, block) in elements(cluster)
+            .into_iter()
+            .filter(|(id, _, _)| *id == 0xa3)
+        {
+            assert_eq!(block[0], 0x81);
+            let relative = i16::from_be_bytes([block[1], block[2]]);
+            blocks.push(Block {
+                timestamp: cluster_timestamp + relative as u64,
+                is_keyframe: block[3] & 0x80 != 0,
+                data: block[4..].to_vec(),
+            });
+        }
+    }
+
+    let cues = elements(segment_elements.last().unwrap().2)
+        .into_iter()
+        .map(|(_, _, cue_point)| {
+            let positions = child(cue_point, 0xb7);
+            assert_eq!(uint(child(positions, 0xf7)), 1);
+            let position = uint(child(positions, 0xf1)) as usize;
+            let (_, cluster_timestamp) = cluster_timestamps
+                .iter()
+                .find(|(offset, _)| *offset == position)
+                .unwrap();
+            (uint(child(cue_point, 0xb3)), *cluster_timestamp)
+        })
+        .collect();
+    Mkv {
+        timestamp_scale,
+        duration,
+        codec_private,
+        blocks,
+        cues,
+    }
+}
+
+#[test]
+fn test_mkv_writer() -> Result<()> {
+    let image = generate_image(&PixFmt::Mono8, 32, 16)?;
+    let config = MkvConfig {
+        encoder: gop_config(3),
+        // 10 ms ticks
+        timestamp_scale: 10_000_000,
+    };
+    let mut wtr = MkvWriter::new_with_config(Cursor::new(Vec::new()), config)?;
+    // The third frame follows a gap longer than a block time stamp offset
+    // can hold.
+    let timestamps_ms = [1000, 1045, 401_000, 401_050, 401_100];
+    for t in timestamps_ms {
+        wtr.write(&image.view(), Duration::from_millis(t))?;
+    }
+    // Rounded to ticks, this is the time stamp of the previous frame.
+    assert!(matches!(
+        wtr.write(&image.view(), Duration::from_millis(401_109)),
+        Err(less_avc::Error::DataShapeProblem { .. })
+    ));
+    let mkv = parse_mkv(&wtr.finish()?.into_inner());
+
+    assert_eq!(mkv.timestamp_scale, 10_000_000);
+    assert_eq!(mkv.duration, 40015.0);
+    let blocks: Vec<_> = mkv
+        .blocks
+        .iter()
+        .map(|b| (b.timestamp, b.is_keyframe))
+        .collect();
+    assert_eq!(
+        blocks,
+        [
+            (0, true),
+            (4, false),
+            (40000, false),
+            (40005, true),
+            (40010, false)
+        ]
+    );
+    // The gap starts a cluster, but only keyframe clusters are cued.
+    assert_eq!(mkv.cues, [(0, 0), (40005, 40005)]);
+
+    // The blocks are the frames written by H264Writer.
+    let (initial, _) = LessEncoder::new_with_config(&image.view(), gop_config(3))?;
+    assert_eq!(
+        mkv.codec_private,
+        initial.avc_decoder_configuration_record(4)?
+    );
+    let mut expected = H264Writer::new_with_config(vec![], gop_config(3))?;
+    for _ in timestamps_ms {
+        expected.write(&image.view())?;
+    }
+    let annex_b = avcc_to_annex_b(&mkv.codec_private, mkv.blocks.iter().map(|b| &b.data));
+    assert_eq!(annex_b, expected.into_inner());
+    assert_eq!(LessDecoder::new().decode_annex_b(&annex_b)?.len(), 5);
+    Ok(())
+}
+
+#[test]
+fn test_mkv_writer_single_frame() -> Result<()> {
+    let image = generate_image(&PixFmt::Mono8, 16, 16)?;
+    let mut wtr = MkvWriter::new(Cursor::new(Vec::new()))?;
+    wtr.write(&image.view(), Duration::from_secs(1))?;
+    let mkv = parse_mkv(&wtr.finish()?.into_inner());
+    assert_eq!(mkv.duration, 1.0);
+    assert_eq!(mkv.blocks.len(), 1);
+    assert!(mkv.blocks[0].is_keyframe);
+    assert_eq!(mkv.cues, [(0, 0)]);
+
+    // Without frames, nothing is written.
+    let wtr = MkvWriter::new(Cursor::new(Vec::new()))?;
+    assert!(wtr.finish()?.into_inner().is_empty());
+    Ok(())
+}
@@ -0,0 +1,162 @@
+// Copyright 2022-2023 Andrew D. Straw.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT
+// or http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use std::{num::NonZeroU32, time::Duration};
+
+use anyhow::Result;
+use less_avc::{EncoderConfig, GopConfig, H264Writer, LessDecoder, Mp4Config, Mp4Writer};
+
+use testbench::*;
+
+/// The boxes in `data` as (type, offset, contents) tuples.
+fn boxes(data: &[u8]) -> Vec<(&[u8], usize, &[u8])> {
+    let mut result = Vec::new();
+    let mut pos = 0;
+    while pos < data.len() {
+        let size = u32::from_be_bytes(data[pos..pos + 4].try_into().unwrap()) as usize;
+        result.push((&data[pos + 4..pos + 8], pos, &data[pos + 8..pos + size]));
+        pos += size;
+    }
+    result
+}
+
+fn child<'a>(data: &'a [u8], box_type: &[u8]) -> &'a [u8] {
+    boxes(data)
+        .into_iter()
+        .find(|(t, _, _)| *t == box_type)
+        .unwrap()
+        .2
+}
+
+fn be_u32(data: &[u8]) -> u32 {
+    u32::from_be_bytes(data[..4].try_into().unwrap())
+}
+
+/// A sample in a movie fragment.
+#[derive(Debug, PartialEq)]
+struct Sample {
+    decode_time: u64,
+    duration: u32,
+    is_sync: bool,
+    data: Vec<u8>,
+}
+
+/// Parse a fragmented MP4 file as written by [Mp4Writer], returning the
+/// `avcC` contents and the samples.
+fn parse_mp4(data: &[u8]) -> (Vec<u8>, Vec<Sample>) {
+    let top = boxes(data);
+    let types: Vec<&[u8]> = top.iter().map(|(t, _, _)| *t).collect();
+    assert_eq!(&types[..2], [b"ftyp", b"moov"]);
+
+    let stsd = child(
+        child(
+            child(child(child(top[1].2, b"trak"), b"mdia"), b"minf"),
+            b"stbl",
+        ),
+        b"stsd",
+    );
+    // full box header and entry count
+    let avc1 = child(&stsd[8..], b"avc1");
+    // The avcC box follows the 78 byte visual sample entry.
+    let avcc = child(&avc1[78..], b"avcC").to_vec();
+
+    let mut samples = Vec::new();
+    for pair in top[2..].chunks(2) {
+        let [(moof_type, moof_offset, moof), (mdat_type, _, _)] = pair else {
+            panic!("moof without mdat");
+        };
+        assert_eq!((*moof_type, *mdat_type), (&b"moof"[..], &b"mdat"[..]));
+        let traf = child(moof, b"traf");
+        let tfdt = child(traf, b"tfdt");
+        assert_eq!(tfdt[0], 1);
+        let mut decode_time = u64::from_be_bytes(tfdt[4..12].try_into().unwrap());
+        let trun = child(traf, b"trun");
+        assert_eq!(be_u32(trun), 0x701);
+        let sample_count = be_u32(&trun[4..]) as usize;
+        let mut data_pos = moof_offset + be_u32(&trun[8..]) as usize;
+        for entry in trun[12..].chunks(12).take(sample_count) {
+            let duration = be_u32(entry);
+            let size = be_u32(&entry[4..]) as usize;
+            let flags = be_u32(&entry[8..]);
+            samples.push(Sample {
+                decode_time,
+                duration,
+                is_sync: flags & 0x0001_0000 == 0,
+                data: data[data_pos..data_pos + size].to_vec(),
+            });
+            decode_time += u64::from(duration);
+            data_pos += size;
+        }
+    }
+    (avcc, samples)
+}
+
+#[test]
+fn test_mp4_writer() -> Result<()> {
+    let image = generate_image(&PixFmt::Rgb12, 64, 48)?;
+    let encoder = EncoderConfig {
+        gop: GopConfig {
+            idr_interval: NonZeroU32::new(2),
+            recovery_point_sei: true,
+        },
+        ..Default::default()
+    };
+    let config = Mp4Config {
+        encoder: encoder.clone(),
+        timescale: 1000,
+        frames_per_fragment: NonZeroU32::new(2).unwrap(),
+    };
+    let mut wtr = Mp4Writer::new_with_config(vec![], config)?;
+    let timestamps_ms = [5000, 5040, 5080, 5130, 5170];
+    for t in timestamps_ms {
+        wtr.write(&image.view(), Duration::from_millis(t))?;
+    }
+    assert!(matches!(
+        wtr.write(&image.view(), Duration::from_millis(5170)),
+        Err(less_avc::Error::DataShapeProblem { .. })
+    ));
+    let data = wtr.finish()?;
+
+    let (avcc, samples) = parse_mp4(&data);
+    // High 4:4:4 profile with chroma format and bit depth extension
+    assert_eq!(&avcc[..2], [1, 244]);
+    assert_eq!(avcc[4], 0xff);
+    assert_eq!(&avcc[avcc.len() - 4..], [0xfc | 1, 0xf8 | 4, 0xf8 | 4, 0]);
+
+    let times: Vec<_> = samples
+        .iter()
+        .map(|s| (s.decode_time, s.duration))
+        .collect();
+    assert_eq!(times, [(0, 40), (40, 40), (80, 50), (130, 40), (170, 40)]);
+    let sync: Vec<_> = samples.iter().map(|s| s.is_sync).collect();
+    assert_eq!(sync, [true, false, true, false, true]);
+
+    // The samples are the frames written by H264Writer.
+    let mut expected = H264Writer::new_with_config(vec![], encoder)?;
+    for _ in timestamps_ms {
+        expected.write(&image.view())?;
+    }
+    let annex_b = avcc_to_annex_b(&avcc, samples.iter().map(|s| &s.data));
+    assert_eq!(annex_b, expected.into_inner());
+    assert_eq!(LessDecoder::new().decode_annex_b(&annex_b)?.len(), 5);
+    Ok(())
+}
+
+#[test]
+fn test_mp4_writer_single_frame() -> Result<()> {
+    let image = generate_image(&PixFmt::Mono8, 16, 16)?;
+    let mut wtr = Mp4Writer::new(vec![])?;
+    wtr.write(&image.view(), Duration::from_secs(1))?;
+    let (avcc, samples) = parse_mp4(&wtr.finish()?);
+    assert_eq!(samples.len(), 1);
+    assert_eq!((samples[0].decode_time, samples[0].duration), (0, 1));
+    assert!(samples[0].is_sync);
+    // High profile for monochrome
+    assert_eq!(avcc[1], 100);
+    assert_eq!(&avcc[avcc.len() - 4..], [0xfc, 0xf8, 0xf8, 0]);
+    Ok(())
+}
@@ -0,0 +1,141 @@
+// Copyright 2022-2023 Andrew D. Straw.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT
+// or http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use anyhow::Result;
+use less_avc::{
+    annex_b::AnnexBSliceReader,
+    index::FrameIndex,
+    nal_unit::{NalUnit, NalUnitType},
+    sei::SupplementalEnhancementInformation,
+    CameraStats, H264Writer, LessDecoder, MultiCameraWriter,
+};
+
+use testbench::*;
+
+/// The frame synchronization SEI messages in `data`, in order.
+fn frame_syncs(data: &[u8]) -> Result<Vec<(u64, u64)>> {
+    let mut result = Vec::new();
+    let mut rdr = AnnexBSliceReader::new(data);
+    while let Some((_offset, ebsp)) = rdr.next_ebsp() {
+        let nal_unit = NalUnit::from_nal_unit(ebsp)?;
+        if nal_unit.unit_type() != NalUnitType::SupplementalEnhancementInformation {
+            continue;
+        }
+        for message in SupplementalEnhancementInformation::from_rbsp(&nal_unit.rbsp_data().data)? {
+            if let SupplementalEnhancementInformation::UserDataUnregistered(udu) = message {
+                result.extend(udu.frame_sync());
+            }
+        }
+    }
+    Ok(result)
+}
+
+#[test]
+fn test_multi_camera_writer() -> Result<()> {
+    let mono = generate_image(&PixFmt::Mono8, 32, 32)?;
+    let rgb = generate_image(&PixFmt::Rgb8, 64, 48)?;
+    let writers = vec![
+        H264Writer::new(vec![])?,
+        H264Writer::new(vec![])?,
+        H264Writer::new(vec![])?,
+    ];
+    let mut wtr = MultiCameraWriter::new(writers);
+
+    for i in 0..4u64 {
+        // Camera 1 misses the third trigger.
+        let second = (i != 2).then(|| rgb.view());
+        let infos =
+            wtr.write_frame_set(1000 + i, &[Some(mono.view()), second, Some(mono.view())])?;
+        assert_eq!(infos[0].as_ref().unwrap().frame_number, i);
+        assert_eq!(infos[1].is_none(), i == 2);
+    }
+    assert_eq!(wtr.frame_set_count(), 4);
+    assert_eq!(
+        wtr.stats(),
+        [
+            CameraStats {
+                frames_written: 4,
+                frames_missing: 0,
+                frames_failed: 0,
+            },
+            CameraStats {
+                frames_written: 3,
+                frames_missing: 1,
+                frames_failed: 0,
+            },
+            CameraStats {
+                frames_written: 4,
+                frames_missing: 0,
+                frames_failed: 0,
+            },
+        ]
+    );
+    assert!(matches!(
+        wtr.write_frame_set(0, &[Some(mono.view())]),
+        Err(less_avc::Error::DataShapeProblem { .. })
+    ));
+
+    let streams = wtr.into_inner();
+    assert_eq!(
+        frame_syncs(&streams[0])?,
+        [(0, 1000), (1, 1001), (2, 1002), (3, 1003)]
+    );
+    assert_eq!(frame_syncs(&streams[1])?, [(0, 1000), (1, 1001), (3, 1003)]);
+    assert_eq!(streams[0], streams[2]);
+    for (stream, n_frames) in streams.iter().zip([4, 3, 4]) {
+        assert_eq!(LessDecoder::new().decode_annex_b(stream)?.len(), n_frames);
+        assert_eq!(FrameIndex::from_annex_b(stream)?.len(), n_frames);
+    }
+    Ok(())
+}
+
+#[test]
+fn test_multi_camera_writer_error() -> Result<()> {
+    let image = generate_image(&PixFmt::Rgb8, 32, 32)?;
+    // Odd widths are only supported for mono8.
+    let bad_image = generate_image(&PixFmt::Rgb8, 33, 32)?;
+    let writers = vec![
+        H264Writer::new(vec![])?,
+        H264Writer::new(vec![])?,
+        H264Writer::new(vec![])?,
+    ];
+    let mut wtr = MultiCameraWriter::new(writers);
+
+    // The cameras after the failing one are still written.
+    assert!(matches!(
+        wtr.write_frame_set(1000, &[Some(bad_image.view()), Some(image.view()), None]),
+        Err(less_avc::Error::DataShapeProblem { .. })
+    ));
+    wtr.write_frame_set(1001, &[Some(image.view()), Some(image.view()), None])?;
+    assert_eq!(
+        wtr.stats(),
+        [
+            CameraStats {
+                frames_written: 1,
+                frames_missing: 0,
+                frames_failed: 1,
+            },
+            CameraStats {
+                frames_written: 2,
+                frames_missing: 0,
+                frames_failed: 0,
+            },
+            CameraStats {
+                frames_written: 0,
+                frames_missing: 2,
+                frames_failed: 0,
+            },
+        ]
+    );
+
+    let streams = wtr.into_inner();
+    assert_eq!(frame_syncs(&streams[0])?, [(1, 1001)]);
+    assert_eq!(frame_syncs(&streams[1])?, [(0, 1000), (1, 1001)]);
+    assert!(streams[2].is_empty());
+    assert_eq!(LessDecoder::new().decode_annex_b(&streams[0])?.len(), 1);
+    Ok(())
+}
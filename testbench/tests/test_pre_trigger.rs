@@ -0,0 +1,159 @@
+// Copyright 2022-2023 Andrew D. Straw.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT
+// or http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use std::{
+    num::{NonZeroU32, NonZeroUsize},
+    time::Duration,
+};
+
+use anyhow::Result;
+use less_avc::{
+    index::FrameIndex, ycbcr_image::OwnedPlanes, EncoderConfig, LessDecoder, PreTriggerLimits,
+    PreTriggerRecorder, WriteInterval,
+};
+
+use testbench::*;
+
+/// Mono8 images whose first pixel is the frame number.
+fn numbered_images(n_frames: u8) -> Result<Vec<MyYCbCrImage>> {
+    (0..n_frames)
+        .map(|i| {
+            let mut image = generate_image(&PixFmt::Mono8, 32, 32)?;
+            let MyPlanes::Mono(y) = &mut image.planes else {
+                unreachable!();
+            };
+            y.data[0] = i;
+            Ok(image)
+        })
+        .collect()
+}
+
+/// The frame numbers of the decoded frames in `data`.
+fn decoded_numbers(data: &[u8]) -> Result<Vec<u8>> {
+    Ok(LessDecoder::new()
+        .decode_annex_b(data)?
+        .iter()
+        .map(|frame| match &frame.planes {
+            OwnedPlanes::Mono(y) => y.data[0],
+            OwnedPlanes::YCbCr(_) => panic!("expected mono image"),
+        })
+        .collect())
+}
+
+#[test]
+fn test_pre_trigger_frames() -> Result<()> {
+    let images = numbered_images(12)?;
+    let limits = PreTriggerLimits {
+        max_bytes: 1 << 20,
+        max_frames: NonZeroUsize::new(3),
+        max_age: None,
+    };
+    let mut recorder = PreTriggerRecorder::new(gop_config(3), limits);
+    for image in &images[..5] {
+        assert!(recorder.write(&image.view())?.is_none());
+    }
+    let stats = recorder.stats();
+    assert_eq!(stats.buffered_frames, 3);
+    assert_eq!(stats.frames_discarded, 2);
+    assert!(stats.memory_bytes >= stats.buffered_bytes);
+
+    let post_trigger = WriteInterval::Frames(NonZeroU32::new(4).unwrap());
+    recorder.trigger(vec![], Some(post_trigger))?;
+    assert!(recorder.is_recording());
+    assert_eq!(recorder.stats().buffered_frames, 0);
+    for image in &images[5..8] {
+        assert!(recorder.write(&image.view())?.is_none());
+    }
+    let data = recorder.write(&images[8].view())?.unwrap();
+    assert!(!recorder.is_recording());
+
+    assert_eq!(decoded_numbers(&data)?, [2, 3, 4, 5, 6, 7, 8]);
+    let index = FrameIndex::from_annex_b(&data)?;
+    let sync: Vec<bool> = index.entries().iter().map(|e| e.is_sync).collect();
+    // Buffered frames are IDR pictures, later frames follow the GOP.
+    assert_eq!(sync, [true, true, true, false, false, true, false]);
+
+    // Buffering resumes after the recording stops.
+    for image in &images[9..] {
+        recorder.write(&image.view())?;
+    }
+    recorder.trigger(vec![], None)?;
+    assert!(recorder.trigger(vec![], None).is_err());
+    let data = recorder.stop().unwrap();
+    assert_eq!(decoded_numbers(&data)?, [9, 10, 11]);
+    Ok(())
+}
+
+#[test]
+fn test_pre_trigger_memory_limit() -> Result<()> {
+    let images = numbered_images(10)?;
+    let frame_size = {
+        let mut recorder = PreTriggerRecorder::<Vec<u8>>::new(
+            EncoderConfig::default(),
+            PreTriggerLimits {
+                max_bytes: 1 << 20,
+                max_frames: None,
+                max_age: None,
+            },
+        );
+        // Buffers after the first have room for the largest possible frame.
+        recorder.write(&images[0].view())?;
+        let first_size = recorder.stats().memory_bytes;
+        recorder.write(&images[1].view())?;
+        recorder.stats().memory_bytes - first_size
+    };
+
+    let limits = PreTriggerLimits {
+        max_bytes: 4 * frame_size,
+        max_frames: None,
+        max_age: None,
+    };
+    let mut recorder = PreTriggerRecorder::new(EncoderConfig::default(), limits);
+    for image in images.iter() {
+        recorder.write(&image.view())?;
+        let stats = recorder.stats();
+        assert!(stats.memory_bytes <= limits.max_bytes + frame_size);
+    }
+    assert_eq!(recorder.stats().buffered_frames, 4);
+    recorder.trigger(vec![], None)?;
+    assert_eq!(decoded_numbers(&recorder.stop().unwrap())?, [6, 7, 8, 9]);
+
+    // A trigger before any frame writes the parameter sets with the first.
+    let mut recorder = PreTriggerRecorder::new(EncoderConfig::default(), limits);
+    recorder.trigger(vec![], None)?;
+    recorder.write(&images[0].view())?;
+    assert_eq!(decoded_numbers(&recorder.stop().unwrap())?, [0]);
+    Ok(())
+}
+
+#[test]
+fn test_pre_trigger_all_frames_expired() -> Result<()> {
+    let images = numbered_images(6)?;
+    let limits = PreTriggerLimits {
+        max_bytes: 1 << 20,
+        max_frames: None,
+        max_age: Some(Duration::from_millis(1)),
+    };
+    let mut recorder = PreTriggerRecorder::new(gop_config(3), limits);
+    for image in &images[..3] {
+        recorder.write(&image.view())?;
+    }
+    std::thread::sleep(Duration::from_millis(20));
+    recorder.trigger(vec![], None)?;
+    assert_eq!(recorder.stats().frames_discarded, 3);
+    for image in &images[3..] {
+        recorder.write(&image.view())?;
+    }
+    let data = recorder.stop().unwrap();
+
+    // The recording starts with an IDR picture despite the empty buffer.
+    assert_eq!(decoded_numbers(&data)?, [3, 4, 5]);
+    let index = FrameIndex::from_annex_b(&data)?;
+    let sync: Vec<bool> = index.entries().iter().map(|e| e.is_sync).collect();
+    assert_eq!(sync, [true, false, false]);
+    Ok(())
+}
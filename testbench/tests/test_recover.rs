@@ -0,0 +1,146 @@
+// Copyright 2022-2023 Andrew D. Straw.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT
+// or http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use std::num::NonZeroU32;
+
+use anyhow::Result;
+use less_avc::{
+    index::FrameIndex, recover_h264_file, EncoderConfig, GopConfig, H264Writer, LessDecoder,
+    SyncWrite, WriteInterval,
+};
+
+use testbench::*;
+
+/// Counts calls to [SyncWrite::sync].
+#[derive(Default)]
+struct CountingSync {
+    buf: Vec<u8>,
+    syncs: usize,
+}
+
+impl std::io::Write for CountingSync {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.buf.write(buf)
+    }
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl SyncWrite for CountingSync {
+    fn sync(&mut self) -> std::io::Result<()> {
+        self.syncs += 1;
+        Ok(())
+    }
+}
+
+fn no_idr_config() -> EncoderConfig {
+    EncoderConfig {
+        gop: GopConfig {
+            idr_interval: None,
+            recovery_point_sei: false,
+        },
+        ..Default::default()
+    }
+}
+
+#[test]
+fn test_repeated_parameter_sets() -> Result<()> {
+    let image = generate_image(&PixFmt::Rgb8, 64, 48)?;
+    let interval = Some(WriteInterval::Frames(NonZeroU32::new(3).unwrap()));
+
+    let mut wtr = H264Writer::new_with_config(vec![], no_idr_config())?;
+    wtr.set_parameter_set_interval(interval);
+    for _ in 0..7 {
+        wtr.write(&image.view())?;
+    }
+    let data = wtr.into_inner();
+
+    let index = FrameIndex::from_annex_b(&data)?;
+    let sync: Vec<bool> = index.entries().iter().map(|e| e.is_sync).collect();
+    assert_eq!(sync, [true, false, false, true, false, false, true]);
+    for entry in index.entries() {
+        // SPS, PPS and slice for IDR pictures
+        let n_nal_units = if entry.is_sync { 3 } else { 1 };
+        assert_eq!(entry.nal_sizes.len(), n_nal_units);
+    }
+
+    // Decoding can start at any IDR picture.
+    let suffix = &data[index.entries()[3].offset as usize..];
+    assert_eq!(LessDecoder::new().decode_annex_b(suffix)?.len(), 4);
+
+    // Batches are split where an IDR picture is forced.
+    let frames: Vec<_> = (0..7).map(|_| image.view()).collect();
+    let mut wtr = H264Writer::new_with_config(vec![], no_idr_config())?;
+    wtr.set_parameter_set_interval(interval);
+    wtr.write_batch(&frames[..2])?;
+    wtr.write_batch(&frames[2..])?;
+    assert_eq!(wtr.into_inner(), data);
+    Ok(())
+}
+
+#[test]
+fn test_sync_interval() -> Result<()> {
+    let image = generate_image(&PixFmt::Mono8, 32, 32)?;
+
+    let mut wtr = H264Writer::new(CountingSync::default())?;
+    wtr.set_sync_interval(Some(WriteInterval::Frames(NonZeroU32::new(2).unwrap())));
+    for _ in 0..5 {
+        wtr.write(&image.view())?;
+    }
+    assert_eq!(wtr.into_inner().syncs, 2);
+
+    let mut wtr = H264Writer::new(CountingSync::default())?;
+    wtr.set_sync_interval(Some(WriteInterval::Duration(std::time::Duration::ZERO)));
+    for _ in 0..3 {
+        wtr.write(&image.view())?;
+    }
+    let frames: Vec<_> = (0..3).map(|_| image.view()).collect();
+    wtr.write_batch(&frames)?;
+    assert_eq!(wtr.into_inner().syncs, 4);
+    Ok(())
+}
+
+#[test]
+fn test_recover() -> Result<()> {
+    let tmpdir = tempfile::tempdir()?;
+    let image = generate_image(&PixFmt::Rgb8, 64, 48)?;
+    let path = tmpdir.path().join("recorded.h264");
+
+    let mut wtr = H264Writer::new(std::fs::File::create(&path)?)?;
+    let infos: Vec<_> = (0..5)
+        .map(|_| wtr.write(&image.view()))
+        .collect::<Result<_, _>>()?;
+    drop(wtr);
+    let len = std::fs::metadata(&path)?.len();
+
+    // An intact file is unchanged.
+    let info = recover_h264_file(&path)?;
+    assert_eq!(info.frame_count, 5);
+    assert_eq!(info.len, len);
+    assert_eq!(info.truncated, 0);
+
+    // A torn last frame is removed.
+    std::fs::OpenOptions::new()
+        .write(true)
+        .open(&path)?
+        .set_len(len - 100)?;
+    let info = recover_h264_file(&path)?;
+    assert_eq!(info.frame_count, 4);
+    assert_eq!(info.len, infos[4].offset);
+    assert_eq!(info.truncated, len - 100 - infos[4].offset);
+    assert_eq!(std::fs::metadata(&path)?.len(), infos[4].offset);
+    let decoded = LessDecoder::new().decode_annex_b(&std::fs::read(&path)?)?;
+    assert_eq!(decoded.len(), 4);
+
+    // Other files are left untouched.
+    let other_path = tmpdir.path().join("other.txt");
+    std::fs::write(&other_path, b"hello")?;
+    assert!(recover_h264_file(&other_path).is_err());
+    assert_eq!(std::fs::read(&other_path)?, b"hello");
+    Ok(())
+}
@@ -0,0 +1,133 @@
+// Copyright 2022-2023 Andrew D. Straw.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT
+// or http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use std::sync::{Arc, Mutex};
+
+use anyhow::Result;
+use less_avc::{H264Writer, LessDecoder, RotatingH264Writer, RotationPolicy, SegmentInfo};
+
+use testbench::*;
+
+/// Write `n_frames` frames and return the segments reported to the callback.
+fn write_rotating(
+    template: &str,
+    policy: RotationPolicy,
+    image: &MyYCbCrImage,
+    n_frames: u64,
+) -> Result<Vec<SegmentInfo>> {
+    let segments = Arc::new(Mutex::new(Vec::new()));
+    let mut wtr = RotatingH264Writer::new(template, gop_config(4), policy)?;
+    let callback_segments = segments.clone();
+    wtr.set_rotation_callback(move |info| callback_segments.lock().unwrap().push(info.clone()));
+    for i in 0..n_frames {
+        let info = wtr.write(&image.view())?;
+        assert_eq!(info.frame_number, i);
+    }
+    assert_eq!(wtr.finish(), n_frames);
+    let segments = segments.lock().unwrap().clone();
+    Ok(segments)
+}
+
+#[test]
+fn test_rotate_by_frames() -> Result<()> {
+    let tmpdir = tempfile::tempdir()?;
+    let image = generate_image(&PixFmt::Rgb8, 64, 48)?;
+    let template = tmpdir.path().join("rec-{segment:03}.h264");
+    let policy = RotationPolicy {
+        max_frames: std::num::NonZeroU64::new(3),
+        ..Default::default()
+    };
+    let segments = write_rotating(template.to_str().unwrap(), policy, &image, 7)?;
+
+    let counts: Vec<_> = segments
+        .iter()
+        .map(|s| (s.segment_number, s.first_frame_number, s.frame_count))
+        .collect();
+    assert_eq!(counts, [(0, 0, 3), (1, 3, 3), (2, 6, 1)]);
+
+    // Each segment is a stream of its own.
+    let mut expected = H264Writer::new_with_config(vec![], gop_config(4))?;
+    for _ in 0..3 {
+        expected.write(&image.view())?;
+    }
+    let expected = expected.into_inner();
+    for (i, segment) in segments.iter().enumerate() {
+        assert_eq!(segment.path, tmpdir.path().join(format!("rec-00{i}.h264")));
+        let data = std::fs::read(&segment.path)?;
+        assert_eq!(data.len() as u64, segment.size);
+        assert!(expected.starts_with(&data));
+        let decoded = LessDecoder::new().decode_annex_b(&data)?;
+        assert_eq!(decoded.len() as u64, segment.frame_count);
+    }
+    Ok(())
+}
+
+#[test]
+fn test_rotate_by_size() -> Result<()> {
+    let tmpdir = tempfile::tempdir()?;
+    let image = generate_image(&PixFmt::Mono8, 32, 32)?;
+    let template = tmpdir.path().join("rec-{segment}.h264");
+    let max_bytes = 3 * 1200;
+    let policy = RotationPolicy {
+        max_bytes: Some(max_bytes),
+        ..Default::default()
+    };
+    let segments = write_rotating(template.to_str().unwrap(), policy, &image, 10)?;
+
+    assert!(segments.len() > 1);
+    let mut next_frame_number = 0;
+    for segment in segments.iter() {
+        assert!(segment.size <= max_bytes);
+        assert_eq!(segment.first_frame_number, next_frame_number);
+        next_frame_number += segment.frame_count;
+        let decoded = LessDecoder::new().decode_annex_b(&std::fs::read(&segment.path)?)?;
+        assert_eq!(decoded.len() as u64, segment.frame_count);
+    }
+    assert_eq!(next_frame_number, 10);
+
+    assert!(matches!(
+        RotatingH264Writer::new("rec.h264", gop_config(4), policy),
+        Err(less_avc::Error::InvalidConfiguration { .. })
+    ));
+    Ok(())
+}
+
+#[test]
+fn test_rotate_first_frame_error() -> Result<()> {
+    let tmpdir = tempfile::tempdir()?;
+    let image = generate_image(&PixFmt::Rgb8, 32, 32)?;
+    // Odd widths are only supported for mono8.
+    let bad_image = generate_image(&PixFmt::Rgb8, 33, 32)?;
+    let template = tmpdir.path().join("rec-{segment}.h264");
+    let policy = RotationPolicy {
+        max_frames: std::num::NonZeroU64::new(2),
+        ..Default::default()
+    };
+    let mut wtr = RotatingH264Writer::new(template.to_str().unwrap(), gop_config(4), policy)?;
+    for _ in 0..2 {
+        wtr.write(&image.view())?;
+    }
+
+    // The failed first frame of a segment leaves no segment behind.
+    assert!(matches!(
+        wtr.write(&bad_image.view()),
+        Err(less_avc::Error::DataShapeProblem { .. })
+    ));
+    assert!(wtr.current_segment().is_none());
+    let path = tmpdir.path().join("rec-1.h264");
+    assert!(!path.exists());
+
+    let info = wtr.write(&image.view())?;
+    assert_eq!(info.frame_number, 2);
+    let segment = wtr.current_segment().unwrap();
+    assert_eq!(segment.path, path);
+    assert_eq!((segment.first_frame_number, segment.frame_count), (2, 1));
+    assert_eq!(wtr.finish(), 3);
+    let decoded = LessDecoder::new().decode_annex_b(&std::fs::read(&path)?)?;
+    assert_eq!(decoded.len(), 1);
+    Ok(())
+}
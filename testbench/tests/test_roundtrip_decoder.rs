@@ -0,0 +1,90 @@
+// Copyright 2022-2023 Andrew D. Straw.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT
+// or http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use anyhow::Result;
+use less_avc::{ycbcr_image::OwnedPlanes, BitDepth, LessDecoder};
+
+use testbench::*;
+
+const WIDTHS: &[u32] = &[12, 16, 636, 640];
+const HEIGHTS: &[u32] = &[14, 16, 478, 480];
+
+fn check_plane(
+    input: &MyImagePlane,
+    decoded: &less_avc::ycbcr_image::OwnedDataPlane,
+    width: u32,
+    height: u32,
+) {
+    assert_eq!(decoded.bit_depth, input.bit_depth);
+    let valid_bytes = match input.bit_depth {
+        BitDepth::Depth8 => width as usize,
+        BitDepth::Depth12 => width as usize * 3 / 2,
+    };
+    for (input_row, decoded_row) in input
+        .data
+        .chunks_exact(input.stride)
+        .zip(decoded.data.chunks_exact(decoded.stride))
+        .take(height as usize)
+    {
+        assert_eq!(decoded_row[..valid_bytes], input_row[..valid_bytes]);
+    }
+}
+
+fn check_roundtrip_decoder(pixfmt: PixFmt, widths: &[u32], heights: &[u32]) -> Result<()> {
+    for width in widths.iter() {
+        for height in heights.iter() {
+            println!("testing {} {}x{}", pixfmt.as_str(), width, height);
+            let input_yuv = generate_image(&pixfmt, *width, *height)?;
+
+            let mut my_h264_writer = less_avc::H264Writer::new(vec![])?;
+            for _ in 0..2 {
+                my_h264_writer.write(&input_yuv.view())?;
+            }
+            let h264_raw_buf = my_h264_writer.into_inner();
+
+            let decoded = LessDecoder::new().decode_annex_b(&h264_raw_buf)?;
+            assert_eq!(decoded.len(), 2);
+            for decoded_yuv in decoded.iter() {
+                assert_eq!(decoded_yuv.width, *width);
+                assert_eq!(decoded_yuv.height, *height);
+                match (&input_yuv.planes, &decoded_yuv.planes) {
+                    (MyPlanes::Mono(y), OwnedPlanes::Mono(decoded_y)) => {
+                        check_plane(y, decoded_y, *width, *height);
+                    }
+                    (MyPlanes::YCbCr((y, u, v)), OwnedPlanes::YCbCr((dy, du, dv))) => {
+                        check_plane(y, dy, *width, *height);
+                        check_plane(u, du, width / 2, height / 2);
+                        check_plane(v, dv, width / 2, height / 2);
+                    }
+                    _ => panic!("decoded planes differ from input"),
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn test_roundtrip_decoder_mono8() -> Result<()> {
+    check_roundtrip_decoder(PixFmt::Mono8, WIDTHS, HEIGHTS)?;
+    check_roundtrip_decoder(PixFmt::Mono8, &[13, 639], &[15])
+}
+
+#[test]
+fn test_roundtrip_decoder_mono12() -> Result<()> {
+    check_roundtrip_decoder(PixFmt::Mono12, WIDTHS, HEIGHTS)
+}
+
+#[test]
+fn test_roundtrip_decoder_rgb8() -> Result<()> {
+    check_roundtrip_decoder(PixFmt::Rgb8, WIDTHS, HEIGHTS)
+}
+
+#[test]
+fn test_roundtrip_decoder_rgb12() -> Result<()> {
+    check_roundtrip_decoder(PixFmt::Rgb12, WIDTHS, HEIGHTS)
+}
@@ -97,6 +97,97 @@ fn test_roundtrip_openh264() -> anyhow::Result<()> {
     Ok(())
 }
 
+#[test]
+fn test_gop_openh264() -> anyhow::Result<()> {
+    let (width, height) = (64, 48);
+    let input_yuv = testbench::generate_image(&PixFmt::Rgb8, width, height)?;
+    let frame_view = input_yuv.view();
+    let config = less_avc::EncoderConfig {
+        low_latency: true,
+        gop: less_avc::GopConfig {
+            idr_interval: std::num::NonZeroU32::new(3),
+            recovery_point_sei: true,
+        },
+        ..Default::default()
+    };
+
+    let mut my_h264_writer = less_avc::H264Writer::new_with_config(vec![], config.clone())?;
+    for i in 0..7u64 {
+        let info = my_h264_writer.write(&frame_view)?;
+        assert_eq!(info.frame_number, i);
+        assert_eq!(info.is_sync, i % 3 == 0);
+    }
+
+    // Decode frame by frame to check each is output immediately.
+    let (initial, mut encoder) = less_avc::LessEncoder::new_with_config(&frame_view, config)?;
+    let mut decoder = openh264::decoder::Decoder::new()?;
+    let mut buf = initial.sps.to_annex_b_data();
+    buf.extend(initial.pps.to_annex_b_data());
+    buf.extend(initial.frame.to_annex_b_data());
+    for _ in 0..7 {
+        let decoded_yuv = decoder.decode(&buf)?.unwrap();
+
+        let (oys, _, _) = decoded_yuv.strides_yuv();
+        let luma = input_yuv.view_luma();
+        let input_valid_size = luma.stride * height as usize;
+        for (input_y_row, decoded_y_row) in luma.data[..input_valid_size]
+            .chunks_exact(luma.stride)
+            .zip(decoded_yuv.y_with_stride().chunks_exact(oys))
+        {
+            assert_eq!(
+                decoded_y_row[..width as usize],
+                input_y_row[..width as usize]
+            );
+        }
+
+        buf = encoder.encode(&frame_view)?.to_annex_b_data();
+    }
+    Ok(())
+}
+
+#[test]
+fn test_slices_openh264() -> anyhow::Result<()> {
+    let (width, height) = (640, 480);
+    let input_yuv = testbench::generate_image(&PixFmt::Rgb8, width, height)?;
+    let frame_view = input_yuv.view();
+    for slice_mode in [
+        less_avc::SliceMode::MacroblockRows(std::num::NonZeroU32::new(4).unwrap()),
+        less_avc::SliceMode::MaxBytes(1400),
+    ] {
+        let config = less_avc::EncoderConfig {
+            slice_mode: slice_mode.clone(),
+            ..Default::default()
+        };
+        let (initial, _encoder) = less_avc::LessEncoder::new_with_config(&frame_view, config)?;
+        assert!(initial.frame.nal_units.len() > 1);
+        if let less_avc::SliceMode::MaxBytes(max_bytes) = slice_mode {
+            for nal_unit in initial.frame.nal_units.iter() {
+                assert!(nal_unit.to_nal_unit().len() <= max_bytes);
+            }
+        }
+
+        let mut buf = initial.sps.to_annex_b_data();
+        buf.extend(initial.pps.to_annex_b_data());
+        buf.extend(initial.frame.to_annex_b_data());
+
+        let mut decoder = openh264::decoder::Decoder::new()?;
+        let decoded_yuv = decoder.decode(&buf)?.unwrap();
+        let (oys, _, _) = decoded_yuv.strides_yuv();
+        let luma = input_yuv.view_luma();
+        let input_valid_size = luma.stride * height as usize;
+        for (input_y_row, decoded_y_row) in luma.data[..input_valid_size]
+            .chunks_exact(luma.stride)
+            .zip(decoded_yuv.y_with_stride().chunks_exact(oys))
+        {
+            assert_eq!(
+                decoded_y_row[..width as usize],
+                input_y_row[..width as usize]
+            );
+        }
+    }
+    Ok(())
+}
+
 fn generate_image(fmt: &str, width: u32, height: u32) -> anyhow::Result<MyYCbCrImage> {
     // luma
     let stride = next_multiple(width, 16) as usize;